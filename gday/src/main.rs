@@ -2,21 +2,30 @@
 #![allow(dead_code)]
 
 mod base32;
+mod proxy;
 mod server_connector;
 
-use clap::{Parser, Subcommand};
-use gday_chat::file_dialog;
-use gday_encryption::{EncryptedReader, EncryptedWriter};
-use gday_hole_punch::client::{random_peer_secret, ContactSharer, PeerSecret};
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4};
+use clap::{Args, Parser, Subcommand};
+use gday_chat::{file_dialog, LocalFileMeta, MultiProgress, ProgressBar};
+use gday_encryption::{EncryptedReader, EncryptedStream, EncryptedWriter};
+use gday_hole_punch::client::{
+    combine_secrets, decode_contact, encode_contact, manual_contact, public_addr,
+    random_peer_secret, ContactSharer, PeerConnector, PeerSecret, RoomExtender,
+};
+use gday_hole_punch::{RoomEvent, Transport, PORT_PROBES};
+use proxy::Proxy;
+use server_connector::{ServerConnector, ServerTrust};
+use std::io::Write;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
 use std::path::PathBuf;
 use std::process::exit;
+use std::sync::Once;
+use std::time::{Duration, Instant};
 use std::{iter::Iterator, net::SocketAddrV6};
-use tokio::net::{
-    tcp::{OwnedReadHalf, OwnedWriteHalf},
-    TcpStream,
-};
-use tokio_rustls::client::TlsStream;
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::task::JoinSet;
 
 const SERVER_V6: SocketAddrV6 = SocketAddrV6::new(
     Ipv6Addr::new(
@@ -30,24 +39,95 @@ const SERVER_V4: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(146, 235, 206, 2
 
 const SERVER_NAME: &str = "psend";
 
+/// Port the server accepts WebSocket connections on,
+/// for when its usual port is blocked.
+const WEBSOCKET_PORT: u16 = 443;
+
+/// How long to wait for the server on its usual port before trying [`WEBSOCKET_PORT`].
+/// A firewall that silently drops packets would otherwise make each
+/// attempt wait out the operating system's connect timeout.
+const DIRECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Exit status of `gday send --recipients` when only some recipients received the files.
+const PARTIAL_SUCCESS: i32 = 2;
+
 /// TODO description here
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
     operation: Commands,
+
+    #[command(flatten)]
+    server: ServerArgs,
+}
+
+#[derive(Args, Clone, Debug)]
+#[allow(clippy::struct_excessive_bools)]
+struct ServerArgs {
+    /// Use a custom server, given as "host:port", instead of the default one
+    #[arg(long, global = true)]
+    server: Option<String>,
+
+    /// Connect to the server through a proxy, such as `http://proxy:3128` or `socks5://proxy:1080`.
+    /// Defaults to the `HTTPS_PROXY` or `ALL_PROXY` environment variable,
+    /// unless `NO_PROXY` lists the server.
+    /// Your peer is then reached through the server's relay.
+    #[arg(long, global = true)]
+    proxy: Option<String>,
+
+    /// Connect to the server over WebSocket on port 443.
+    /// Done automatically when the server's usual port is blocked.
+    #[arg(long, global = true)]
+    websocket: bool,
+
+    /// Trust only a server whose public key has this SHA-256 hash (as printed by `gday_server`)
+    #[arg(long, global = true, conflicts_with = "tofu")]
+    pin: Option<String>,
+
+    /// Trust the server's public key the first time, and require the same key afterwards
+    #[arg(long, global = true)]
+    tofu: bool,
+
+    /// Access key for servers that only let authorized users create rooms
+    #[arg(long, global = true)]
+    key: Option<String>,
+
+    /// Keep your room open for up to this many minutes while waiting for your peer,
+    /// extending it as needed. The server may limit how long a room can stay open.
+    #[arg(long, global = true)]
+    wait: Option<u64>,
+
+    /// Ask your router to open a port your peer can connect to
+    #[arg(long, global = true)]
+    port_mapping: bool,
+
+    /// Exchange contacts with your peer by copy-pasting them, without any server
+    #[arg(long, global = true, conflicts_with_all = ["server", "key", "wait", "proxy", "websocket"])]
+    manual: bool,
 }
 
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Create a room to send files
-    Send { paths: Vec<PathBuf> },
+    Send {
+        paths: Vec<PathBuf>,
+
+        /// Send the files to this many recipients at once, all joining with the same password.
+        /// There's no chat when sending to more than one recipient.
+        /// Exits with status 2 if only some of them received the files.
+        #[arg(short, long, default_value_t = 1)]
+        recipients: u32,
+    },
 
     /// Create a room to chat
     Chat,
 
     /// Join a room
-    Join { password: String },
+    Join {
+        #[arg(required_unless_present = "manual")]
+        password: Option<String>,
+    },
 }
 
 #[tokio::main]
@@ -55,12 +135,20 @@ async fn main() {
     let cli = Cli::parse();
 
     match cli.operation {
-        Commands::Send { paths } => {
+        Commands::Send { paths, recipients } => {
             let files = file_dialog::confirm_send(&paths).unwrap_or_else(|err| {
                 eprintln!("{err}");
                 exit(1)
             });
-            let (mut writer, mut reader) = start_room().await;
+            if recipients > 1 {
+                if cli.server.manual {
+                    eprintln!("Can't send to more than one recipient without a server.");
+                    exit(1)
+                }
+                send_to_many(&cli.server, files, recipients).await;
+                return;
+            }
+            let (mut writer, mut reader) = start_room(&cli.server).await;
             gday_chat::creator_run(&mut reader, &mut writer, Some(files))
                 .await
                 .unwrap_or_else(|err| {
@@ -70,7 +158,7 @@ async fn main() {
         }
 
         Commands::Chat => {
-            let (mut writer, mut reader) = start_room().await;
+            let (mut writer, mut reader) = start_room(&cli.server).await;
             gday_chat::creator_run(&mut reader, &mut writer, None)
                 .await
                 .unwrap_or_else(|err| {
@@ -80,7 +168,11 @@ async fn main() {
         }
 
         Commands::Join { password } => {
-            let (mut writer, mut reader) = join_room(password).await;
+            let (mut writer, mut reader) = if cli.server.manual {
+                manual_connection(false, &cli.server).await
+            } else {
+                join_room(&cli.server, password.unwrap_or_default()).await
+            };
             gday_chat::not_creator_run(&mut reader, &mut writer)
                 .await
                 .unwrap_or_else(|err| {
@@ -91,105 +183,600 @@ async fn main() {
     }
 }
 
-/// (IPV6, IPV4)
-async fn connect_to_server() -> (Option<TlsStream<TcpStream>>, Option<TlsStream<TcpStream>>) {
-    let root_cert = include_bytes!("cert_authority.der").to_vec();
-    let tls_conn = server_connector::get_tls_connector(root_cert).unwrap_or_else(|err| {
+/// (IPV6, IPV4, IPv4 port probes)
+async fn connect_to_server(
+    args: &ServerArgs,
+) -> (
+    Option<Box<dyn Transport>>,
+    Option<Box<dyn Transport>>,
+    Vec<Box<dyn Transport>>,
+) {
+    let tls_conn = server_connector::get_tls_connector(server_trust(args)).unwrap_or_else(|err| {
         eprintln!("{err}");
         exit(1)
     });
 
-    (
-        server_connector::connect(SERVER_V6, SERVER_NAME, &tls_conn)
-            .await
-            .ok(),
-        server_connector::connect(SERVER_V4, SERVER_NAME, &tls_conn)
-            .await
-            .ok(),
-    )
+    if let Some(proxy) = get_proxy(args) {
+        let mut result = Err(std::io::ErrorKind::NotConnected.into());
+        if !args.websocket {
+            result = connect_through_proxy(&proxy, args, false, &tls_conn).await;
+        }
+        // proxies often only allow tunneling to standard HTTPS ports
+        if result.is_err() {
+            result = connect_through_proxy(&proxy, args, true, &tls_conn).await;
+        }
+        let stream = result.unwrap_or_else(|err| {
+            eprintln!("Couldn't connect to server through proxy: {err}");
+            exit(1)
+        });
+        let ipv6 = stream.local_addr().is_ok_and(|addr| addr.is_ipv6());
+
+        return if ipv6 {
+            (Some(stream), None, Vec::new())
+        } else {
+            (None, Some(stream), Vec::new())
+        };
+    }
+
+    let (server_v6, server_v4, server_name) = if let Some(server) = &args.server {
+        resolve_server(server).await
+    } else {
+        (
+            Some(SocketAddr::V6(SERVER_V6)),
+            Some(SocketAddr::V4(SERVER_V4)),
+            SERVER_NAME.to_string(),
+        )
+    };
+
+    let mut conn_v6 = None;
+    let mut conn_v4 = None;
+    let mut probes = Vec::new();
+
+    if !args.websocket {
+        if let Some(addr) = server_v6 {
+            conn_v6 = connect_directly(addr, &server_name, &tls_conn).await.ok();
+        }
+
+        if let Some(addr) = server_v4 {
+            conn_v4 = connect_directly(addr, &server_name, &tls_conn).await.ok();
+
+            // a NAT that keeps the local port doesn't need its allocation measured
+            let mut rewrites_ports = false;
+            if let Some(conn) = conn_v4.take() {
+                let local = conn.local_addr().ok();
+                if let Ok((conn, public)) = public_addr(conn).await {
+                    rewrites_ports = local.is_some_and(|local| local.port() != public.port());
+                    conn_v4 = Some(conn);
+                }
+            }
+
+            // opened one after another, so the NAT allocates their ports in order
+            if rewrites_ports {
+                for _ in 0..PORT_PROBES {
+                    if let Ok(probe) =
+                        server_connector::connect(addr, &server_name, false, &tls_conn).await
+                    {
+                        probes.push(probe);
+                    }
+                }
+            }
+        }
+    }
+
+    // the server's usual port may be blocked, so try a standard HTTPS port
+    if conn_v6.is_none() && conn_v4.is_none() {
+        if let Some(addr) = server_v6 {
+            let addr = SocketAddr::new(addr.ip(), WEBSOCKET_PORT);
+            conn_v6 = server_connector::connect(addr, &server_name, true, &tls_conn)
+                .await
+                .ok();
+        }
+        if let Some(addr) = server_v4 {
+            let addr = SocketAddr::new(addr.ip(), WEBSOCKET_PORT);
+            conn_v4 = server_connector::connect(addr, &server_name, true, &tls_conn)
+                .await
+                .ok();
+        }
+    }
+
+    (conn_v6, conn_v4, probes)
 }
 
-async fn start_room() -> (
-    EncryptedWriter<OwnedWriteHalf>,
-    EncryptedReader<OwnedReadHalf>,
-) {
-    let server_conn = connect_to_server().await;
-    let (sharer, room_id) = ContactSharer::create_room(server_conn.0, server_conn.1)
+/// Connects to the server on its usual port, giving up after [`DIRECT_TIMEOUT`].
+async fn connect_directly(
+    addr: SocketAddr,
+    server_name: &str,
+    tls_conn: &ServerConnector,
+) -> std::io::Result<Box<dyn Transport>> {
+    let connect = server_connector::connect(addr, server_name, false, tls_conn);
+    tokio::time::timeout(DIRECT_TIMEOUT, connect).await?
+}
+
+/// Connects to the server through `proxy`, over WebSocket on
+/// [`WEBSOCKET_PORT`] if `websocket`, or else on the server's usual port.
+async fn connect_through_proxy(
+    proxy: &Proxy,
+    args: &ServerArgs,
+    websocket: bool,
+    tls_conn: &ServerConnector,
+) -> std::io::Result<Box<dyn Transport>> {
+    let (host, port, server_name) = server_target(args);
+    let port = if websocket { WEBSOCKET_PORT } else { port };
+    let stream = proxy.connect(&host, port).await?;
+    server_connector::handshake(stream, &server_name, websocket, tls_conn).await
+}
+
+/// Returns the server's (IPv6 address, IPv4 address, TLS name).
+async fn resolve_server(server: &str) -> (Option<SocketAddr>, Option<SocketAddr>, String) {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host(server)
         .await
         .unwrap_or_else(|err| {
-            eprintln!("Error connecting to server: {err}");
+            eprintln!("Couldn't find server '{server}': {err}");
+            exit(1)
+        })
+        .collect();
+
+    let host = server
+        .rsplit_once(':')
+        .map_or(server, |(host, _port)| host)
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+
+    (
+        addrs.iter().copied().find(SocketAddr::is_ipv6),
+        addrs.iter().copied().find(SocketAddr::is_ipv4),
+        host,
+    )
+}
+
+/// Returns the server's (host, port, TLS name), without resolving the host.
+fn server_target(args: &ServerArgs) -> (String, u16, String) {
+    let Some(server) = &args.server else {
+        return (
+            SERVER_V4.ip().to_string(),
+            SERVER_V4.port(),
+            SERVER_NAME.to_string(),
+        );
+    };
+
+    let Some((host, Ok(port))) = server
+        .rsplit_once(':')
+        .map(|(host, port)| (host, port.parse()))
+    else {
+        eprintln!("Server must be given as \"host:port\"");
+        exit(1)
+    };
+
+    let host = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    (host.clone(), port, host)
+}
+
+/// Returns the proxy given with `--proxy` or by the environment, if any.
+///
+/// A proxy in the environment may be meant for other programs,
+/// so one that can't be used is ignored with a warning instead.
+fn get_proxy(args: &ServerArgs) -> Option<Proxy> {
+    if let Some(url) = &args.proxy {
+        let proxy = Proxy::parse(url).unwrap_or_else(|err| {
+            eprintln!("{err}");
+            exit(1)
+        });
+        return Some(proxy);
+    }
+
+    let (host, _, _) = server_target(args);
+    match Proxy::from_env(&host)? {
+        Ok(proxy) => Some(proxy),
+        Err(err) => {
+            static WARNING: Once = Once::new();
+            WARNING.call_once(|| eprintln!("{err} in the environment. Connecting directly."));
+            None
+        }
+    }
+}
+
+/// Opens a plain TCP connection to the server's relay, through the proxy if there is one.
+/// The server also relays on [`WEBSOCKET_PORT`], which is tried if its usual port is blocked.
+async fn connect_to_relay(args: &ServerArgs) -> std::io::Result<TcpStream> {
+    let (host, port, _) = server_target(args);
+    let proxy = get_proxy(args);
+    let ports = if args.websocket {
+        vec![WEBSOCKET_PORT]
+    } else {
+        vec![port, WEBSOCKET_PORT]
+    };
+
+    let mut result = Err(std::io::ErrorKind::NotConnected.into());
+    for port in ports {
+        let connect = async {
+            match &proxy {
+                Some(proxy) => proxy.connect(&host, port).await,
+                None => TcpStream::connect((host.as_str(), port)).await,
+            }
+        };
+        result = if port == WEBSOCKET_PORT {
+            connect.await
+        } else {
+            tokio::time::timeout(DIRECT_TIMEOUT, connect)
+                .await
+                .unwrap_or_else(|elapsed| Err(elapsed.into()))
+        };
+        if result.is_ok() {
+            break;
+        }
+    }
+    result
+}
+
+fn server_trust(args: &ServerArgs) -> ServerTrust {
+    if let Some(pin) = &args.pin {
+        let hash = server_connector::parse_spki_hash(pin).unwrap_or_else(|| {
+            eprintln!("The pinned key must be 64 hexadecimal characters.");
             exit(1)
         });
+        ServerTrust::PinnedKey(hash)
+    } else if args.tofu {
+        let known_servers = dirs::config_dir()
+            .unwrap_or_default()
+            .join("gday")
+            .join("known_servers");
+        let server = args
+            .server
+            .clone()
+            .unwrap_or_else(|| SERVER_NAME.to_string());
+        ServerTrust::TrustOnFirstUse {
+            known_servers,
+            server,
+        }
+    } else {
+        ServerTrust::CertAuthority(include_bytes!("cert_authority.der").to_vec())
+    }
+}
+
+async fn start_room(
+    server: &ServerArgs,
+) -> (
+    EncryptedWriter<WriteHalf<Box<dyn Transport>>>,
+    EncryptedReader<ReadHalf<Box<dyn Transport>>>,
+) {
+    if server.manual {
+        return manual_connection(true, server).await;
+    }
+
+    let (mut sharer, peer_secret) = open_room(server, 1, &MultiProgress::new()).await;
+    let connector = sharer.next_peer_connector().await.unwrap_or_else(|err| {
+        eprintln!("Couldn't get peer contact: {err}");
+        exit(1)
+    });
+    report_port_mapping(&sharer);
+    establish_peer_connection(connector, peer_secret, server).await
+}
+
+/// Sends `files` to `recipients` peers that join the same room.
+/// Each peer is connected to as soon as it's ready, and sent the files
+/// while the other peers are still joining.
+///
+/// A recipient that can't be connected to or sent the files doesn't affect the others.
+/// Exits with [`PARTIAL_SUCCESS`] if only some recipients received the files,
+/// or 1 if none did.
+async fn send_to_many(server: &ServerArgs, files: Vec<LocalFileMeta>, recipients: u32) {
+    // the room's countdown is drawn along with the transfers' progress bars
+    let progress = MultiProgress::new();
+    let (mut sharer, peer_secret) = open_room(server, recipients, &progress).await;
+
+    let recipients = sharer.max_joiners();
+    let mut transfers = JoinSet::new();
+    let mut received = 0;
+
+    for i in 1..=recipients {
+        let connector = match sharer.next_peer_connector().await {
+            Ok(connector) => connector,
+            Err(err) => {
+                // no one else can join, but the transfers already started can finish
+                eprintln!("Couldn't get contact of recipient {i}: {err}");
+                break;
+            }
+        };
+        if i == 1 {
+            report_port_mapping(&sharer);
+        }
+
+        let files = files.clone();
+        let progress = progress.clone();
+        let server = server.clone();
+        transfers.spawn(async move {
+            let (mut writer, mut reader) =
+                match try_establish_peer_connection(connector, peer_secret, &server).await {
+                    Ok(connection) => connection,
+                    Err(err) => return (i, Err(err.into())),
+                };
+            let _ = progress.println(format!("Connected to recipient {i} of {recipients}."));
+
+            let name = format!("recipient {i}: ");
+            let result = gday_chat::sender_run(&mut reader, &mut writer, files, &progress, &name);
+            (i, result.await)
+        });
+    }
+    drop(sharer);
+
+    while let Some(transfer) = transfers.join_next().await {
+        match transfer {
+            Ok((i, Ok(()))) => {
+                progress.suspend(|| println!("Sent the files to recipient {i}."));
+                received += 1;
+            }
+            Ok((i, Err(err))) => {
+                progress.suspend(|| eprintln!("Error sending to recipient {i}: {err}"));
+            }
+            Err(err) => progress.suspend(|| eprintln!("{err}")),
+        }
+    }
+
+    if received < recipients {
+        eprintln!("Sent the files to {received} of {recipients} recipients.");
+        exit(if received == 0 { 1 } else { PARTIAL_SUCCESS })
+    }
+}
+
+/// Creates a room that up to `max_joiners` peers can join, and prints the password to join it.
+/// Shows the room's status in `progress` while the [`ContactSharer`] is kept.
+async fn open_room(
+    server: &ServerArgs,
+    max_joiners: u32,
+    progress: &MultiProgress,
+) -> (ContactSharer, PeerSecret) {
+    let server_conn = connect_to_server(server).await;
+    let (mut sharer, room_id) = ContactSharer::create_room(
+        server_conn.0,
+        server_conn.1,
+        server.key.as_deref(),
+        max_joiners,
+    )
+    .await
+    .unwrap_or_else(|err| {
+        eprintln!("Error connecting to server: {err}");
+        exit(1)
+    });
+
+    if server.port_mapping {
+        sharer.enable_port_mapping();
+    }
+    if get_proxy(server).is_some() {
+        sharer.require_relay();
+    }
+    // without a pattern, the peer only tries the port the server saw
+    let _ = sharer.measure_port_allocation(server_conn.2).await;
 
     let peer_secret = random_peer_secret();
     let password = base32::to_string(&[0, room_id, peer_secret]);
 
-    println!("Have your peer run: \"gday join {password}\". Password is case-insensitive.");
+    if max_joiners > 1 {
+        let max_joiners = sharer.max_joiners();
+        if max_joiners == 1 {
+            println!("The server only allows 1 recipient per room.");
+        }
+        println!(
+            "Have each of your {max_joiners} recipients run: \"gday join {password}\". \
+            Password is case-insensitive."
+        );
+    } else {
+        println!("Have your peer run: \"gday join {password}\". Password is case-insensitive.");
+    }
+
+    if let Some(events) = sharer.room_events() {
+        let wait_until = server
+            .wait
+            .map(|minutes| Instant::now() + Duration::from_secs(minutes * 60));
+        tokio::spawn(show_room_status(
+            events,
+            sharer.room_expiry(),
+            sharer.room_extender(),
+            wait_until,
+            progress.add(ProgressBar::new_spinner()),
+        ));
+    }
+
+    (sharer, peer_secret)
+}
+
+/// Shows a countdown until the room expires in `status`, and prints the events
+/// the server reports about the room, until the peer's contact has been received.
+///
+/// Extends the room whenever it's about to expire before `wait_until`.
+async fn show_room_status(
+    mut events: UnboundedReceiver<RoomEvent>,
+    mut expiry: Option<Instant>,
+    extender: RoomExtender,
+    wait_until: Option<Instant>,
+    status: ProgressBar,
+) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let Some(event) = event else { break };
+                let message = match event {
+                    RoomEvent::PeerJoined => "A peer joined the room.".to_string(),
+                    RoomEvent::PeerDisconnected => {
+                        "A peer disconnected. Waiting for someone to join again.".to_string()
+                    }
+                    RoomEvent::JoinAttemptFailed => {
+                        "Someone else tried to join the room, but was refused.".to_string()
+                    }
+                    RoomEvent::RoomExpiring { seconds_left } => {
+                        let new_expiry = Instant::now() + Duration::from_secs(seconds_left);
+                        expiry = Some(new_expiry);
+                        if wait_until.is_some_and(|wait_until| new_expiry < wait_until) {
+                            extender.extend();
+                            continue;
+                        }
+                        format!("The room will expire in {seconds_left} seconds.")
+                    }
+                    RoomEvent::RoomExtended { seconds_left } => {
+                        expiry = Some(Instant::now() + Duration::from_secs(seconds_left));
+                        continue;
+                    }
+                };
+                status.suspend(|| println!("{message}"));
+            }
+            _ = ticker.tick() => {
+                if let Some(expiry) = expiry {
+                    let left = expiry.saturating_duration_since(Instant::now()).as_secs();
+                    status.set_message(format!("Room expires in {}:{:02}", left / 60, left % 60));
+                }
+            }
+        }
+    }
 
-    establish_peer_connection(sharer, peer_secret).await
+    status.finish_and_clear();
 }
 
 async fn join_room(
+    server: &ServerArgs,
     password: String,
 ) -> (
-    EncryptedWriter<OwnedWriteHalf>,
-    EncryptedReader<OwnedReadHalf>,
+    EncryptedWriter<WriteHalf<Box<dyn Transport>>>,
+    EncryptedReader<ReadHalf<Box<dyn Transport>>>,
 ) {
     let [_, room_id, peer_secret] = base32::from_string(&password)[..] else {
         println!("Code must be seperated by two \".\"");
         exit(1)
     };
 
-    let server_conn = connect_to_server().await;
+    let server_conn = connect_to_server(server).await;
 
-    let sharer = ContactSharer::join_room(server_conn.0, server_conn.1, room_id)
+    let mut sharer = ContactSharer::join_room(server_conn.0, server_conn.1, room_id)
         .await
         .unwrap_or_else(|err| {
             eprintln!("Error joining room: {err}");
             exit(1)
         });
 
-    establish_peer_connection(sharer, peer_secret).await
+    if server.port_mapping {
+        sharer.enable_port_mapping();
+    }
+    if get_proxy(server).is_some() {
+        sharer.require_relay();
+    }
+    // without a pattern, the peer only tries the port the server saw
+    let _ = sharer.measure_port_allocation(server_conn.2).await;
+
+    let connector = sharer.next_peer_connector().await.unwrap_or_else(|err| {
+        eprintln!("Couldn't get peer contact: {err}");
+        exit(1)
+    });
+    report_port_mapping(&sharer);
+
+    establish_peer_connection(connector, peer_secret, server).await
+}
+
+/// Connects to a peer by having the user exchange contacts with it, instead of a server.
+/// Exactly one of the peers must be the creator.
+async fn manual_connection(
+    is_creator: bool,
+    server: &ServerArgs,
+) -> (
+    EncryptedWriter<WriteHalf<Box<dyn Transport>>>,
+    EncryptedReader<ReadHalf<Box<dyn Transport>>>,
+) {
+    // any free port works, since the peer learns it from the contact
+    let port = std::net::TcpListener::bind((Ipv6Addr::UNSPECIFIED, 0))
+        .or_else(|_| std::net::TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)))
+        .and_then(|listener| listener.local_addr())
+        .unwrap_or_else(|err| {
+            eprintln!("Couldn't find a free port: {err}");
+            exit(1)
+        })
+        .port();
+
+    let local = manual_contact(port);
+    let secret = random_peer_secret();
+    println!(
+        "Send your peer this contact:\n\n{}\n",
+        encode_contact(&local, secret)
+    );
+
+    print!("Paste your peer's contact: ");
+    let _ = std::io::stdout().flush();
+    let mut line = String::new();
+    if let Err(err) = std::io::stdin().read_line(&mut line) {
+        eprintln!("{err}");
+        exit(1)
+    }
+
+    let (peer, peer_secret) = decode_contact(&line).unwrap_or_else(|err| {
+        eprintln!("{err}");
+        exit(1)
+    });
+
+    let connector = PeerConnector::new(local, peer, is_creator);
+    establish_peer_connection(connector, combine_secrets(secret, peer_secret), server).await
+}
+
+/// Prints whether the router opened the port requested with `--port-mapping`.
+fn report_port_mapping(sharer: &ContactSharer) {
+    match sharer.port_mapping() {
+        Some(Ok(mapping)) => println!(
+            "Router opened port {} with {:?}.",
+            mapping.external_addr(),
+            mapping.protocol()
+        ),
+        Some(Err(err)) => eprintln!("Router didn't open a port: {err}"),
+        None => (),
+    }
 }
 
 async fn establish_peer_connection(
-    contact_sharer: ContactSharer,
+    connector: PeerConnector,
     peer_secret: PeerSecret,
+    server: &ServerArgs,
 ) -> (
-    EncryptedWriter<OwnedWriteHalf>,
-    EncryptedReader<OwnedReadHalf>,
+    EncryptedWriter<WriteHalf<Box<dyn Transport>>>,
+    EncryptedReader<ReadHalf<Box<dyn Transport>>>,
 ) {
-    let connector = contact_sharer
-        .get_peer_connector()
+    try_establish_peer_connection(connector, peer_secret, server)
         .await
         .unwrap_or_else(|err| {
-            eprintln!("Couldn't get peer contact: {err}");
+            eprintln!("{err}");
             exit(1)
-        });
+        })
+}
 
-    let (tcp_stream, shared_secret) =
-        connector
-            .connect_to_peer(peer_secret)
-            .await
-            .unwrap_or_else(|err| {
-                eprintln!("Couldn't connect to peer: {err}");
-                exit(1)
-            });
+/// Like [`establish_peer_connection()`], but returns an error instead of exiting.
+async fn try_establish_peer_connection(
+    connector: PeerConnector,
+    peer_secret: PeerSecret,
+    server: &ServerArgs,
+) -> std::io::Result<(
+    EncryptedWriter<WriteHalf<Box<dyn Transport>>>,
+    EncryptedReader<ReadHalf<Box<dyn Transport>>>,
+)> {
+    let is_creator = connector.is_creator();
+    let connection = if connector.needs_relay() {
+        println!("Connecting to your peer through the server's relay.");
+        match connect_to_relay(server).await {
+            Ok(stream) => connector.connect_via_relay(stream, peer_secret).await,
+            Err(err) => Err(err),
+        }
+    } else {
+        connector.connect_to_peer(peer_secret).await
+    };
 
-    let (read, write) = tcp_stream.into_split();
+    let connection = connection.map_err(|err| {
+        std::io::Error::new(err.kind(), format!("Couldn't connect to peer: {err}"))
+    })?;
 
-    (
-        gday_encryption::EncryptedWriter::new(write, shared_secret)
-            .await
-            .unwrap_or_else(|err| {
-                eprintln!("Couldn't encrypt peer connection: {err}");
-                exit(1)
-            }),
-        gday_encryption::EncryptedReader::new(read, shared_secret)
-            .await
-            .unwrap_or_else(|err| {
-                eprintln!("Couldn't encrypt peer connection: {err}");
-                exit(1)
-            }),
-    )
+    let stream = EncryptedStream::new(connection.stream, connection.shared_secret, is_creator)
+        .await
+        .map_err(|err| {
+            std::io::Error::new(err.kind(), format!("Couldn't encrypt peer connection: {err}"))
+        })?;
+
+    let (reader, writer) = stream.into_split();
+    Ok((writer, reader))
 }
@@ -1,28 +1,98 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    fmt::Write as _,
+    fs::{self, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::SystemTime,
+};
 
-use tokio::net::{TcpStream, TcpSocket};
-use tokio_rustls::{TlsConnector, rustls, client::TlsStream};
+use gday_hole_punch::{Transport, WebSocket};
+use sha2::{Digest, Sha256};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio_rustls::{
+    rustls::{
+        self,
+        client::{ServerCertVerified, ServerCertVerifier},
+        Certificate, ServerName,
+    },
+    TlsConnector,
+};
 
+/// How the client decides whether to trust the server it connects to.
+pub enum ServerTrust {
+    /// Trust servers whose certificate is signed by this DER-encoded certificate authority.
+    CertAuthority(Vec<u8>),
 
+    /// Trust only a server whose public key has this SHA-256 SPKI hash.
+    PinnedKey([u8; 32]),
 
+    /// Trust the public key a server presents the first time it's seen,
+    /// and require the same key on every later connection.
+    TrustOnFirstUse {
+        /// File that remembers the key of each server.
+        known_servers: PathBuf,
+        /// Name under which this server is remembered.
+        server: String,
+    },
+}
+
+/// Opens TLS connections to the server, trusting it as a [`ServerTrust`] says.
+pub struct ServerConnector {
+    tls_connector: TlsConnector,
+    /// Set if the server is trusted on first use,
+    /// to remember its key once a handshake succeeds
+    tofu: Option<Arc<TofuVerifier>>,
+}
+
+pub fn get_tls_connector(trust: ServerTrust) -> Result<ServerConnector, rustls::Error> {
+    let builder = rustls::ClientConfig::builder().with_safe_defaults();
+    let mut tofu = None;
 
-pub fn get_tls_connector(cert_authority: Vec<u8>) -> Result<TlsConnector, rustls::Error> {
-    let cert = rustls::Certificate(cert_authority);
-    let mut cert_store = rustls::RootCertStore::empty();
-    cert_store.add(&cert)?;
-    let config = rustls::ClientConfig::builder()
-        .with_safe_defaults()
-        .with_root_certificates(cert_store)
-        .with_no_client_auth();
+    let config = match trust {
+        ServerTrust::CertAuthority(cert_authority) => {
+            let cert = rustls::Certificate(cert_authority);
+            let mut cert_store = rustls::RootCertStore::empty();
+            cert_store.add(&cert)?;
+            builder
+                .with_root_certificates(cert_store)
+                .with_no_client_auth()
+        }
+        ServerTrust::PinnedKey(hash) => builder
+            .with_custom_certificate_verifier(Arc::new(PinnedKeyVerifier { hash }))
+            .with_no_client_auth(),
+        ServerTrust::TrustOnFirstUse {
+            known_servers,
+            server,
+        } => {
+            let verifier = Arc::new(TofuVerifier {
+                known_servers,
+                server,
+            });
+            tofu = Some(verifier.clone());
+            builder
+                .with_custom_certificate_verifier(verifier)
+                .with_no_client_auth()
+        }
+    };
 
-    Ok(TlsConnector::from(Arc::new(config)))
+    Ok(ServerConnector {
+        tls_connector: TlsConnector::from(Arc::new(config)),
+        tofu,
+    })
 }
 
+/// Connects to the server at `server_addr`.
+///
+/// If `websocket`, tunnels the connection through a WebSocket,
+/// as the server expects on its WebSocket port.
 pub async fn connect(
     server_addr: impl Into<SocketAddr>,
     server_name: &str,
-    tls_connector: &TlsConnector,
-) -> std::io::Result<TlsStream<TcpStream>> {
+    websocket: bool,
+    connector: &ServerConnector,
+) -> std::io::Result<Box<dyn Transport>> {
     let server_addr = server_addr.into();
     let socket = match server_addr {
         SocketAddr::V6(_) => TcpSocket::new_v6(),
@@ -30,11 +100,320 @@ pub async fn connect(
     }?;
     let _ = socket.set_reuseaddr(true);
     let _ = socket.set_reuseport(true);
-    
+
     let tcp_stream = socket.connect(server_addr).await?;
-    let tls_stream = tls_connector
-        .connect(server_name.try_into().unwrap(), tcp_stream)
+    handshake(tcp_stream, server_name, websocket, connector).await
+}
+
+/// Starts a TLS session with the server over an existing connection,
+/// such as one through a proxy. If `websocket`, tunnels it through a WebSocket.
+pub async fn handshake(
+    tcp_stream: TcpStream,
+    server_name: &str,
+    websocket: bool,
+    connector: &ServerConnector,
+) -> std::io::Result<Box<dyn Transport>> {
+    let tls_name = server_name.try_into().map_err(|_| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid server name")
+    })?;
+    let tls_stream = connector
+        .tls_connector
+        .connect(tls_name, tcp_stream)
         .await?;
 
-    Ok(tls_stream)
-}
\ No newline at end of file
+    // only remember a key once the server has proven it holds it
+    if let Some(tofu) = &connector.tofu {
+        let cert = tls_stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(<[Certificate]>::first)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Server sent no certificate",
+                )
+            })?;
+        tofu.remember_key(cert)?;
+    }
+
+    if websocket {
+        Ok(Box::new(WebSocket::connect(tls_stream, server_name).await?))
+    } else {
+        Ok(Box::new(tls_stream))
+    }
+}
+
+/// Returns the SHA-256 hash of a DER certificate's `SubjectPublicKeyInfo`.
+pub fn spki_hash(cert: &[u8]) -> Option<[u8; 32]> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    Some(Sha256::digest(cert.public_key().raw).into())
+}
+
+/// Parses a hash in the hexadecimal format printed by `gday_server`.
+pub fn parse_spki_hash(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut hash = [0; 32];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(hash)
+}
+
+pub fn format_spki_hash(hash: &[u8; 32]) -> String {
+    hash.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+/// Accepts only a server certificate with a specific public key.
+/// The certificate's name, issuer, and validity period are ignored,
+/// so the server can use a self-signed certificate.
+struct PinnedKeyVerifier {
+    hash: [u8; 32],
+}
+
+impl ServerCertVerifier for PinnedKeyVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let hash = spki_hash(&end_entity.0).ok_or(rustls::Error::InvalidCertificate(
+            rustls::CertificateError::BadEncoding,
+        ))?;
+
+        if hash == self.hash {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "Server's public key {} doesn't match the pinned key {}",
+                format_spki_hash(&hash),
+                format_spki_hash(&self.hash)
+            )))
+        }
+    }
+}
+
+/// Remembers each server's public key the first time it's seen,
+/// and afterwards accepts only that key.
+struct TofuVerifier {
+    known_servers: PathBuf,
+    server: String,
+}
+
+impl TofuVerifier {
+    /// Returns the remembered key of this server, if any.
+    /// Each line of the file has the format "<server> <hash>".
+    fn known_hash(&self) -> std::io::Result<Option<[u8; 32]>> {
+        let contents = match fs::read_to_string(&self.known_servers) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        for line in contents.lines() {
+            if let Some((server, hash)) = line.trim().rsplit_once(' ') {
+                if server == self.server {
+                    return parse_spki_hash(hash).map(Some).ok_or_else(|| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("Invalid public key hash for server '{server}'"),
+                        )
+                    });
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Remembers the key of `cert` if this server has no known key yet.
+    /// Fails if the server's known key is a different one.
+    ///
+    /// Called once the TLS handshake has succeeded, since [`TofuVerifier::verify_server_cert()`]
+    /// runs before the server has proven it holds the certificate's private key.
+    fn remember_key(&self, cert: &Certificate) -> std::io::Result<()> {
+        let hash = spki_hash(&cert.0).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Invalid server certificate",
+            )
+        })?;
+
+        match self.known_hash()? {
+            Some(known_hash) if known_hash == hash => return Ok(()),
+            Some(known_hash) => {
+                return Err(std::io::Error::other(
+                    self.changed_message(&known_hash, &hash),
+                ))
+            }
+            None => (),
+        }
+
+        if let Some(parent) = self.known_servers.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.known_servers)?;
+        writeln!(file, "{} {}", self.server, format_spki_hash(&hash))?;
+
+        eprintln!(
+            "Trusting server '{}' with public key {} from now on.",
+            self.server,
+            format_spki_hash(&hash)
+        );
+        Ok(())
+    }
+
+    fn changed_message(&self, known_hash: &[u8; 32], hash: &[u8; 32]) -> String {
+        format!(
+            "Public key of server '{}' changed from {} to {}! \
+            If this is expected, remove its line from '{}'.",
+            self.server,
+            format_spki_hash(known_hash),
+            format_spki_hash(hash),
+            self.known_servers.display()
+        )
+    }
+}
+
+impl ServerCertVerifier for TofuVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let hash = spki_hash(&end_entity.0).ok_or(rustls::Error::InvalidCertificate(
+            rustls::CertificateError::BadEncoding,
+        ))?;
+
+        let known_hash = self.known_hash().map_err(|err| {
+            rustls::Error::General(format!(
+                "Couldn't read '{}': {err}",
+                self.known_servers.display()
+            ))
+        })?;
+
+        match known_hash {
+            Some(known_hash) if known_hash != hash => Err(rustls::Error::General(
+                self.changed_message(&known_hash, &hash),
+            )),
+            // a new key is remembered by `handshake()` once the handshake succeeds
+            _ => Ok(ServerCertVerified::assertion()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cert() -> Certificate {
+        Certificate(include_bytes!("cert_authority.der").to_vec())
+    }
+
+    fn verify(verifier: &impl ServerCertVerifier) -> Result<ServerCertVerified, rustls::Error> {
+        verifier.verify_server_cert(
+            &cert(),
+            &[],
+            &ServerName::try_from("localhost").unwrap(),
+            &mut std::iter::empty(),
+            &[],
+            SystemTime::now(),
+        )
+    }
+
+    fn tofu_verifier(name: &str) -> TofuVerifier {
+        let known_servers = std::env::temp_dir()
+            .join(format!("gday_tofu_test_{}", std::process::id()))
+            .join(name);
+        let _ = fs::remove_file(&known_servers);
+        TofuVerifier {
+            known_servers,
+            server: "example.com:2311".to_string(),
+        }
+    }
+
+    #[test]
+    fn spki_hash_round_trips() {
+        let hash = spki_hash(&cert().0).unwrap();
+        let hex = format_spki_hash(&hash);
+        assert_eq!(hex.len(), 64);
+        assert_eq!(parse_spki_hash(&hex), Some(hash));
+        assert_eq!(parse_spki_hash(&hex.to_uppercase()), Some(hash));
+        assert_eq!(parse_spki_hash(&hex[1..]), None);
+        assert_eq!(parse_spki_hash(&hex.replace('0', "g")), None);
+    }
+
+    #[test]
+    fn pinned_key_must_match() {
+        let hash = spki_hash(&cert().0).unwrap();
+        assert!(verify(&PinnedKeyVerifier { hash }).is_ok());
+
+        let mut other = hash;
+        other[0] ^= 1;
+        assert!(verify(&PinnedKeyVerifier { hash: other }).is_err());
+    }
+
+    #[test]
+    fn trusts_key_on_first_use() {
+        let verifier = tofu_verifier("first_use");
+
+        // not remembered until the handshake succeeds
+        assert!(verify(&verifier).is_ok());
+        assert_eq!(verifier.known_hash().unwrap(), None);
+
+        verifier.remember_key(&cert()).unwrap();
+        let hash = spki_hash(&cert().0).unwrap();
+        assert_eq!(verifier.known_hash().unwrap(), Some(hash));
+
+        // the same key is accepted, and not written again
+        assert!(verify(&verifier).is_ok());
+        verifier.remember_key(&cert()).unwrap();
+        let contents = fs::read_to_string(&verifier.known_servers).unwrap();
+        assert_eq!(contents.lines().count(), 1);
+    }
+
+    #[test]
+    fn rejects_changed_key() {
+        let verifier = tofu_verifier("changed");
+        fs::create_dir_all(verifier.known_servers.parent().unwrap()).unwrap();
+        fs::write(
+            &verifier.known_servers,
+            format!("other 00\n{} {}\n", verifier.server, "ab".repeat(32)),
+        )
+        .unwrap();
+
+        assert!(verify(&verifier).is_err());
+        assert!(verifier.remember_key(&cert()).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_known_hash() {
+        let verifier = tofu_verifier("invalid");
+        fs::create_dir_all(verifier.known_servers.parent().unwrap()).unwrap();
+        fs::write(
+            &verifier.known_servers,
+            format!("{} abc\n", verifier.server),
+        )
+        .unwrap();
+
+        assert!(verifier.known_hash().is_err());
+        assert!(verify(&verifier).is_err());
+        assert!(verifier.remember_key(&cert()).is_err());
+    }
+}
@@ -1,12 +1,18 @@
-use crate::{AsyncReadable, AsyncWritable, Error};
+use crate::{AsyncReadable, Error};
+use gday_encryption::EncryptedWriter;
 use rustyline_async::{Readline, ReadlineEvent, SharedWriter};
 use std::io::Write;
-use tokio::io::{AsyncWriteExt, AsyncBufReadExt};
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt, AsyncBufReadExt};
+use tokio::time::MissedTickBehavior;
 use crossterm::style::Stylize;
 
+/// How often the chat sends a chunk, whether or not anything was typed
+const COVER_INTERVAL: Duration = Duration::from_millis(200);
+
 pub async fn start_chat(
     reader: &mut impl AsyncReadable,
-    writer: &mut impl AsyncWritable,
+    writer: &mut EncryptedWriter<impl AsyncWrite + Send + Unpin>,
 ) -> Result<(), Error> {
     let (user_input, terminal) = Readline::new("you: ".to_string()).unwrap();
 
@@ -32,21 +38,36 @@ async fn chat_listen(
     Ok(())
 }
 
+/// Sends the typed lines in padded chunks at a fixed cadence, with cover traffic in between,
+/// so anyone watching the connection can't tell how long messages are or when they're typed.
 async fn chat_talk(
-    writer: &mut impl AsyncWritable,
+    writer: &mut EncryptedWriter<impl AsyncWrite + Send + Unpin>,
     mut user_input: Readline,
     mut terminal: SharedWriter
 ) -> Result<(), Error> {
+    writer.set_padding(true);
+    let mut ticks = tokio::time::interval(COVER_INTERVAL);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
-    while let ReadlineEvent::Line(text) = user_input.readline().await? {
-        if !text.trim().is_empty() {
-            user_input.add_history_entry(text.to_string());
-            writer.write_all(text.as_bytes()).await?;
-            writer.write_all(&[b'\n']).await?;
-            writer.flush().await?;
-            terminal.flush()?;
+    loop {
+        tokio::select! {
+            event = user_input.readline() => {
+                let ReadlineEvent::Line(text) = event? else {
+                    break;
+                };
+                if !text.trim().is_empty() {
+                    user_input.add_history_entry(text.to_string());
+                    writer.write_all(text.as_bytes()).await?;
+                    writer.write_all(b"\n").await?;
+                    terminal.flush()?;
+                }
+            }
+            // sends the lines typed since the last tick, or cover traffic if there were none
+            _ = ticks.tick() => writer.flush_or_cover().await?,
         }
     }
 
+    // tells the peer the chat ended, rather than being cut off
+    writer.shutdown().await?;
     Ok(())
 }
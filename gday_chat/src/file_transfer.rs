@@ -4,6 +4,7 @@ use crate::{
     protocol::{FileMeta, LocalFileMeta},
     AsyncReadable, AsyncWritable, RECEIVED_FILE_FOLDER,
 };
+use gday_encryption::EncryptedWriter;
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 use pin_project::pin_project;
 use tokio::{
@@ -12,13 +13,37 @@ use tokio::{
 };
 
 pub async fn send_files(
-    writer: &mut impl AsyncWritable,
+    writer: &mut EncryptedWriter<impl AsyncWrite + Send + Unpin>,
     files: Vec<LocalFileMeta>,
 ) -> std::io::Result<()> {
     let size: u64 = files.iter().map(|meta| meta.size).sum();
 
     let progress = create_progress_bar(size);
+    send_files_with_progress(writer, files, &progress).await
+}
+
+/// Sends `files`, showing the progress on `progress`.
+pub async fn send_files_with_progress(
+    writer: &mut EncryptedWriter<impl AsyncWrite + Send + Unpin>,
+    files: Vec<LocalFileMeta>,
+    progress: &ProgressBar,
+) -> std::io::Result<()> {
+    // `tokio::io::copy()` flushes whenever the file isn't ready to be read,
+    // which would make the writer send many small chunks
+    writer.set_defer_flushes(true);
+    let result = copy_files(writer, files, progress).await;
+    writer.set_defer_flushes(false);
+    result?;
 
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn copy_files(
+    writer: &mut impl AsyncWritable,
+    files: Vec<LocalFileMeta>,
+    progress: &ProgressBar,
+) -> std::io::Result<()> {
     for meta in files {
         let msg = meta.public_path.to_string_lossy().to_string();
         progress.set_message(msg);
@@ -26,13 +51,11 @@ pub async fn send_files(
 
         let mut writer = ProgressWrite {
             writer,
-            progress: &progress,
+            progress,
         };
 
         tokio::io::copy(&mut file, &mut writer).await?;
     }
-
-    writer.flush().await?;
     Ok(())
 }
 
@@ -58,13 +81,14 @@ pub async fn receive_files(
         };
 
         tokio::io::copy_buf(&mut reader, &mut writer).await?;
+        file.flush().await?;
     }
     Ok(())
 }
 
-fn create_progress_bar(bytes: u64) -> ProgressBar {
+pub fn create_progress_bar(bytes: u64) -> ProgressBar {
     let style = ProgressStyle::with_template(
-        "{msg} [{wide_bar}] {bytes}/{total_bytes} | {bytes_per_sec} | {eta} left",
+        "{prefix}{msg} [{wide_bar}] {bytes}/{total_bytes} | {bytes_per_sec} | {eta} left",
     )
     .unwrap();
     let draw = ProgressDrawTarget::stderr_with_hz(2);
@@ -5,9 +5,13 @@ mod protocol;
 
 use std::str::Utf8Error;
 
-use protocol::{deserialize_from, serialize_into, FileMeta, Message, LocalFileMeta};
+use gday_encryption::EncryptedWriter;
+use protocol::{deserialize_from, serialize_into, FileMeta, Message};
 use thiserror::Error;
-use tokio::io::{AsyncBufRead, AsyncWrite};
+use tokio::io::{AsyncBufRead, AsyncWrite, AsyncWriteExt};
+
+pub use indicatif::{MultiProgress, ProgressBar};
+pub use protocol::LocalFileMeta;
 
 
 const RECEIVED_FILE_FOLDER: &str = "gday_received/";
@@ -39,49 +43,93 @@ pub enum Error {
 
     #[error("Unexpected message: {0:?}")]
     UnexpectedMessge(Message),
+
+    #[error("Peer answered for {answered} files, but {offered} were offered")]
+    WrongAnswerCount { offered: usize, answered: usize },
 }
 
+/// Chats with padding and cover traffic once the files are sent,
+/// which is why `writer` must be encrypted.
 pub async fn creator_run(
     reader: &mut impl AsyncReadable,
-    writer: &mut impl AsyncWritable,
+    writer: &mut EncryptedWriter<impl AsyncWrite + Send + Unpin>,
     files: Option<Vec<LocalFileMeta>>,
 ) -> Result<(), Error> {
 
     if let Some(files) = files {
-        let metas = files.iter().map(|file| FileMeta{path: file.public_path.clone(), size: file.size}).collect();
-
-        let msg = Message::FileOffer(Some(metas));
+        let files_to_send = offer_files(reader, writer, files).await?;
+        file_transfer::send_files(writer, files_to_send).await?;
+    } else {
+        let msg = Message::FileOffer(None);
         serialize_into(writer, &msg).await?;
+    }
 
-        let mut tmp_buf = Vec::new();
-        let reply = deserialize_from(reader, &mut tmp_buf).await?;
 
-        if let Message::FileAccept(chosen) = reply {
-            // ADD ASSERT TO ENSURE REPLY IS PROPER LENGTH
+    chat::start_chat(reader, writer).await
+}
 
-            let files_to_send: Vec<LocalFileMeta> = files
-                .into_iter()
-                .zip(chosen.into_iter())
-                .filter(|(_file, accepted)| *accepted)
-                .map(|(file, _accepted)| file)
-                .collect();
+/// Offers `files` to the peer and sends the ones it accepts, without starting a chat.
+/// Closes `writer` once done, which ends the peer's chat.
+///
+/// Meant for sending to several peers at once,
+/// so the progress is shown as a bar labeled `name` in `progress`.
+pub async fn sender_run(
+    reader: &mut impl AsyncReadable,
+    writer: &mut EncryptedWriter<impl AsyncWrite + Send + Unpin>,
+    files: Vec<LocalFileMeta>,
+    progress: &MultiProgress,
+    name: &str,
+) -> Result<(), Error> {
+    let files_to_send = offer_files(reader, writer, files).await?;
 
-            file_transfer::send_files(writer, files_to_send).await?;
-        } else {
-            return Err(Error::UnexpectedMessge(reply));
+    let size = files_to_send.iter().map(|file| file.size).sum();
+    let bar = progress.add(file_transfer::create_progress_bar(size));
+    bar.set_prefix(name.to_string());
+
+    file_transfer::send_files_with_progress(writer, files_to_send, &bar).await?;
+    bar.finish();
+
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// Offers `files` to the peer, and returns the ones it accepted.
+async fn offer_files(
+    reader: &mut impl AsyncReadable,
+    writer: &mut impl AsyncWritable,
+    files: Vec<LocalFileMeta>,
+) -> Result<Vec<LocalFileMeta>, Error> {
+    let metas = files.iter().map(|file| FileMeta{path: file.public_path.clone(), size: file.size}).collect();
+
+    let msg = Message::FileOffer(Some(metas));
+    serialize_into(writer, &msg).await?;
+
+    let mut tmp_buf = Vec::new();
+    let reply = deserialize_from(reader, &mut tmp_buf).await?;
+
+    if let Message::FileAccept(chosen) = reply {
+        if chosen.len() != files.len() {
+            return Err(Error::WrongAnswerCount {
+                offered: files.len(),
+                answered: chosen.len(),
+            });
         }
+
+        Ok(files
+            .into_iter()
+            .zip(chosen)
+            .filter(|(_file, accepted)| *accepted)
+            .map(|(file, _accepted)| file)
+            .collect())
     } else {
-        let msg = Message::FileOffer(None);
-        serialize_into(writer, &msg).await?;
+        Err(Error::UnexpectedMessge(reply))
     }
-
-
-    chat::start_chat(reader, writer).await
 }
 
+/// Like [`creator_run()`], but receives the files.
 pub async fn not_creator_run(
     mut reader: &mut impl AsyncReadable,
-    mut writer: &mut impl AsyncWritable,
+    mut writer: &mut EncryptedWriter<impl AsyncWrite + Send + Unpin>,
 ) -> Result<(), Error> {
     let mut tmp_buf = Vec::new();
     println!("Waiting on message!");
@@ -96,7 +144,7 @@ pub async fn not_creator_run(
 
             let files_to_receive: Vec<FileMeta> = files
                 .into_iter()
-                .zip(chosen.into_iter())
+                .zip(chosen)
                 .filter(|(_file, accepted)| *accepted)
                 .map(|(file, _accepted)| file)
                 .collect();
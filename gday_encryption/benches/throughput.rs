@@ -0,0 +1,138 @@
+//! Compares sending data over local TCP with and without encryption.
+//!
+//! Run with `cargo bench -p gday-encryption`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use gday_encryption::{Builder, CipherSuite};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+
+const DATA_LEN: usize = 16 * 1024 * 1024;
+
+/// Size of each write, like the buffer of [`tokio::io::copy()`]
+const WRITE_LEN: usize = 8 * 1024;
+
+/// Returns both ends of a local TCP connection.
+async fn tcp_pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+    (client.unwrap(), server.unwrap().0)
+}
+
+/// Writes `data` in [`WRITE_LEN`] pieces, flushing after each if `flush` is true,
+/// while reading it all on the other end.
+async fn transfer(
+    mut writer: impl AsyncWrite + Unpin,
+    mut reader: impl AsyncRead + Unpin,
+    data: &[u8],
+    flush: bool,
+) {
+    let write = async {
+        for piece in data.chunks(WRITE_LEN) {
+            writer.write_all(piece).await.unwrap();
+            if flush {
+                writer.flush().await.unwrap();
+            }
+        }
+        writer.shutdown().await.unwrap();
+    };
+    let read = async {
+        let mut buf = vec![0; 64 * 1024];
+        while reader.read(&mut buf).await.unwrap() != 0 {}
+    };
+    tokio::join!(write, read);
+}
+
+/// Sends the data through encrypted streams configured by `builder`.
+async fn encrypted_transfer(builder: Builder, data: &[u8], flush: bool) {
+    let key = [7; 32];
+    let (client, server) = tcp_pair().await;
+    let (writer, reader) = tokio::join!(
+        builder.writer(client, key, true),
+        builder.reader(server, key, false)
+    );
+    transfer(writer.unwrap(), reader.unwrap(), data, flush).await;
+}
+
+fn throughput(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let data: Vec<u8> = (0..DATA_LEN).map(|i| i as u8).collect();
+
+    let mut group = c.benchmark_group("throughput");
+    group.throughput(Throughput::Bytes(DATA_LEN as u64));
+    group.sample_size(10);
+
+    group.bench_function("plain tcp", |b| {
+        b.to_async(&runtime).iter(|| async {
+            let (client, server) = tcp_pair().await;
+            transfer(client, server, &data, false).await;
+        })
+    });
+
+    for chunk_size in [8 * 1024, 64 * 1024, 1024 * 1024] {
+        let builder = Builder::new().chunk_size(chunk_size);
+        group.bench_with_input(
+            BenchmarkId::new("encrypted", chunk_size),
+            &builder,
+            |b, &builder| {
+                b.to_async(&runtime)
+                    .iter(|| encrypted_transfer(builder, &data, false))
+            },
+        );
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("cipher suite");
+    group.throughput(Throughput::Bytes(DATA_LEN as u64));
+    group.sample_size(10);
+
+    for suite in CipherSuite::ALL {
+        let builder = Builder::new().chunk_size(64 * 1024).cipher_suites(&[suite]);
+        group.bench_with_input(format!("{suite:?}"), &builder, |b, &builder| {
+            b.to_async(&runtime)
+                .iter(|| encrypted_transfer(builder, &data, false))
+        });
+    }
+    group.finish();
+
+    // like tokio::io::copy, flush after every write
+    let mut group = c.benchmark_group("flush every write");
+    group.throughput(Throughput::Bytes(DATA_LEN as u64));
+    group.sample_size(10);
+
+    for defer in [false, true] {
+        let builder = Builder::new().chunk_size(64 * 1024).defer_flushes(defer);
+        let name = if defer { "deferred" } else { "immediate" };
+        group.bench_with_input(name, &builder, |b, &builder| {
+            b.to_async(&runtime)
+                .iter(|| encrypted_transfer(builder, &data, true))
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("pipelined");
+    group.throughput(Throughput::Bytes(DATA_LEN as u64));
+    group.sample_size(10);
+
+    for chunk_size in [64 * 1024, 1024 * 1024] {
+        for threads in [0, 2, 4] {
+            let builder = Builder::new()
+                .chunk_size(chunk_size)
+                .pipeline_threads(threads);
+            group.bench_with_input(
+                BenchmarkId::new(format!("{threads} threads"), chunk_size),
+                &builder,
+                |b, &builder| {
+                    b.to_async(&runtime)
+                        .iter(|| encrypted_transfer(builder, &data, false))
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, throughput);
+criterion_main!(benches);
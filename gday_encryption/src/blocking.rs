@@ -0,0 +1,214 @@
+//! Blocking versions of the encrypted streams, for [`std::io`] readers and writers.
+//!
+//! They send and receive the same chunks as the async versions,
+//! so a blocking writer can talk to an async reader and the other way around.
+
+use std::io::{BufRead, ErrorKind, Read, Write};
+
+use crate::cipher::{shut_down_error, ChunkDecryptor, ChunkEncryptor};
+use crate::suite::Offer;
+use crate::{Builder, CipherSuite, HelperBuf, HEADER_PREFIX_LEN, MAX_HEADER_LEN};
+
+/// Blocking version of [`EncryptedReader`](crate::EncryptedReader).
+pub struct EncryptedReader<R: Read> {
+    reader: R,
+    decryptor: ChunkDecryptor,
+    cleartext: HelperBuf,
+    ciphertext: HelperBuf,
+}
+
+impl<R: Read> EncryptedReader<R> {
+    /// Decrypts with a key derived from `shared_key` for the peer's direction.
+    /// `is_creator` must be true on exactly one of the two peers,
+    /// whose writers are given the same value.
+    pub fn new(reader: R, shared_key: [u8; 32], is_creator: bool) -> std::io::Result<Self> {
+        Self::with_builder(reader, shared_key, is_creator, &Builder::new(), None)
+    }
+
+    pub(crate) fn with_builder(
+        mut reader: R,
+        shared_key: [u8; 32],
+        is_creator: bool,
+        builder: &Builder,
+        peer_offer: Option<Offer>,
+    ) -> std::io::Result<Self> {
+        let mut header = [0; MAX_HEADER_LEN];
+        reader.read_exact(&mut header[..HEADER_PREFIX_LEN])?;
+        let header_len = ChunkDecryptor::header_len(&header)?;
+        reader.read_exact(&mut header[HEADER_PREFIX_LEN..header_len])?;
+        let decryptor = ChunkDecryptor::new(
+            shared_key,
+            is_creator,
+            &header[..header_len],
+            builder,
+            peer_offer,
+        )?;
+
+        Ok(Self {
+            reader,
+            cleartext: HelperBuf::with_capacity(decryptor.chunk_size()),
+            ciphertext: HelperBuf::with_capacity(decryptor.chunk_size() * 2),
+            decryptor,
+        })
+    }
+
+    /// The cipher suite the writer chose.
+    pub fn cipher_suite(&self) -> CipherSuite {
+        self.decryptor.suite()
+    }
+
+    /// Reads until a chunk is decrypted. Returns true if the writer ended the stream instead.
+    fn fill_cleartext(&mut self) -> std::io::Result<bool> {
+        loop {
+            self.decryptor
+                .decrypt_full_chunks(&mut self.ciphertext, &mut self.cleartext)?;
+            if !self.cleartext.data().is_empty() {
+                return Ok(false);
+            }
+            if self.decryptor.is_finished() {
+                return Ok(true);
+            }
+
+            let buf = &mut self.ciphertext.buf;
+            let old_len = buf.len();
+            buf.resize(buf.capacity(), 0);
+            let bytes_read = match self.reader.read(&mut buf[old_len..]) {
+                Ok(bytes_read) => bytes_read,
+                Err(err) => {
+                    buf.truncate(old_len);
+                    return Err(err);
+                }
+            };
+            buf.truncate(old_len + bytes_read);
+
+            // EOF before the final chunk means the stream was cut short
+            if bytes_read == 0 {
+                return Err(std::io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "Stream ended before its final chunk",
+                ));
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for EncryptedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let data = self.fill_buf()?;
+        let num_bytes = std::cmp::min(buf.len(), data.len());
+        buf[..num_bytes].copy_from_slice(&data[..num_bytes]);
+        self.consume(num_bytes);
+        Ok(num_bytes)
+    }
+}
+
+impl<R: Read> BufRead for EncryptedReader<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        if self.cleartext.data().is_empty() && self.fill_cleartext()? {
+            return Ok(&[]);
+        }
+        Ok(self.cleartext.data())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.cleartext.advance_cursor(amt);
+    }
+}
+
+/// Blocking version of [`EncryptedWriter`](crate::EncryptedWriter).
+///
+/// Call [`EncryptedWriter::finish()`] once done writing,
+/// or the reader will take the stream as cut short.
+pub struct EncryptedWriter<W: Write> {
+    writer: W,
+    encryptor: ChunkEncryptor,
+    /// Bytes to encrypt into the next chunk,
+    /// after a placeholder for its length prefix
+    bytes: HelperBuf,
+}
+
+impl<W: Write> EncryptedWriter<W> {
+    /// Encrypts with a key derived from `shared_key` for this peer's direction.
+    /// `is_creator` must be true on exactly one of the two peers,
+    /// whose readers are given the same value.
+    pub fn new(writer: W, shared_key: [u8; 32], is_creator: bool) -> std::io::Result<Self> {
+        Self::with_builder(writer, shared_key, is_creator, &Builder::new(), None)
+    }
+
+    pub(crate) fn with_builder(
+        mut writer: W,
+        shared_key: [u8; 32],
+        is_creator: bool,
+        builder: &Builder,
+        peer_offer: Option<Offer>,
+    ) -> std::io::Result<Self> {
+        let (encryptor, header) = ChunkEncryptor::new(shared_key, is_creator, builder, peer_offer)?;
+
+        writer.write_all(&header)?;
+        writer.flush()?;
+
+        let mut bytes = HelperBuf::with_capacity(encryptor.chunk_size());
+        bytes.buf.extend_from_slice(&[0, 0, 0, 0]);
+        Ok(Self {
+            writer,
+            encryptor,
+            bytes,
+        })
+    }
+
+    /// The cipher suite this writer encrypts with.
+    pub fn cipher_suite(&self) -> CipherSuite {
+        self.encryptor.suite()
+    }
+
+    /// See [`crate::EncryptedWriter::set_rekey_limit()`].
+    pub fn set_rekey_limit(&mut self, chunks: u32, bytes: u64) {
+        self.encryptor.set_rekey_limit(chunks, bytes);
+    }
+
+    /// Sends the buffered bytes as the authenticated final chunk,
+    /// and returns the inner writer.
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.write_chunk(true)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    /// Encrypts the buffered bytes into a chunk, and writes it to the inner writer.
+    fn write_chunk(&mut self, is_last: bool) -> std::io::Result<()> {
+        self.encryptor.encrypt_chunk(&mut self.bytes, is_last)?;
+        self.writer.write_all(self.bytes.data())?;
+        self.bytes.advance_cursor(self.bytes.data().len());
+        self.bytes.buf.extend_from_slice(&[0, 0, 0, 0]);
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for EncryptedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.encryptor.is_shut_down() {
+            return Err(shut_down_error());
+        }
+
+        let reserved_len = self.encryptor.reserved_len();
+        let bytes_taken = std::cmp::min(buf.len(), self.bytes.spare_capacity_len() - reserved_len);
+
+        self.bytes.buf.extend_from_slice(&buf[0..bytes_taken]);
+
+        if self.bytes.spare_capacity_len() == reserved_len {
+            self.write_chunk(false)?;
+        }
+
+        Ok(bytes_taken)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.bytes.data().len() > 4 {
+            self.write_chunk(false)?;
+        }
+        self.writer.flush()
+    }
+}
@@ -0,0 +1,207 @@
+use crate::{
+    CipherSuite, DEFAULT_CHUNK_SIZE, DEFAULT_REKEY_BYTES, DEFAULT_REKEY_CHUNKS, MAX_CHUNK_SIZE,
+    MIN_CHUNK_SIZE,
+};
+
+/// Configures encrypted readers and writers before creating them.
+/// Their `new()` functions use the defaults.
+///
+/// The chunk size is negotiated per direction: each writer announces the size of its chunks
+/// before sending any, and the reader refuses sizes above its [`Builder::max_chunk_size()`].
+///
+/// ```no_run
+/// # #[cfg(feature = "tokio")]
+/// # async fn example(socket: tokio::net::TcpStream, shared_key: [u8; 32]) -> std::io::Result<()> {
+/// let stream = gday_encryption::Builder::new()
+///     .chunk_size(64 * 1024)
+///     .defer_flushes(true)
+///     .stream(socket, shared_key, true)
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct Builder {
+    pub(crate) chunk_size: usize,
+    pub(crate) max_chunk_size: usize,
+    pub(crate) rekey_chunks: u32,
+    pub(crate) rekey_bytes: u64,
+    pub(crate) defer_flushes: bool,
+    /// Set of [`CipherSuite::bit()`]s
+    pub(crate) cipher_suites: u8,
+    /// 0 to encrypt and decrypt on the async task
+    pub(crate) pipeline_threads: usize,
+    pub(crate) padding: bool,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self {
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            max_chunk_size: MAX_CHUNK_SIZE,
+            rekey_chunks: DEFAULT_REKEY_CHUNKS,
+            rekey_bytes: DEFAULT_REKEY_BYTES,
+            defer_flushes: false,
+            cipher_suites: CipherSuite::ALL
+                .iter()
+                .fold(0, |set, suite| set | suite.bit()),
+            pipeline_threads: 0,
+            padding: false,
+        }
+    }
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Size of the chunks writers send, including each chunk's 20 bytes of overhead.
+    /// Larger chunks cost less per byte, but the reader buffers 3 times as much.
+    ///
+    /// Defaults to 8 KiB.
+    ///
+    /// # Panics
+    /// If `bytes` is below 64 or above 1 MiB.
+    pub fn chunk_size(mut self, bytes: usize) -> Self {
+        assert!(
+            (MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&bytes),
+            "chunk size must be between {MIN_CHUNK_SIZE} and {MAX_CHUNK_SIZE} bytes"
+        );
+        self.chunk_size = bytes;
+        self
+    }
+
+    /// Largest chunk size readers accept from their writer.
+    /// A reader returns an error if its writer announces larger chunks.
+    ///
+    /// Defaults to 1 MiB.
+    ///
+    /// # Panics
+    /// If `bytes` is below 64 or above 1 MiB.
+    pub fn max_chunk_size(mut self, bytes: usize) -> Self {
+        assert!(
+            (MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&bytes),
+            "max chunk size must be between {MIN_CHUNK_SIZE} and {MAX_CHUNK_SIZE} bytes"
+        );
+        self.max_chunk_size = bytes;
+        self
+    }
+
+    /// Writers switch to a new key after encrypting `chunks` chunks or `bytes` bytes
+    /// with the current one, whichever comes first.
+    ///
+    /// Defaults to 2^24 chunks or 4 GiB.
+    pub fn rekey_limit(mut self, chunks: u32, bytes: u64) -> Self {
+        self.rekey_chunks = chunks;
+        self.rekey_bytes = bytes;
+        self
+    }
+
+    /// See [`EncryptedWriter::set_defer_flushes()`](crate::EncryptedWriter::set_defer_flushes).
+    ///
+    /// Defaults to false.
+    pub fn defer_flushes(mut self, defer: bool) -> Self {
+        self.defer_flushes = defer;
+        self
+    }
+
+    /// Cipher suites to use. Writers use the fastest of them on this CPU,
+    /// readers refuse the others, and streams negotiate one the peer also allows.
+    ///
+    /// Defaults to all of them.
+    ///
+    /// # Panics
+    /// If `suites` is empty.
+    pub fn cipher_suites(mut self, suites: &[CipherSuite]) -> Self {
+        assert!(
+            !suites.is_empty(),
+            "at least one cipher suite must be allowed"
+        );
+        self.cipher_suites = suites.iter().fold(0, |set, suite| set | suite.bit());
+        self
+    }
+
+    /// Encrypts and decrypts chunks on `threads` threads instead of the async task,
+    /// so that one core doesn't limit the speed of fast networks.
+    /// Chunks are still sent and read in order, so the peer needn't do the same.
+    /// Each async reader and writer starts its own threads,
+    /// which pay off with chunks of 64 KiB or more.
+    ///
+    /// Defaults to 0, which encrypts and decrypts on the async task.
+    /// Blocking readers and writers ignore this.
+    pub fn pipeline_threads(mut self, threads: usize) -> Self {
+        self.pipeline_threads = threads;
+        self
+    }
+
+    /// See [`EncryptedWriter::set_padding()`](crate::EncryptedWriter::set_padding).
+    ///
+    /// Defaults to false.
+    pub fn padding(mut self, padding: bool) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    /// Creates an [`EncryptedWriter`](crate::EncryptedWriter).
+    /// See [`EncryptedWriter::new()`](crate::EncryptedWriter::new).
+    #[cfg(feature = "tokio")]
+    pub async fn writer<T: crate::writer::AsyncWritable>(
+        &self,
+        writer: T,
+        shared_key: [u8; 32],
+        is_creator: bool,
+    ) -> std::io::Result<crate::EncryptedWriter<T>> {
+        crate::EncryptedWriter::with_builder(writer, shared_key, is_creator, self, None).await
+    }
+
+    /// Creates an [`EncryptedReader`](crate::EncryptedReader).
+    /// See [`EncryptedReader::new()`](crate::EncryptedReader::new).
+    #[cfg(feature = "tokio")]
+    pub async fn reader<T: crate::reader::AsyncReadable>(
+        &self,
+        reader: T,
+        shared_key: [u8; 32],
+        is_creator: bool,
+    ) -> std::io::Result<crate::EncryptedReader<T>> {
+        crate::EncryptedReader::with_builder(reader, shared_key, is_creator, self, None).await
+    }
+
+    /// Creates an [`EncryptedStream`](crate::EncryptedStream).
+    /// See [`EncryptedStream::new()`](crate::EncryptedStream::new).
+    #[cfg(feature = "tokio")]
+    pub async fn stream<T>(
+        &self,
+        stream: T,
+        shared_key: [u8; 32],
+        is_creator: bool,
+    ) -> std::io::Result<crate::EncryptedStream<T>>
+    where
+        T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Unpin,
+    {
+        crate::EncryptedStream::with_builder(stream, shared_key, is_creator, self).await
+    }
+
+    /// Creates a blocking [`EncryptedWriter`](crate::blocking::EncryptedWriter).
+    /// Flushes are never deferred.
+    #[cfg(feature = "blocking")]
+    pub fn blocking_writer<W: std::io::Write>(
+        &self,
+        writer: W,
+        shared_key: [u8; 32],
+        is_creator: bool,
+    ) -> std::io::Result<crate::blocking::EncryptedWriter<W>> {
+        crate::blocking::EncryptedWriter::with_builder(writer, shared_key, is_creator, self, None)
+    }
+
+    /// Creates a blocking [`EncryptedReader`](crate::blocking::EncryptedReader).
+    #[cfg(feature = "blocking")]
+    pub fn blocking_reader<R: std::io::Read>(
+        &self,
+        reader: R,
+        shared_key: [u8; 32],
+        is_creator: bool,
+    ) -> std::io::Result<crate::blocking::EncryptedReader<R>> {
+        crate::blocking::EncryptedReader::with_builder(reader, shared_key, is_creator, self, None)
+    }
+}
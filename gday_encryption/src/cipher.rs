@@ -0,0 +1,482 @@
+use bytes::BytesMut;
+use rand::RngCore;
+use std::io::ErrorKind;
+use std::sync::Arc;
+
+use crate::suite::{self, CipherSuite, Offer, StreamCipher};
+use crate::{
+    keys, Builder, HelperBuf, CHUNK_FLAGS, CIPHERTEXT_OVERHEAD, HEADER_PREFIX_LEN, LAST_CHUNK,
+    MIN_CHUNK_SIZE, MIN_PADDED_CHUNK_SIZE, PADDED_CHUNK, REKEY_CHUNK,
+};
+
+/// Most chunks that can be encrypted with one key,
+/// since the STREAM construction's counter has 31 bits, minus 3 it reserves.
+const MAX_CHUNKS_PER_KEY: u32 = 0x0fff_ffff;
+
+/// Encrypts the chunks of one direction of a stream,
+/// switching keys as it goes. Shared by the async and blocking writers.
+pub struct ChunkEncryptor {
+    /// `None` once the final chunk has been encrypted
+    cipher: Option<Arc<StreamCipher>>,
+    suite: CipherSuite,
+    /// Key of the current `cipher`
+    key: [u8; 32],
+    nonce: Vec<u8>,
+    /// Size of the chunks, including their length prefix and authentication tag
+    chunk_size: usize,
+    /// Chunks and bytes to encrypt with one key before switching to the next
+    rekey_chunks: u32,
+    rekey_bytes: u64,
+    /// Whether chunks are padded to hide the length of their data
+    padding: bool,
+    /// Chunks and bytes encrypted with the current key.
+    /// The number of chunks is also the STREAM position of the next chunk.
+    chunks_encrypted: u32,
+    bytes_encrypted: u64,
+}
+
+impl ChunkEncryptor {
+    /// Returns an encryptor configured by `builder`, with a random nonce.
+    /// The returned header announces its cipher suite, chunk size, offers and nonce,
+    /// and must be sent to the reader first.
+    ///
+    /// Uses the suite negotiated with `peer_offer`, the offer received from the reader's peer.
+    /// Without one, uses the fastest of the builder's cipher suites on this CPU.
+    pub fn new(
+        shared_key: [u8; 32],
+        is_creator: bool,
+        builder: &Builder,
+        peer_offer: Option<Offer>,
+    ) -> std::io::Result<(Self, Vec<u8>)> {
+        let offer = Offer::new(builder.cipher_suites);
+        let suite = match peer_offer {
+            Some(peer_offer) => offer.negotiate(peer_offer)?,
+            None => suite::preferred(builder.cipher_suites).unwrap(),
+        };
+        let mut nonce = vec![0; suite.nonce_len()];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let mut header = vec![suite.id()];
+        header.extend_from_slice(&u32::try_from(builder.chunk_size).unwrap().to_be_bytes());
+        header.extend_from_slice(&offer.to_bytes());
+        header.extend_from_slice(&Offer::option_to_bytes(peer_offer));
+        header.extend_from_slice(&nonce);
+        let key = keys::direction_key(shared_key, is_creator, &header);
+
+        let this = Self {
+            cipher: Some(Arc::new(StreamCipher::new(suite, &key, &nonce))),
+            suite,
+            key,
+            nonce,
+            chunk_size: builder.chunk_size,
+            rekey_chunks: builder.rekey_chunks,
+            rekey_bytes: builder.rekey_bytes,
+            padding: builder.padding,
+            chunks_encrypted: 0,
+            bytes_encrypted: 0,
+        };
+        Ok((this, header))
+    }
+
+    pub fn suite(&self) -> CipherSuite {
+        self.suite
+    }
+
+    /// Size of the chunks, including their length prefix and authentication tag.
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    pub fn set_rekey_limit(&mut self, chunks: u32, bytes: u64) {
+        self.rekey_chunks = chunks;
+        self.rekey_bytes = bytes;
+    }
+
+    #[cfg(feature = "tokio")]
+    pub fn padding(&self) -> bool {
+        self.padding
+    }
+
+    /// Pads every chunk from the next one on to [`MIN_PADDED_CHUNK_SIZE`]
+    /// or the next power of 2, up to the chunk size.
+    #[cfg(feature = "tokio")]
+    pub fn set_padding(&mut self, padding: bool) {
+        self.padding = padding;
+    }
+
+    /// Bytes of each chunk that can't hold data:
+    /// the authentication tag, and the length of the data if padded.
+    pub fn reserved_len(&self) -> usize {
+        if self.padding {
+            CIPHERTEXT_OVERHEAD + 4
+        } else {
+            CIPHERTEXT_OVERHEAD
+        }
+    }
+
+    /// True once the final chunk has been encrypted.
+    pub fn is_shut_down(&self) -> bool {
+        self.cipher.is_none()
+    }
+
+    /// Encrypts the bytes after the 4-byte placeholder at the start of `bytes`,
+    /// and fills in the placeholder with the chunk's length prefix.
+    /// See [`ChunkEncryptor::next_job()`].
+    pub fn encrypt_chunk(&mut self, bytes: &mut HelperBuf, is_last: bool) -> std::io::Result<()> {
+        self.next_job(&mut bytes.buf, is_last)?
+            .encrypt(&mut bytes.buf)
+    }
+
+    /// Pads `chunk` if padding is on, and returns the job of encrypting it,
+    /// so that it can be done on another thread.
+    /// `chunk` must start with a 4-byte placeholder for its length prefix.
+    /// Chunks must be sent in the order of their jobs.
+    ///
+    /// If `is_last`, the chunk is marked as the final one, and nothing can be encrypted after it.
+    /// Otherwise, switches to the next key after the chunk if the current one reached its limit.
+    pub fn next_job(&mut self, chunk: &mut BytesMut, is_last: bool) -> std::io::Result<ChunkJob> {
+        let Some(cipher) = self.cipher.clone() else {
+            return Err(shut_down_error());
+        };
+        let position = self.chunks_encrypted;
+        let padded = self.padding && self.pad(chunk);
+
+        self.chunks_encrypted += 1;
+        self.bytes_encrypted += (chunk.len() - 4) as u64;
+        let rekey = !is_last
+            && (self.chunks_encrypted >= self.rekey_chunks.min(MAX_CHUNKS_PER_KEY)
+                || self.bytes_encrypted >= self.rekey_bytes);
+
+        let flags = if is_last {
+            self.cipher = None;
+            LAST_CHUNK
+        } else if rekey {
+            self.key = keys::next_key(self.key);
+            self.cipher = Some(Arc::new(StreamCipher::new(
+                self.suite,
+                &self.key,
+                &self.nonce,
+            )));
+            self.chunks_encrypted = 0;
+            self.bytes_encrypted = 0;
+            REKEY_CHUNK
+        } else {
+            0
+        };
+
+        Ok(ChunkJob {
+            cipher,
+            position,
+            flags: if padded { flags | PADDED_CHUNK } else { flags },
+        })
+    }
+
+    /// Pads the data in `chunk`, after its placeholder, then appends the length of the data.
+    /// Returns false if they don't fit in a chunk, which only happens
+    /// to a chunk filled before padding was turned on.
+    /// Such a chunk is full, so its length doesn't reveal anything anyway.
+    fn pad(&self, chunk: &mut BytesMut) -> bool {
+        let data_len = chunk.len() - 4;
+        let unpadded_size = chunk.len() + 4 + CIPHERTEXT_OVERHEAD;
+        if unpadded_size > self.chunk_size {
+            return false;
+        }
+
+        let padded_size = unpadded_size
+            .next_power_of_two()
+            .max(MIN_PADDED_CHUNK_SIZE)
+            .min(self.chunk_size);
+        chunk.resize(padded_size - 4 - CIPHERTEXT_OVERHEAD, 0);
+        chunk.extend_from_slice(&u32::try_from(data_len).unwrap().to_be_bytes());
+        true
+    }
+}
+
+/// Decrypts the chunks of one direction of a stream,
+/// switching keys along with the writer. Shared by the async and blocking readers.
+pub struct ChunkDecryptor {
+    /// `None` once the final chunk has been decrypted
+    cipher: Option<Arc<StreamCipher>>,
+    suite: CipherSuite,
+    /// Key of the current `cipher`
+    key: [u8; 32],
+    nonce: Vec<u8>,
+    /// STREAM position of the next chunk
+    position: u32,
+    /// Size of the writer's chunks, including their length prefix and authentication tag
+    chunk_size: usize,
+}
+
+impl ChunkDecryptor {
+    /// Returns the length of the header whose first [`HEADER_PREFIX_LEN`] bytes are `prefix`,
+    /// which depends on the writer's cipher suite.
+    pub fn header_len(prefix: &[u8]) -> std::io::Result<usize> {
+        Ok(HEADER_PREFIX_LEN + CipherSuite::from_id(prefix[0])?.nonce_len())
+    }
+
+    /// Returns a decryptor for the stream whose writer sent `header`.
+    ///
+    /// Returns an error if the writer's cipher suite or chunk size
+    /// isn't accepted by `builder`.
+    /// Given `peer_offer`, the offer received from the writer's peer,
+    /// also returns an error unless the header repeats both offers
+    /// and the suite negotiated from them.
+    pub fn new(
+        shared_key: [u8; 32],
+        is_creator: bool,
+        header: &[u8],
+        builder: &Builder,
+        peer_offer: Option<Offer>,
+    ) -> std::io::Result<Self> {
+        let suite = CipherSuite::from_id(header[0])?;
+        let chunk_size = u32::from_be_bytes(header[1..5].try_into().unwrap()) as usize;
+        let writer_offer = Offer::from_bytes(header[5..7].try_into().unwrap());
+        let received_offer = Offer::option_from_bytes(header[7..9].try_into().unwrap());
+        let nonce = header[HEADER_PREFIX_LEN..].to_vec();
+        let max_chunk_size = builder.max_chunk_size;
+
+        if builder.cipher_suites & suite.bit() == 0 {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Peer chose cipher suite {suite:?}, which isn't allowed"),
+            ));
+        }
+
+        if let Some(peer_offer) = peer_offer {
+            let offer = Offer::new(builder.cipher_suites);
+            if writer_offer != peer_offer
+                || received_offer != Some(offer)
+                || suite != offer.negotiate(peer_offer)?
+            {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Cipher suite offers were tampered with",
+                ));
+            }
+        }
+
+        if chunk_size < MIN_CHUNK_SIZE {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Peer's chunk size of {chunk_size} bytes is below the minimum of {MIN_CHUNK_SIZE}"),
+            ));
+        }
+        if chunk_size > max_chunk_size {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Peer's chunk size of {chunk_size} bytes is above the maximum of {max_chunk_size}"),
+            ));
+        }
+
+        let key = keys::direction_key(shared_key, !is_creator, header);
+        Ok(Self {
+            cipher: Some(Arc::new(StreamCipher::new(suite, &key, &nonce))),
+            suite,
+            key,
+            nonce,
+            position: 0,
+            chunk_size,
+        })
+    }
+
+    pub fn suite(&self) -> CipherSuite {
+        self.suite
+    }
+
+    /// Size of the writer's chunks, including their length prefix and authentication tag.
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// True once the final chunk has been decrypted,
+    /// or handed out with [`ChunkDecryptor::next_chunk()`].
+    pub fn is_finished(&self) -> bool {
+        self.cipher.is_none()
+    }
+
+    /// Decrypts every full chunk at the start of `ciphertext` into `cleartext`,
+    /// until `cleartext` runs out of space.
+    pub fn decrypt_full_chunks(
+        &mut self,
+        ciphertext: &mut HelperBuf,
+        cleartext: &mut HelperBuf,
+    ) -> std::io::Result<()> {
+        while let Some((msg, flags)) = peek_cipher_chunk(ciphertext, self.chunk_size - 4)? {
+            let msg_len = msg.len();
+            if cleartext.spare_capacity_len() < msg_len {
+                return Ok(());
+            }
+
+            let mut decryption_space = cleartext.buf.split_off(cleartext.buf.len());
+
+            decryption_space.extend_from_slice(msg);
+
+            ciphertext.advance_cursor(msg_len + 4);
+
+            self.next_job(flags)?.decrypt(&mut decryption_space)?;
+
+            cleartext.buf.unsplit(decryption_space);
+        }
+
+        if ciphertext.spare_capacity_len() == 0 {
+            ciphertext.wrap();
+        }
+
+        Ok(())
+    }
+
+    /// Takes the next full chunk out of `ciphertext`,
+    /// and returns it with the job of decrypting it on another thread.
+    /// Chunks must be read in the order of their jobs.
+    ///
+    /// Returns `None` if the next chunk hasn't fully arrived yet.
+    #[cfg(feature = "tokio")]
+    pub fn next_chunk(
+        &mut self,
+        ciphertext: &mut HelperBuf,
+    ) -> std::io::Result<Option<(ChunkJob, BytesMut)>> {
+        let Some((msg, flags)) = peek_cipher_chunk(ciphertext, self.chunk_size - 4)? else {
+            if ciphertext.spare_capacity_len() == 0 {
+                ciphertext.wrap();
+            }
+            return Ok(None);
+        };
+
+        let msg = BytesMut::from(msg);
+        ciphertext.advance_cursor(msg.len() + 4);
+        Ok(Some((self.next_job(flags)?, msg)))
+    }
+
+    /// Returns the job of decrypting the next chunk, whose length prefix had `flags`.
+    fn next_job(&mut self, flags: u32) -> std::io::Result<ChunkJob> {
+        let Some(cipher) = self.cipher.clone() else {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "Received data after the final chunk",
+            ));
+        };
+        let position = self.position;
+
+        match flags & !PADDED_CHUNK {
+            0 => self.position += 1,
+            LAST_CHUNK => self.cipher = None,
+            // the writer switched to the next key after this chunk
+            REKEY_CHUNK => {
+                self.key = keys::next_key(self.key);
+                self.cipher = Some(Arc::new(StreamCipher::new(
+                    self.suite,
+                    &self.key,
+                    &self.nonce,
+                )));
+                self.position = 0;
+            }
+            _ => {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Invalid chunk flags",
+                ))
+            }
+        }
+
+        Ok(ChunkJob {
+            cipher,
+            position,
+            flags,
+        })
+    }
+}
+
+/// Encrypts or decrypts one chunk, on any thread.
+///
+/// A chunk with [`LAST_CHUNK`] or [`REKEY_CHUNK`] is the last one encrypted with its key,
+/// so it's encrypted with the STREAM construction's last-block flag.
+/// The flags are also authenticated as the chunk's associated data.
+pub struct ChunkJob {
+    cipher: Arc<StreamCipher>,
+    position: u32,
+    flags: u32,
+}
+
+impl ChunkJob {
+    /// Encrypts the bytes after the 4-byte placeholder at the start of `chunk`,
+    /// and fills in the placeholder with the chunk's length prefix.
+    pub fn encrypt(self, chunk: &mut BytesMut) -> std::io::Result<()> {
+        let mut msg = chunk.split_off(4);
+        let associated_data = self.flags.to_be_bytes();
+        self.cipher
+            .encrypt_in_place(
+                self.position,
+                self.is_last_block(),
+                &associated_data,
+                &mut msg,
+            )
+            .map_err(|_| std::io::Error::new(ErrorKind::InvalidData, "Encryption error"))?;
+
+        let len = u32::try_from(msg.len()).unwrap() | self.flags;
+        chunk.copy_from_slice(&len.to_be_bytes());
+        chunk.unsplit(msg);
+        Ok(())
+    }
+
+    /// Decrypts `msg`, a chunk without its length prefix, in place,
+    /// and removes its padding.
+    pub fn decrypt(self, msg: &mut BytesMut) -> std::io::Result<()> {
+        let associated_data = self.flags.to_be_bytes();
+        self.cipher
+            .decrypt_in_place(self.position, self.is_last_block(), &associated_data, msg)
+            .map_err(|_| std::io::Error::new(ErrorKind::InvalidData, "Decryption error"))?;
+
+        if self.flags & PADDED_CHUNK != 0 {
+            let invalid = || std::io::Error::new(ErrorKind::InvalidData, "Invalid padding");
+            let len_start = msg.len().checked_sub(4).ok_or_else(invalid)?;
+            let data_len = u32::from_be_bytes(msg[len_start..].try_into().unwrap()) as usize;
+            if data_len > len_start {
+                return Err(invalid());
+            }
+            msg.truncate(data_len);
+        }
+        Ok(())
+    }
+
+    fn is_last_block(&self) -> bool {
+        self.flags & (LAST_CHUNK | REKEY_CHUNK) != 0
+    }
+}
+
+/// Returns the next full chunk in `buf`, and the flags set in its length prefix,
+/// or `None` if it hasn't fully arrived yet.
+///
+/// Returns an error as soon as the length prefix arrives if the length is impossible,
+/// or longer than `max_len`, rather than waiting for a chunk that wouldn't fit in the buffers.
+fn peek_cipher_chunk(buf: &HelperBuf, max_len: usize) -> std::io::Result<Option<(&[u8], u32)>> {
+    let Some(header) = buf.data().get(0..4) else {
+        return Ok(None);
+    };
+    let header = u32::from_be_bytes(header.try_into().unwrap());
+    let len = (header & !CHUNK_FLAGS) as usize;
+
+    if len < CIPHERTEXT_OVERHEAD {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Chunk of {len} bytes is too short to hold its authentication tag"),
+        ));
+    }
+    if len > max_len {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Chunk of {len} bytes is longer than the maximum of {max_len}"),
+        ));
+    }
+
+    Ok(buf
+        .data()
+        .get(4..4 + len)
+        .map(|msg| (msg, header & CHUNK_FLAGS)))
+}
+
+pub fn shut_down_error() -> std::io::Error {
+    std::io::Error::new(
+        ErrorKind::BrokenPipe,
+        "Wrote to a shut down EncryptedWriter",
+    )
+}
@@ -0,0 +1,35 @@
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+/// Derives the key for data sent by the creator if `from_creator`, or by the joiner otherwise.
+///
+/// Each direction gets its own key, so a stream reflected back to its sender
+/// can't be decrypted, and both directions choosing the same nonce doesn't reuse a keystream.
+///
+/// The key also depends on the `header` the writer sent,
+/// so a header changed on the way, such as a raised chunk size, fails to decrypt.
+pub fn direction_key(shared_key: [u8; 32], from_creator: bool, header: &[u8]) -> [u8; 32] {
+    let label: &[u8] = if from_creator {
+        b"gday encryption creator to joiner"
+    } else {
+        b"gday encryption joiner to creator"
+    };
+
+    expand(shared_key, &[label, header].concat())
+}
+
+/// Derives the key that replaces `key` once it has encrypted enough data.
+///
+/// The old key can't be recovered from the new one,
+/// so a key leaked later doesn't expose the data encrypted before it.
+pub fn next_key(key: [u8; 32]) -> [u8; 32] {
+    expand(key, b"gday encryption rekey")
+}
+
+fn expand(key: [u8; 32], info: &[u8]) -> [u8; 32] {
+    let mut output = [0; 32];
+    Hkdf::<Sha256>::new(None, &key)
+        .expand(info, &mut output)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    output
+}
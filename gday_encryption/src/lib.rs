@@ -1,17 +1,92 @@
+// nothing uses the shared chunk format without either of the streams
+#![cfg_attr(not(any(feature = "tokio", feature = "blocking")), allow(dead_code))]
+
+mod builder;
+mod cipher;
+mod keys;
+#[cfg(feature = "tokio")]
+mod pipeline;
+#[cfg(feature = "tokio")]
+mod pool;
+#[cfg(feature = "tokio")]
 mod reader;
+#[cfg(feature = "tokio")]
+mod stream;
+mod suite;
+#[cfg(feature = "tokio")]
 mod writer;
 
-#[cfg(test)]
+#[cfg(feature = "blocking")]
+pub mod blocking;
+
+#[cfg(all(test, feature = "tokio"))]
 mod tests;
 
+pub use builder::Builder;
 use bytes::BytesMut;
+#[cfg(feature = "tokio")]
 pub use reader::EncryptedReader;
+#[cfg(feature = "tokio")]
+pub use stream::EncryptedStream;
+pub use suite::CipherSuite;
+#[cfg(feature = "tokio")]
 pub use writer::EncryptedWriter;
 
-const MAX_CHUNK_SIZE: usize = 8 * 1024;
+/// Size of the chunks an [`EncryptedWriter`] sends by default,
+/// including their length prefix and authentication tag.
+const DEFAULT_CHUNK_SIZE: usize = 8 * 1024;
+
+/// Smallest chunk size a [`Builder`] accepts.
+const MIN_CHUNK_SIZE: usize = 64;
+
+/// Largest chunk size a [`Builder`] accepts,
+/// and the largest an [`EncryptedReader`] accepts from its writer by default.
+const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Length of the start of the header a writer sends before its first chunk:
+/// the ID of its [`CipherSuite`], the size of its chunks as a big-endian `u32`,
+/// then the suites it offered and the offer it received from the reader, if any.
+/// The rest of the header is the nonce, whose length depends on the suite.
+///
+/// The whole header goes into the derivation of the writer's key,
+/// so a reader can't decrypt anything if any of it was tampered with.
+const HEADER_PREFIX_LEN: usize = 5 + 2 * suite::Offer::LEN;
+
+/// Length of the longest header, with a 20-byte XChaCha20-Poly1305 nonce.
+const MAX_HEADER_LEN: usize = HEADER_PREFIX_LEN + 20;
 
 const CIPHERTEXT_OVERHEAD: usize = 16;
 
+/// Set in the length prefix of the final chunk of a stream,
+/// which is encrypted with the STREAM construction's last-block flag.
+/// A reader that reaches EOF before this chunk knows the stream was cut short.
+const LAST_CHUNK: u32 = 1 << 31;
+
+/// Set in the length prefix of the last chunk encrypted with a key,
+/// which is also encrypted with the last-block flag.
+/// Both ends then ratchet to the next key with [`keys::next_key()`].
+const REKEY_CHUNK: u32 = 1 << 30;
+
+/// Set in the length prefix of a chunk padded to hide the length of its data.
+/// Its cleartext ends with the length of the data as a big-endian `u32`,
+/// and is otherwise zeros after the data.
+const PADDED_CHUNK: u32 = 1 << 29;
+
+/// Flags that can be set in the length prefix of a chunk.
+/// They're authenticated as the chunk's associated data,
+/// so a [`REKEY_CHUNK`] can't be passed off as a [`LAST_CHUNK`].
+const CHUNK_FLAGS: u32 = LAST_CHUNK | REKEY_CHUNK | PADDED_CHUNK;
+
+/// Smallest size padded chunks are padded to, including their length prefix and tag.
+/// Larger ones are padded to the next power of 2, up to the chunk size.
+const MIN_PADDED_CHUNK_SIZE: usize = 256;
+
+/// Number of chunks an [`EncryptedWriter`] encrypts with one key by default.
+const DEFAULT_REKEY_CHUNKS: u32 = 1 << 24;
+
+/// Number of bytes an [`EncryptedWriter`] encrypts with one key by default.
+const DEFAULT_REKEY_BYTES: u64 = 1 << 32;
+
 struct HelperBuf {
     buf: BytesMut,
     cursor: usize,
@@ -0,0 +1,141 @@
+use bytes::{Buf, BytesMut};
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io::ErrorKind,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::io::AsyncWrite;
+use tokio::sync::oneshot;
+
+use crate::cipher::ChunkJob;
+use crate::pool::Pool;
+
+/// A chunk being encrypted or decrypted on the [`Pool`]
+type InFlight = oneshot::Receiver<std::io::Result<BytesMut>>;
+
+/// Encrypts the chunks of an [`EncryptedWriter`](crate::EncryptedWriter) on a [`Pool`],
+/// and writes them out in the order they were handed in.
+pub struct WritePipeline {
+    pool: Pool,
+    /// Chunks being encrypted, oldest first
+    in_flight: VecDeque<InFlight>,
+    /// Rest of the oldest encrypted chunk, being written to the inner writer
+    outgoing: BytesMut,
+}
+
+impl WritePipeline {
+    pub fn new(threads: usize) -> Self {
+        Self {
+            pool: Pool::new(threads),
+            in_flight: VecDeque::new(),
+            outgoing: BytesMut::new(),
+        }
+    }
+
+    /// Starts encrypting `chunk`, which begins with a 4-byte placeholder.
+    /// Only call this when [`WritePipeline::poll_write_out()`] says there's room.
+    pub fn encrypt(&mut self, job: ChunkJob, mut chunk: BytesMut) {
+        let encrypted = self
+            .pool
+            .run(move || job.encrypt(&mut chunk).map(|()| chunk));
+        self.in_flight.push_back(encrypted);
+    }
+
+    /// Writes encrypted chunks to `writer` as they become ready.
+    ///
+    /// If `all`, returns once every chunk has been written.
+    /// Otherwise, returns once there's room to encrypt another chunk,
+    /// which keeps every thread busy without buffering unboundedly.
+    pub fn poll_write_out(
+        &mut self,
+        mut writer: Pin<&mut impl AsyncWrite>,
+        cx: &mut Context<'_>,
+        all: bool,
+    ) -> Poll<std::io::Result<()>> {
+        loop {
+            let has_room = self.in_flight.len() < 2 * self.pool.threads();
+
+            while !self.outgoing.is_empty() {
+                let poll = writer.as_mut().poll_write(cx, &self.outgoing);
+                if poll.is_pending() && !all && has_room {
+                    return Poll::Ready(Ok(()));
+                }
+                let bytes_wrote = ready!(poll)?;
+                if bytes_wrote == 0 {
+                    return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+                }
+                self.outgoing.advance(bytes_wrote);
+            }
+
+            let Some(oldest) = self.in_flight.front_mut() else {
+                return Poll::Ready(Ok(()));
+            };
+            match Pin::new(oldest).poll(cx) {
+                Poll::Ready(encrypted) => {
+                    self.in_flight.pop_front();
+                    self.outgoing = encrypted.unwrap_or_else(|_| Err(pool_error()))?;
+                }
+                Poll::Pending if !all && has_room => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Decrypts the chunks of an [`EncryptedReader`](crate::EncryptedReader) on a [`Pool`],
+/// and hands them back in the order they were handed in.
+pub struct ReadPipeline {
+    pool: Pool,
+    /// Chunks being decrypted, oldest first
+    in_flight: VecDeque<InFlight>,
+    /// Error in the ciphertext after the chunks in flight
+    error: Option<std::io::Error>,
+}
+
+impl ReadPipeline {
+    pub fn new(threads: usize) -> Self {
+        Self {
+            pool: Pool::new(threads),
+            in_flight: VecDeque::new(),
+            error: None,
+        }
+    }
+
+    /// True if another chunk can be handed in.
+    pub fn has_room(&self) -> bool {
+        self.in_flight.len() < 2 * self.pool.threads() && self.error.is_none()
+    }
+
+    /// True if an error is waiting behind the chunks in flight.
+    pub fn has_failed(&self) -> bool {
+        self.error.is_some()
+    }
+
+    /// Starts decrypting `msg`, a chunk without its length prefix.
+    pub fn decrypt(&mut self, job: ChunkJob, mut msg: BytesMut) {
+        let decrypted = self.pool.run(move || job.decrypt(&mut msg).map(|()| msg));
+        self.in_flight.push_back(decrypted);
+    }
+
+    /// Returns `error` once the chunks before it have been returned.
+    pub fn fail(&mut self, error: std::io::Error) {
+        self.error = Some(error);
+    }
+
+    /// Returns the oldest decrypted chunk, or `None` if nothing is in flight.
+    pub fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<std::io::Result<BytesMut>>> {
+        let Some(oldest) = self.in_flight.front_mut() else {
+            return Poll::Ready(self.error.take().map(Err));
+        };
+        let decrypted = ready!(Pin::new(oldest).poll(cx));
+        self.in_flight.pop_front();
+        Poll::Ready(Some(decrypted.unwrap_or_else(|_| Err(pool_error()))))
+    }
+}
+
+/// Returned if a pool thread dropped a chunk without finishing it.
+fn pool_error() -> std::io::Error {
+    std::io::Error::other("Encryption thread stopped unexpectedly")
+}
@@ -0,0 +1,53 @@
+use std::sync::{mpsc, Arc, Mutex};
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads that encrypt or decrypt chunks off the async task.
+///
+/// The threads exit once the pool is dropped and they finish their jobs.
+pub struct Pool {
+    jobs: mpsc::Sender<Job>,
+    threads: usize,
+}
+
+impl Pool {
+    /// Starts `threads` threads.
+    pub fn new(threads: usize) -> Self {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..threads {
+            let receiver = receiver.clone();
+            std::thread::spawn(move || loop {
+                // release the lock before running the job
+                let job = receiver.lock().unwrap().recv();
+                match job {
+                    Ok(job) => job(),
+                    Err(mpsc::RecvError) => return,
+                }
+            });
+        }
+
+        Self { jobs, threads }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Runs `f` on one of the threads, and returns a receiver for its result.
+    pub fn run<R: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> R + Send + 'static,
+    ) -> oneshot::Receiver<R> {
+        let (sender, receiver) = oneshot::channel();
+        let job = Box::new(move || {
+            // the receiver may have been dropped along with its reader or writer
+            let _ = sender.send(f());
+        });
+        // the threads only stop once the sender is dropped
+        self.jobs.send(job).unwrap();
+        receiver
+    }
+}
@@ -1,5 +1,4 @@
 #![allow(dead_code)]
-use chacha20poly1305::{aead::stream::DecryptorLE31, ChaCha20Poly1305};
 use pin_project::pin_project;
 use std::{
     io::ErrorKind,
@@ -8,46 +7,79 @@ use std::{
 };
 use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, ReadBuf};
 
-use crate::{HelperBuf, MAX_CHUNK_SIZE};
+use crate::cipher::ChunkDecryptor;
+use crate::pipeline::ReadPipeline;
+use crate::suite::Offer;
+use crate::{Builder, CipherSuite, HelperBuf, HEADER_PREFIX_LEN, MAX_HEADER_LEN};
 
 pub trait AsyncReadable: AsyncRead + Send + Unpin {}
 impl<T: AsyncRead + Send + Unpin> AsyncReadable for T {}
 
-fn peek_cipher_chunk(buf: &HelperBuf) -> Option<&[u8]> {
-    if let Some(len) = buf.data().get(0..4) {
-        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
-        buf.data().get(4..4 + len)
-    } else {
-        None
-    }
-}
-
 #[pin_project]
 pub struct EncryptedReader<T: AsyncReadable> {
     #[pin]
     reader: T,
-    decryptor: DecryptorLE31<ChaCha20Poly1305>,
+    decryptor: ChunkDecryptor,
     cleartext: HelperBuf,
     ciphertext: HelperBuf,
+    /// Decrypts chunks on other threads if [`Builder::pipeline_threads()`] isn't 0.
+    /// Then `cleartext` holds the latest decrypted chunk.
+    pipeline: Option<ReadPipeline>,
 }
 
 impl<T: AsyncReadable> EncryptedReader<T> {
-    pub async fn new(mut reader: T, shared_key: [u8; 32]) -> std::io::Result<Self> {
-        let mut nonce = [0; 8];
-        reader.read_exact(&mut nonce).await?;
+    /// Decrypts with a key derived from `shared_key` for the peer's direction.
+    /// `is_creator` must be true on exactly one of the two peers,
+    /// whose [`EncryptedWriter`](crate::EncryptedWriter)s are given the same value.
+    ///
+    /// Accepts chunks of up to 1 MiB. Use a [`Builder`] to lower the limit.
+    pub async fn new(
+        reader: T,
+        shared_key: [u8; 32],
+        is_creator: bool,
+    ) -> std::io::Result<Self> {
+        Self::with_builder(reader, shared_key, is_creator, &Builder::new(), None).await
+    }
+
+    pub(crate) async fn with_builder(
+        mut reader: T,
+        shared_key: [u8; 32],
+        is_creator: bool,
+        builder: &Builder,
+        peer_offer: Option<Offer>,
+    ) -> std::io::Result<Self> {
+        let mut header = [0; MAX_HEADER_LEN];
+        reader.read_exact(&mut header[..HEADER_PREFIX_LEN]).await?;
+        let header_len = ChunkDecryptor::header_len(&header)?;
+        reader.read_exact(&mut header[HEADER_PREFIX_LEN..header_len]).await?;
+        let decryptor = ChunkDecryptor::new(
+            shared_key,
+            is_creator,
+            &header[..header_len],
+            builder,
+            peer_offer,
+        )?;
 
-        let decryptor = DecryptorLE31::new(&shared_key.into(), &nonce.into());
         Ok(Self {
             reader,
+            cleartext: HelperBuf::with_capacity(decryptor.chunk_size()),
+            ciphertext: HelperBuf::with_capacity(decryptor.chunk_size() * 2),
             decryptor,
-            cleartext: HelperBuf::with_capacity(MAX_CHUNK_SIZE),
-            ciphertext: HelperBuf::with_capacity(MAX_CHUNK_SIZE * 2),
+            pipeline: match builder.pipeline_threads {
+                0 => None,
+                threads => Some(ReadPipeline::new(threads)),
+            },
         })
     }
 
+    /// The cipher suite the writer chose.
+    pub fn cipher_suite(&self) -> CipherSuite {
+        self.decryptor.suite()
+    }
+
     /// Reads data from the inner reader into self.ciphertext,
-    /// and returns the poll returned by the inner reader.
-    fn inner_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+    /// and returns how many bytes were read.
+    fn inner_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<usize>> {
         let this = self.as_mut().project();
 
         let old_cipherbuf_len = this.ciphertext.buf.len();
@@ -55,40 +87,17 @@ impl<T: AsyncReadable> EncryptedReader<T> {
         let mut read_buf = ReadBuf::uninit(spare);
         ready!(this.reader.poll_read(cx, &mut read_buf))?;
 
-        let new_len = old_cipherbuf_len + read_buf.filled().len();
+        let bytes_read = read_buf.filled().len();
+        let new_len = old_cipherbuf_len + bytes_read;
         unsafe { this.ciphertext.buf.set_len(new_len) }
 
-        Poll::Ready(Ok(()))
+        Poll::Ready(Ok(bytes_read))
     }
 
     /// true if decrypted all full chunks, false otherwise
     fn decrypt_all_full_chunks(self: Pin<&mut Self>) -> std::io::Result<()> {
         let this = self.project();
-        while let Some(msg) = peek_cipher_chunk(this.ciphertext) {
-            let msg_len = msg.len();
-            if this.cleartext.spare_capacity_len() < msg_len {
-                return Ok(());
-            }
-   
-            let mut decryption_space = this.cleartext.buf.split_off(this.cleartext.buf.len());
-
-            decryption_space.extend_from_slice(msg);
-
-            this.ciphertext.advance_cursor(msg_len + 4);
-
-
-            this.decryptor
-                .decrypt_next_in_place(&[], &mut decryption_space)
-                .map_err(|_| std::io::Error::new(ErrorKind::InvalidData, "Decryption error"))?;
-
-            this.cleartext.buf.unsplit(decryption_space);
-        }
-
-        if this.ciphertext.spare_capacity_len() == 0 {
-            this.ciphertext.wrap();
-        }
-
-        Ok(())
+        this.decryptor.decrypt_full_chunks(this.ciphertext, this.cleartext)
     }
 
     /// True if eof, false if not. Stops reading when cleartext has length at least wanted_bytes
@@ -97,8 +106,12 @@ impl<T: AsyncReadable> EncryptedReader<T> {
         cx: &mut Context<'_>,
         wanted_bytes: Option<usize>,
     ) -> Poll<std::io::Result<bool>> {
-        debug_assert!(self.cleartext.buf.capacity() == MAX_CHUNK_SIZE);
-        debug_assert!(self.ciphertext.buf.capacity() == 2 * MAX_CHUNK_SIZE);
+        if self.pipeline.is_some() {
+            return self.read_pipelined(cx);
+        }
+
+        debug_assert!(self.cleartext.buf.capacity() == self.decryptor.chunk_size());
+        debug_assert!(self.ciphertext.buf.capacity() == 2 * self.decryptor.chunk_size());
 
         let mut bytes_amount =
             self.cleartext.buf.capacity();
@@ -109,6 +122,11 @@ impl<T: AsyncReadable> EncryptedReader<T> {
 
         self.as_mut().decrypt_all_full_chunks()?;
 
+        // the writer authenticated the end of the stream
+        if self.decryptor.is_finished() && self.cleartext.data().is_empty() {
+            return Poll::Ready(Ok(true));
+        }
+
         while self.cleartext.data().len() < bytes_amount && self.ciphertext.spare_capacity_len() != 0 {
             let poll = self.as_mut().inner_read(cx)?;
 
@@ -118,10 +136,20 @@ impl<T: AsyncReadable> EncryptedReader<T> {
                 } else {
                     break;
                 }
-            } else if self.ciphertext.data().is_empty() && self.cleartext.data().is_empty() {
-                return Poll::Ready(Ok(true));
+            } else if poll == Poll::Ready(0) {
+                if !self.cleartext.data().is_empty() {
+                    break;
+                }
+                // EOF before the final chunk means the stream was cut short
+                return Poll::Ready(Err(std::io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "Stream ended before its final chunk",
+                )));
             } else {
                 self.as_mut().decrypt_all_full_chunks()?;
+                if self.decryptor.is_finished() && self.cleartext.data().is_empty() {
+                    return Poll::Ready(Ok(true));
+                }
             }
         }
 
@@ -129,6 +157,59 @@ impl<T: AsyncReadable> EncryptedReader<T> {
 
         Poll::Ready(Ok(false))
     }
+
+    /// Like [`EncryptedReader::read_if_necessary()`], but decrypts on the pipeline.
+    /// Keeps its threads busy by reading ahead while they decrypt.
+    fn read_pipelined(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        loop {
+            let this = self.as_mut().project();
+            let pipeline = this.pipeline.as_mut().unwrap();
+
+            if !this.cleartext.data().is_empty() {
+                return Poll::Ready(Ok(false));
+            }
+
+            while pipeline.has_room() {
+                match this.decryptor.next_chunk(this.ciphertext) {
+                    Ok(Some((job, msg))) => pipeline.decrypt(job, msg),
+                    Ok(None) => break,
+                    // the chunks before the invalid one are still returned
+                    Err(err) => pipeline.fail(err),
+                }
+            }
+
+            let is_idle = match pipeline.poll_next(cx) {
+                Poll::Ready(Some(msg)) => {
+                    *this.cleartext = HelperBuf { buf: msg?, cursor: 0 };
+                    continue;
+                }
+                // the writer authenticated the end of the stream
+                Poll::Ready(None) if this.decryptor.is_finished() => return Poll::Ready(Ok(true)),
+                Poll::Ready(None) => true,
+                // nothing more to read until the oldest chunk is decrypted
+                Poll::Pending
+                    if this.decryptor.is_finished()
+                        || pipeline.has_failed()
+                        || !pipeline.has_room() =>
+                {
+                    return Poll::Pending
+                }
+                Poll::Pending => false,
+            };
+
+            if ready!(self.as_mut().inner_read(cx))? == 0 {
+                if !is_idle {
+                    // woken once the oldest chunk is decrypted
+                    return Poll::Pending;
+                }
+                // EOF before the final chunk means the stream was cut short
+                return Poll::Ready(Err(std::io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "Stream ended before its final chunk",
+                )));
+            }
+        }
+    }
 }
 
 impl<T: AsyncReadable> AsyncRead for EncryptedReader<T> {
@@ -0,0 +1,161 @@
+use std::{
+    io::IoSlice,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{
+    AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf,
+};
+
+use crate::suite::Offer;
+use crate::{Builder, CipherSuite, EncryptedReader, EncryptedWriter};
+
+/// Encrypts both directions of `T`, such as a socket to the peer.
+///
+/// Made of an [`EncryptedReader`] and an [`EncryptedWriter`] over the halves of `T`,
+/// which [`EncryptedStream::split()`] and [`EncryptedStream::into_split()`] hand out
+/// to read and write from different tasks.
+pub struct EncryptedStream<T: AsyncRead + AsyncWrite + Send + Unpin> {
+    reader: EncryptedReader<ReadHalf<T>>,
+    writer: EncryptedWriter<WriteHalf<T>>,
+}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> EncryptedStream<T> {
+    /// Agrees on a [`CipherSuite`] with the peer, which must also use an `EncryptedStream`.
+    /// Then sends this peer's nonce and receives the peer's at the same time,
+    /// so that both peers can call this at once without waiting on each other.
+    ///
+    /// To negotiate over the separate halves of a socket,
+    /// pass them joined with [`tokio::io::join()`].
+    ///
+    /// `is_creator` must be true on exactly one of the two peers.
+    ///
+    /// Use a [`Builder`] to configure the chunk size and other options.
+    pub async fn new(stream: T, shared_key: [u8; 32], is_creator: bool) -> std::io::Result<Self> {
+        Self::with_builder(stream, shared_key, is_creator, &Builder::new()).await
+    }
+
+    pub(crate) async fn with_builder(
+        stream: T,
+        shared_key: [u8; 32],
+        is_creator: bool,
+        builder: &Builder,
+    ) -> std::io::Result<Self> {
+        let (mut read, mut write) = tokio::io::split(stream);
+
+        let offer = Offer::new(builder.cipher_suites);
+        let mut peer_offer = [0; Offer::LEN];
+        let (sent, received) = tokio::join!(
+            async {
+                write.write_all(&offer.to_bytes()).await?;
+                write.flush().await
+            },
+            read.read_exact(&mut peer_offer)
+        );
+        sent?;
+        received?;
+
+        // both directions use the agreed suite, and their headers repeat both offers,
+        // so that a tampered offer is caught by the reader it was meant for
+        let peer_offer = Some(Offer::from_bytes(peer_offer));
+        let (reader, writer) = tokio::join!(
+            EncryptedReader::with_builder(read, shared_key, is_creator, builder, peer_offer),
+            EncryptedWriter::with_builder(write, shared_key, is_creator, builder, peer_offer)
+        );
+        Ok(Self {
+            reader: reader?,
+            writer: writer?,
+        })
+    }
+
+    /// The cipher suite both directions agreed on.
+    pub fn cipher_suite(&self) -> CipherSuite {
+        self.writer.cipher_suite()
+    }
+
+    /// See [`EncryptedWriter::set_rekey_limit()`].
+    pub fn set_rekey_limit(&mut self, chunks: u32, bytes: u64) {
+        self.writer.set_rekey_limit(chunks, bytes);
+    }
+
+    /// See [`EncryptedWriter::set_defer_flushes()`].
+    pub fn set_defer_flushes(&mut self, defer: bool) {
+        self.writer.set_defer_flushes(defer);
+    }
+
+    /// See [`EncryptedWriter::set_padding()`].
+    pub fn set_padding(&mut self, padding: bool) {
+        self.writer.set_padding(padding);
+    }
+
+    /// See [`EncryptedWriter::flush_or_cover()`].
+    pub async fn flush_or_cover(&mut self) -> std::io::Result<()> {
+        self.writer.flush_or_cover().await
+    }
+
+    /// Borrows the reading and writing halves, to use them concurrently.
+    pub fn split(
+        &mut self,
+    ) -> (
+        &mut EncryptedReader<ReadHalf<T>>,
+        &mut EncryptedWriter<WriteHalf<T>>,
+    ) {
+        (&mut self.reader, &mut self.writer)
+    }
+
+    /// Splits into the reading and writing halves, to move them to different tasks.
+    pub fn into_split(self) -> (EncryptedReader<ReadHalf<T>>, EncryptedWriter<WriteHalf<T>>) {
+        (self.reader, self.writer)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> AsyncRead for EncryptedStream<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> AsyncBufRead for EncryptedStream<T> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().reader).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.reader).consume(amt);
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> AsyncWrite for EncryptedStream<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.writer).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.writer).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.writer.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    /// Ends the outgoing direction. The incoming one stays open until the peer ends it.
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.writer).poll_shutdown(cx)
+    }
+}
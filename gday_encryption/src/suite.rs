@@ -0,0 +1,232 @@
+use aead::{
+    stream::{NewStream, StreamLE31, StreamPrimitive},
+    Buffer, KeyInit,
+};
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
+use std::io::ErrorKind;
+
+/// AEAD used to encrypt the chunks of a stream.
+///
+/// Each writer announces its suite in the header of its stream,
+/// along with the suites it offered.
+/// [`EncryptedStream`](crate::EncryptedStream)s negotiate one for both directions,
+/// preferring AES-256-GCM only if both peers' CPUs accelerate it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CipherSuite {
+    ChaCha20Poly1305,
+    /// ChaCha20-Poly1305 with a 20-byte stream nonce instead of 8 bytes
+    XChaCha20Poly1305,
+    Aes256Gcm,
+}
+
+impl CipherSuite {
+    pub const ALL: [CipherSuite; 3] = [
+        CipherSuite::ChaCha20Poly1305,
+        CipherSuite::XChaCha20Poly1305,
+        CipherSuite::Aes256Gcm,
+    ];
+
+    /// Identifies the suite in stream headers.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Self::ChaCha20Poly1305 => 1,
+            Self::XChaCha20Poly1305 => 2,
+            Self::Aes256Gcm => 3,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> std::io::Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|suite| suite.id() == id)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Peer chose unknown cipher suite {id}"),
+                )
+            })
+    }
+
+    /// This suite's bit in a set of suites.
+    pub(crate) fn bit(self) -> u8 {
+        1 << self.id()
+    }
+
+    /// Length of the nonce of the STREAM construction over this suite.
+    pub(crate) fn nonce_len(self) -> usize {
+        match self {
+            Self::ChaCha20Poly1305 | Self::Aes256Gcm => 8,
+            Self::XChaCha20Poly1305 => 20,
+        }
+    }
+}
+
+/// Which suites are fastest on this CPU, from first to last.
+fn preference(fast_aes: bool) -> [CipherSuite; 3] {
+    if fast_aes {
+        [
+            CipherSuite::Aes256Gcm,
+            CipherSuite::ChaCha20Poly1305,
+            CipherSuite::XChaCha20Poly1305,
+        ]
+    } else {
+        [
+            CipherSuite::ChaCha20Poly1305,
+            CipherSuite::XChaCha20Poly1305,
+            CipherSuite::Aes256Gcm,
+        ]
+    }
+}
+
+/// True if this CPU has instructions that make AES-GCM faster than ChaCha20-Poly1305.
+pub(crate) fn has_fast_aes() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        std::arch::is_x86_feature_detected!("aes")
+            && std::arch::is_x86_feature_detected!("pclmulqdq")
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        std::arch::is_aarch64_feature_detected!("aes")
+            && std::arch::is_aarch64_feature_detected!("pmull")
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        false
+    }
+}
+
+/// The fastest suite on this CPU out of the set `suites`.
+pub(crate) fn preferred(suites: u8) -> Option<CipherSuite> {
+    preference(has_fast_aes())
+        .into_iter()
+        .find(|suite| suites & suite.bit() != 0)
+}
+
+/// What a peer tells the other before they pick a suite.
+///
+/// [`EncryptedStream`](crate::EncryptedStream)s exchange offers before their headers.
+/// Every header then repeats the writer's offer and the one it received from the reader,
+/// so that both are authenticated along with the rest of the header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Offer {
+    /// Set of suites this peer accepts
+    pub suites: u8,
+    pub fast_aes: bool,
+}
+
+impl Offer {
+    pub const LEN: usize = 2;
+
+    /// The offer of this peer, which accepts the set `suites`.
+    pub fn new(suites: u8) -> Self {
+        Self {
+            suites,
+            fast_aes: has_fast_aes(),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; Self::LEN] {
+        [self.suites, u8::from(self.fast_aes)]
+    }
+
+    pub fn from_bytes(bytes: [u8; Self::LEN]) -> Self {
+        Self {
+            suites: bytes[0],
+            fast_aes: bytes[1] & 1 != 0,
+        }
+    }
+
+    /// Encodes an offer that may not have been received, as in a header.
+    /// No offer is encoded as an empty set of suites, which no peer offers.
+    pub fn option_to_bytes(offer: Option<Self>) -> [u8; Self::LEN] {
+        offer.map_or([0; Self::LEN], Self::to_bytes)
+    }
+
+    pub fn option_from_bytes(bytes: [u8; Self::LEN]) -> Option<Self> {
+        Some(Self::from_bytes(bytes)).filter(|offer| offer.suites != 0)
+    }
+
+    /// Picks the suite both peers use, given their offers.
+    /// Gives the same answer on both peers, whichever offer is `self`.
+    pub fn negotiate(self, peer: Offer) -> std::io::Result<CipherSuite> {
+        let common = self.suites & peer.suites;
+        preference(self.fast_aes && peer.fast_aes)
+            .into_iter()
+            .find(|suite| common & suite.bit() != 0)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    "No cipher suite in common with the peer",
+                )
+            })
+    }
+}
+
+/// The STREAM construction over any [`CipherSuite`], for one key.
+///
+/// Encrypts and decrypts chunks at any position in the stream,
+/// so that chunks can be handled on several threads at once.
+pub(crate) enum StreamCipher {
+    ChaCha20Poly1305(StreamLE31<ChaCha20Poly1305>),
+    XChaCha20Poly1305(StreamLE31<XChaCha20Poly1305>),
+    /// Boxed since AES's expanded key is much larger than ChaCha20's
+    Aes256Gcm(Box<StreamLE31<Aes256Gcm>>),
+}
+
+impl StreamCipher {
+    /// `nonce` must be [`CipherSuite::nonce_len()`] bytes long.
+    pub fn new(suite: CipherSuite, key: &[u8; 32], nonce: &[u8]) -> Self {
+        match suite {
+            CipherSuite::ChaCha20Poly1305 => Self::ChaCha20Poly1305(StreamLE31::from_aead(
+                ChaCha20Poly1305::new(key.into()),
+                nonce.into(),
+            )),
+            CipherSuite::XChaCha20Poly1305 => Self::XChaCha20Poly1305(StreamLE31::from_aead(
+                XChaCha20Poly1305::new(key.into()),
+                nonce.into(),
+            )),
+            CipherSuite::Aes256Gcm => Self::Aes256Gcm(Box::new(StreamLE31::from_aead(
+                Aes256Gcm::new(key.into()),
+                nonce.into(),
+            ))),
+        }
+    }
+
+    pub fn encrypt_in_place(
+        &self,
+        position: u32,
+        last_block: bool,
+        associated_data: &[u8],
+        buffer: &mut dyn Buffer,
+    ) -> aead::Result<()> {
+        match self {
+            Self::ChaCha20Poly1305(s) => {
+                s.encrypt_in_place(position, last_block, associated_data, buffer)
+            }
+            Self::XChaCha20Poly1305(s) => {
+                s.encrypt_in_place(position, last_block, associated_data, buffer)
+            }
+            Self::Aes256Gcm(s) => s.encrypt_in_place(position, last_block, associated_data, buffer),
+        }
+    }
+
+    pub fn decrypt_in_place(
+        &self,
+        position: u32,
+        last_block: bool,
+        associated_data: &[u8],
+        buffer: &mut dyn Buffer,
+    ) -> aead::Result<()> {
+        match self {
+            Self::ChaCha20Poly1305(s) => {
+                s.decrypt_in_place(position, last_block, associated_data, buffer)
+            }
+            Self::XChaCha20Poly1305(s) => {
+                s.decrypt_in_place(position, last_block, associated_data, buffer)
+            }
+            Self::Aes256Gcm(s) => s.decrypt_in_place(position, last_block, associated_data, buffer),
+        }
+    }
+}
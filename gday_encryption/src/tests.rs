@@ -5,8 +5,8 @@ async fn test_all() {
     let key: [u8; 32] = rand::random();
     let (read_stream, write_stream) = tokio::io::duplex(100);
     let mut buf = vec![0u8; 3];
-    let mut writer = crate::EncryptedWriter::new(write_stream, key).await.unwrap();
-    let mut reader = crate::EncryptedReader::new(read_stream, key).await.unwrap();
+    let mut writer = crate::EncryptedWriter::new(write_stream, key, true).await.unwrap();
+    let mut reader = crate::EncryptedReader::new(read_stream, key, false).await.unwrap();
 
     let test_data = [b"abc", b"def", b"ghi", b"jkl", b"mno", b"prs", b"tuw", b"yzz"];
 
@@ -16,4 +16,744 @@ async fn test_all() {
         reader.read_exact(&mut buf).await.unwrap();
         assert_eq!(buf, msg[..]);
     }
-}
\ No newline at end of file
+}
+#[tokio::test]
+async fn shutdown_ends_stream() {
+    let key: [u8; 32] = rand::random();
+    let (read_stream, write_stream) = tokio::io::duplex(100);
+    let mut writer = crate::EncryptedWriter::new(write_stream, key, true).await.unwrap();
+    let mut reader = crate::EncryptedReader::new(read_stream, key, false).await.unwrap();
+
+    let (_, received) = tokio::join!(
+        async {
+            writer.write_all(b"abcdef").await.unwrap();
+            writer.shutdown().await.unwrap();
+            assert!(writer.write_all(b"ghi").await.is_err());
+        },
+        async {
+            let mut received = Vec::new();
+            reader.read_to_end(&mut received).await.unwrap();
+            received
+        }
+    );
+    assert_eq!(received, b"abcdef");
+}
+
+/// A stream that closes without the final chunk was cut short.
+#[tokio::test]
+async fn detects_truncation() {
+    let key: [u8; 32] = rand::random();
+    let (read_stream, write_stream) = tokio::io::duplex(100);
+    let mut writer = crate::EncryptedWriter::new(write_stream, key, true).await.unwrap();
+    let mut reader = crate::EncryptedReader::new(read_stream, key, false).await.unwrap();
+
+    writer.write_all(b"abc").await.unwrap();
+    writer.flush().await.unwrap();
+    drop(writer);
+
+    let mut received = Vec::new();
+    let err = reader.read_to_end(&mut received).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    assert_eq!(received, b"abc");
+}
+
+/// A peer can't decrypt its own stream reflected back at it.
+#[tokio::test]
+async fn rejects_reflected_stream() {
+    let key: [u8; 32] = rand::random();
+    let (read_stream, write_stream) = tokio::io::duplex(100);
+    let mut writer = crate::EncryptedWriter::new(write_stream, key, true).await.unwrap();
+    let mut reader = crate::EncryptedReader::new(read_stream, key, true).await.unwrap();
+
+    writer.write_all(b"abc").await.unwrap();
+    writer.flush().await.unwrap();
+
+    let mut buf = [0; 3];
+    let err = reader.read_exact(&mut buf).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+/// Both ends switch keys many times within one stream.
+#[tokio::test]
+async fn crosses_rekey_boundaries() {
+    let key: [u8; 32] = rand::random();
+    let (read_stream, write_stream) = tokio::io::duplex(1000);
+    let mut writer = crate::EncryptedWriter::new(write_stream, key, true).await.unwrap();
+    let mut reader = crate::EncryptedReader::new(read_stream, key, false).await.unwrap();
+
+    // small flushed chunks switch keys every 3 chunks,
+    // and full chunks switch keys after every chunk
+    writer.set_rekey_limit(3, 8000);
+    let data: Vec<u8> = (0..100_000).map(|_| rand::random()).collect();
+
+    let (_, received) = tokio::join!(
+        async {
+            for msg in data[..1000].chunks(10) {
+                writer.write_all(msg).await.unwrap();
+                writer.flush().await.unwrap();
+            }
+            writer.write_all(&data[1000..]).await.unwrap();
+            writer.shutdown().await.unwrap();
+        },
+        async {
+            let mut received = Vec::new();
+            reader.read_to_end(&mut received).await.unwrap();
+            received
+        }
+    );
+    assert_eq!(received, data);
+}
+
+#[tokio::test]
+async fn stream_is_full_duplex() {
+    let key: [u8; 32] = rand::random();
+    let (creator_stream, joiner_stream) = tokio::io::duplex(100);
+
+    // neither peer waits for the other's nonce before sending its own
+    let (creator, joiner) = tokio::join!(
+        crate::EncryptedStream::new(creator_stream, key, true),
+        crate::EncryptedStream::new(joiner_stream, key, false)
+    );
+    let mut creator = creator.unwrap();
+    let (mut joiner_reader, mut joiner_writer) = joiner.unwrap().into_split();
+
+    creator.write_all(b"abc").await.unwrap();
+    creator.flush().await.unwrap();
+    joiner_writer.write_all(b"def").await.unwrap();
+    joiner_writer.flush().await.unwrap();
+
+    let mut buf = [0; 3];
+    joiner_reader.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"abc");
+
+    let (creator_reader, _) = creator.split();
+    creator_reader.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"def");
+}
+
+#[cfg(feature = "blocking")]
+#[tokio::test]
+async fn blocking_writer_to_async_reader() {
+    use std::io::Write;
+
+    let key: [u8; 32] = rand::random();
+    let data: Vec<u8> = (0..30_000).map(|_| rand::random()).collect();
+
+    let mut writer = crate::blocking::EncryptedWriter::new(Vec::new(), key, true).unwrap();
+    writer.set_rekey_limit(2, 10_000);
+    writer.write_all(&data[..10]).unwrap();
+    writer.flush().unwrap();
+    writer.write_all(&data[10..]).unwrap();
+    let sent = writer.finish().unwrap();
+
+    let mut reader = crate::EncryptedReader::new(&sent[..], key, false)
+        .await
+        .unwrap();
+    let mut received = Vec::new();
+    reader.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, data);
+}
+
+#[cfg(feature = "blocking")]
+#[tokio::test]
+async fn async_writer_to_blocking_reader() {
+    use std::io::Read;
+
+    let key: [u8; 32] = rand::random();
+    let data: Vec<u8> = (0..30_000).map(|_| rand::random()).collect();
+
+    let mut sent = Vec::new();
+    let mut writer = crate::EncryptedWriter::new(&mut sent, key, true)
+        .await
+        .unwrap();
+    writer.set_rekey_limit(2, 10_000);
+    writer.write_all(&data).await.unwrap();
+    writer.shutdown().await.unwrap();
+
+    let mut reader = crate::blocking::EncryptedReader::new(&sent[..], key, false).unwrap();
+    let mut received = Vec::new();
+    reader.read_to_end(&mut received).unwrap();
+    assert_eq!(received, data);
+
+    // without the final chunk, the stream was cut short
+    let truncated = &sent[..sent.len() - 100];
+    let mut reader = crate::blocking::EncryptedReader::new(truncated, key, false).unwrap();
+    let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+}
+
+/// A length prefix longer than any chunk is rejected as soon as it arrives,
+/// instead of waiting for a chunk that would never fit.
+#[tokio::test]
+async fn rejects_oversized_chunk() {
+    let key: [u8; 32] = rand::random();
+    let (read_stream, mut write_stream) = tokio::io::duplex(100);
+
+    let too_long = crate::DEFAULT_CHUNK_SIZE as u32 - 4 + 1;
+    write_stream.write_all(&header()).await.unwrap();
+    write_stream.write_all(&too_long.to_be_bytes()).await.unwrap();
+
+    // the peer keeps the stream open without sending anything else
+    let mut reader = crate::EncryptedReader::new(read_stream, key, false).await.unwrap();
+    let err = reader.read_to_end(&mut Vec::new()).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[tokio::test]
+async fn rejects_chunk_shorter_than_tag() {
+    let key: [u8; 32] = rand::random();
+
+    let mut sent = header();
+    sent.extend_from_slice(&15_u32.to_be_bytes());
+    sent.extend_from_slice(&[0; 15]);
+
+    let err = read_stream(&sent, key).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+/// The reader refuses a writer whose chunks would be larger than it allows.
+#[tokio::test]
+async fn rejects_chunk_size_above_limit() {
+    let key: [u8; 32] = rand::random();
+
+    let mut sent = Vec::new();
+    crate::Builder::new()
+        .chunk_size(64 * 1024)
+        .writer(&mut sent, key, true)
+        .await
+        .unwrap();
+
+    let result = crate::Builder::new()
+        .max_chunk_size(16 * 1024)
+        .reader(&sent[..], key, false)
+        .await;
+    assert_eq!(result.err().unwrap().kind(), std::io::ErrorKind::InvalidData);
+}
+
+/// Every bit of the header is authenticated, including the chunk size and cipher suite.
+#[tokio::test]
+async fn flipped_header_bit_is_rejected() {
+    let key: [u8; 32] = rand::random();
+    let builder = crate::Builder::new()
+        .chunk_size(crate::MIN_CHUNK_SIZE)
+        .cipher_suites(&[crate::CipherSuite::ChaCha20Poly1305]);
+    let sent = write_stream(&[7; 500], &[(100, true)], key, builder).await;
+    assert_eq!(read_stream(&sent, key).await.unwrap(), [7; 500]);
+
+    for index in 0..header().len() {
+        for bit in 0..8 {
+            let mut flipped = sent.clone();
+            flipped[index] ^= 1 << bit;
+            assert!(
+                read_stream(&flipped, key).await.is_err(),
+                "accepted a flip of bit {bit} of header byte {index}"
+            );
+        }
+    }
+}
+
+/// Flushing while deferring flushes doesn't send a chunk for every few bytes.
+#[tokio::test]
+async fn defers_flushes() {
+    let key: [u8; 32] = rand::random();
+    let data: Vec<u8> = (0..10_000).map(|_| rand::random()).collect();
+    let writes = [(10, true)];
+
+    let flushed = write_stream(&data, &writes, key, crate::Builder::new()).await;
+    let deferred = write_stream(
+        &data,
+        &writes,
+        key,
+        crate::Builder::new().defer_flushes(true),
+    )
+    .await;
+
+    assert!(deferred.len() < flushed.len() / 2);
+    assert_eq!(read_stream(&deferred, key).await.unwrap(), data);
+}
+
+#[tokio::test]
+async fn writes_vectored() {
+    use std::io::IoSlice;
+
+    let key: [u8; 32] = rand::random();
+    let mut sent = Vec::new();
+    let mut writer = crate::Builder::new()
+        .chunk_size(64)
+        .writer(&mut sent, key, true)
+        .await
+        .unwrap();
+    assert!(tokio::io::AsyncWrite::is_write_vectored(&writer));
+
+    // a 64-byte chunk holds 44 bytes of data
+    let bufs = [IoSlice::new(&[1; 30]), IoSlice::new(&[2; 30])];
+    assert_eq!(writer.write_vectored(&bufs).await.unwrap(), 44);
+    writer.write_all(&[2; 16]).await.unwrap();
+    writer.shutdown().await.unwrap();
+
+    let mut expected = vec![1; 30];
+    expected.extend_from_slice(&[2; 30]);
+    assert_eq!(read_stream(&sent, key).await.unwrap(), expected);
+}
+
+#[tokio::test]
+async fn every_cipher_suite_round_trips() {
+    let key: [u8; 32] = rand::random();
+
+    for suite in crate::CipherSuite::ALL {
+        let mut sent = Vec::new();
+        let mut writer = crate::Builder::new()
+            .cipher_suites(&[suite])
+            .rekey_limit(2, 10_000)
+            .writer(&mut sent, key, true)
+            .await
+            .unwrap();
+        assert_eq!(writer.cipher_suite(), suite);
+        writer.write_all(&[suite.id(); 30_000]).await.unwrap();
+        writer.shutdown().await.unwrap();
+
+        let mut reader = crate::EncryptedReader::new(&sent[..], key, false)
+            .await
+            .unwrap();
+        assert_eq!(reader.cipher_suite(), suite);
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, [suite.id(); 30_000]);
+    }
+}
+
+#[tokio::test]
+async fn rejects_disallowed_cipher_suite() {
+    use crate::CipherSuite;
+    let key: [u8; 32] = rand::random();
+
+    let mut sent = Vec::new();
+    crate::Builder::new()
+        .cipher_suites(&[CipherSuite::Aes256Gcm])
+        .writer(&mut sent, key, true)
+        .await
+        .unwrap();
+
+    let result = crate::Builder::new()
+        .cipher_suites(&[CipherSuite::ChaCha20Poly1305])
+        .reader(&sent[..], key, false)
+        .await;
+    assert_eq!(result.err().unwrap().kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn negotiates_same_suite_on_both_peers() {
+    use crate::suite::Offer;
+    use crate::CipherSuite::*;
+
+    let all = Aes256Gcm.bit() | ChaCha20Poly1305.bit() | XChaCha20Poly1305.bit();
+    let offer = |suites, fast_aes| Offer { suites, fast_aes };
+
+    // AES-GCM is only preferred if both peers accelerate it
+    let cases = [
+        (offer(all, true), offer(all, true), Aes256Gcm),
+        (offer(all, true), offer(all, false), ChaCha20Poly1305),
+        (offer(all, false), offer(all, false), ChaCha20Poly1305),
+        (
+            offer(all, false),
+            offer(Aes256Gcm.bit() | XChaCha20Poly1305.bit(), false),
+            XChaCha20Poly1305,
+        ),
+        (offer(Aes256Gcm.bit(), false), offer(all, false), Aes256Gcm),
+    ];
+    for (a, b, expected) in cases {
+        assert_eq!(a.negotiate(b).unwrap(), expected);
+        assert_eq!(b.negotiate(a).unwrap(), expected);
+    }
+
+    let err = offer(Aes256Gcm.bit(), true)
+        .negotiate(offer(ChaCha20Poly1305.bit(), true))
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[tokio::test]
+async fn streams_agree_on_cipher_suite() {
+    use crate::CipherSuite;
+    let key: [u8; 32] = rand::random();
+    let (creator_stream, joiner_stream) = tokio::io::duplex(100);
+
+    let builder = crate::Builder::new()
+        .cipher_suites(&[CipherSuite::XChaCha20Poly1305, CipherSuite::Aes256Gcm]);
+    let (creator, joiner) = tokio::join!(
+        builder.stream(creator_stream, key, true),
+        crate::EncryptedStream::new(joiner_stream, key, false)
+    );
+    let mut creator = creator.unwrap();
+    let mut joiner = joiner.unwrap();
+    assert_eq!(creator.cipher_suite(), joiner.cipher_suite());
+    assert_ne!(creator.cipher_suite(), CipherSuite::ChaCha20Poly1305);
+
+    creator.write_all(b"abc").await.unwrap();
+    creator.flush().await.unwrap();
+    let mut buf = [0; 3];
+    joiner.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"abc");
+}
+
+/// Peers that allow different suites use one they both allow,
+/// even over the separate halves of a socket.
+#[tokio::test]
+async fn streams_with_different_cipher_suites_agree() {
+    use crate::CipherSuite;
+    let key: [u8; 32] = rand::random();
+    let (creator_stream, joiner_stream) = tokio::io::duplex(100);
+    let (joiner_read, joiner_write) = tokio::io::split(joiner_stream);
+
+    let creator_builder = crate::Builder::new()
+        .cipher_suites(&[CipherSuite::ChaCha20Poly1305, CipherSuite::Aes256Gcm]);
+    let joiner_builder = crate::Builder::new()
+        .cipher_suites(&[CipherSuite::XChaCha20Poly1305, CipherSuite::Aes256Gcm]);
+    let (creator, joiner) = tokio::join!(
+        creator_builder.stream(creator_stream, key, true),
+        joiner_builder.stream(tokio::io::join(joiner_read, joiner_write), key, false)
+    );
+    let (mut creator_reader, mut creator_writer) = creator.unwrap().into_split();
+    let (mut joiner_reader, mut joiner_writer) = joiner.unwrap().into_split();
+    assert_eq!(creator_writer.cipher_suite(), CipherSuite::Aes256Gcm);
+    assert_eq!(joiner_writer.cipher_suite(), CipherSuite::Aes256Gcm);
+
+    creator_writer.write_all(b"abc").await.unwrap();
+    creator_writer.flush().await.unwrap();
+    joiner_writer.write_all(b"def").await.unwrap();
+    joiner_writer.flush().await.unwrap();
+    let mut buf = [0; 3];
+    joiner_reader.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"abc");
+    creator_reader.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"def");
+}
+
+/// An offer changed on its way to the peer is caught by both peers,
+/// since their headers repeat the offers they sent and received.
+#[tokio::test]
+async fn tampered_offer_is_rejected() {
+    use crate::CipherSuite;
+    let key: [u8; 32] = rand::random();
+    let (creator_stream, mut creator_end) = tokio::io::duplex(100);
+    let (joiner_stream, mut joiner_end) = tokio::io::duplex(100);
+
+    // strips AES-GCM from the creator's offer, and forwards everything else
+    tokio::spawn(async move {
+        let (mut from_creator, mut to_creator) = tokio::io::split(&mut creator_end);
+        let (mut from_joiner, mut to_joiner) = tokio::io::split(&mut joiner_end);
+        let mut offer = [0; crate::suite::Offer::LEN];
+        from_creator.read_exact(&mut offer).await.unwrap();
+        offer[0] &= !CipherSuite::Aes256Gcm.bit();
+        to_joiner.write_all(&offer).await.unwrap();
+        let _ = tokio::join!(
+            tokio::io::copy(&mut from_creator, &mut to_joiner),
+            tokio::io::copy(&mut from_joiner, &mut to_creator)
+        );
+    });
+
+    let (creator, joiner) = tokio::join!(
+        crate::EncryptedStream::new(creator_stream, key, true),
+        crate::EncryptedStream::new(joiner_stream, key, false)
+    );
+    assert_eq!(
+        creator.err().unwrap().kind(),
+        std::io::ErrorKind::InvalidData
+    );
+    assert_eq!(
+        joiner.err().unwrap().kind(),
+        std::io::ErrorKind::InvalidData
+    );
+}
+
+#[tokio::test]
+async fn pipelined_streams_round_trip() {
+    let key: [u8; 32] = rand::random();
+    let (creator_stream, joiner_stream) = tokio::io::duplex(4096);
+    let builder = crate::Builder::new()
+        .chunk_size(1024)
+        .rekey_limit(3, 10_000)
+        .pipeline_threads(4);
+
+    let (creator, joiner) = tokio::join!(
+        builder.stream(creator_stream, key, true),
+        builder.stream(joiner_stream, key, false)
+    );
+    let data: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+
+    let exchange = |stream: crate::EncryptedStream<tokio::io::DuplexStream>| {
+        let data = data.clone();
+        async move {
+            let (mut reader, mut writer) = stream.into_split();
+            let write = async {
+                writer.write_all(&data).await.unwrap();
+                writer.shutdown().await.unwrap();
+            };
+            let read = async {
+                let mut received = Vec::new();
+                reader.read_to_end(&mut received).await.unwrap();
+                received
+            };
+            tokio::join!(write, read).1
+        }
+    };
+    let (from_joiner, from_creator) =
+        tokio::join!(exchange(creator.unwrap()), exchange(joiner.unwrap()));
+    assert_eq!(from_joiner, data);
+    assert_eq!(from_creator, data);
+}
+
+#[tokio::test]
+async fn pads_chunks_to_buckets() {
+    let key: [u8; 32] = rand::random();
+    let builder = crate::Builder::new().cipher_suites(&[crate::CipherSuite::ChaCha20Poly1305]);
+    let mut sent = Vec::new();
+    let mut writer = builder.writer(&mut sent, key, true).await.unwrap();
+    writer.set_padding(true);
+
+    for len in [2, 300, 5000] {
+        writer.write_all(&vec![b'a'; len]).await.unwrap();
+        writer.flush().await.unwrap();
+    }
+    writer.shutdown().await.unwrap();
+
+    let mut chunk_sizes = Vec::new();
+    let mut rest = &sent[header().len()..];
+    while !rest.is_empty() {
+        let prefix = u32::from_be_bytes(rest[0..4].try_into().unwrap());
+        assert_ne!(prefix & crate::PADDED_CHUNK, 0);
+        let size = 4 + (prefix & !crate::CHUNK_FLAGS) as usize;
+        chunk_sizes.push(size);
+        rest = &rest[size..];
+    }
+    // padded to powers of 2, up to the 8 KiB chunk size, including the empty final chunk
+    assert_eq!(chunk_sizes, [256, 512, 8192, 256]);
+
+    let received = read_stream(&sent, key).await.unwrap();
+    assert_eq!(received.len(), 2 + 300 + 5000);
+}
+
+#[tokio::test]
+async fn skips_cover_traffic() {
+    let key: [u8; 32] = rand::random();
+    let (creator_stream, joiner_stream) = tokio::io::duplex(4096);
+    let (creator, joiner) = tokio::join!(
+        crate::EncryptedStream::new(creator_stream, key, true),
+        crate::EncryptedStream::new(joiner_stream, key, false)
+    );
+    let mut creator = creator.unwrap();
+    let mut joiner = joiner.unwrap();
+
+    creator.flush_or_cover().await.unwrap();
+    creator.write_all(b"abc").await.unwrap();
+    creator.flush_or_cover().await.unwrap();
+    creator.flush_or_cover().await.unwrap();
+    creator.write_all(b"def").await.unwrap();
+    creator.shutdown().await.unwrap();
+
+    let mut received = Vec::new();
+    joiner.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, b"abcdef");
+}
+
+/// Turning padding on reserves 4 more bytes per chunk,
+/// which a nearly full chunk may no longer have.
+#[tokio::test]
+async fn turns_on_padding_mid_chunk() {
+    let key: [u8; 32] = rand::random();
+    let chunk_size = crate::DEFAULT_CHUNK_SIZE;
+    for threads in [0, 2] {
+        for spare in 17..=20 {
+            let builder = crate::Builder::new().pipeline_threads(threads);
+            let mut sent = Vec::new();
+            let mut writer = builder.writer(&mut sent, key, true).await.unwrap();
+
+            // the 4-byte length prefix also takes up room in the chunk
+            let first = vec![b'a'; chunk_size - 4 - spare];
+            writer.write_all(&first).await.unwrap();
+            writer.set_padding(true);
+            writer.write_all(b"bcd").await.unwrap();
+            writer.shutdown().await.unwrap();
+
+            let received = read_stream_with(&sent, key, builder).await.unwrap();
+            assert_eq!(received.len(), first.len() + 3);
+            assert!(received.ends_with(b"abcd"));
+        }
+    }
+}
+
+/// Header of a standalone ChaCha20-Poly1305 writer
+/// with a zero nonce and the default chunk size.
+fn header() -> Vec<u8> {
+    let suite = crate::CipherSuite::ChaCha20Poly1305;
+    let mut header = vec![suite.id()];
+    header.extend_from_slice(&(crate::DEFAULT_CHUNK_SIZE as u32).to_be_bytes());
+    header.extend_from_slice(&crate::suite::Offer::new(suite.bit()).to_bytes());
+    header.extend_from_slice(&crate::suite::Offer::option_to_bytes(None));
+    header.extend_from_slice(&[0; 8]);
+    header
+}
+
+/// Decrypts everything a creator sent in `sent`.
+async fn read_stream(sent: &[u8], key: [u8; 32]) -> std::io::Result<Vec<u8>> {
+    read_stream_with(sent, key, crate::Builder::new()).await
+}
+
+/// Decrypts everything a creator sent in `sent`, with a reader configured by `builder`.
+async fn read_stream_with(
+    sent: &[u8],
+    key: [u8; 32],
+    builder: crate::Builder,
+) -> std::io::Result<Vec<u8>> {
+    let mut reader = builder.reader(sent, key, false).await?;
+    let mut received = Vec::new();
+    reader.read_to_end(&mut received).await?;
+    Ok(received)
+}
+
+/// Encrypts `data` as a creator configured by `builder`, writing it in pieces of the given sizes
+/// and flushing after each piece marked `true`.
+async fn write_stream(
+    data: &[u8],
+    writes: &[(usize, bool)],
+    key: [u8; 32],
+    builder: crate::Builder,
+) -> Vec<u8> {
+    let mut sent = Vec::new();
+    let mut writer = builder.writer(&mut sent, key, true).await.unwrap();
+
+    let mut rest = data;
+    for &(size, flush) in writes.iter().cycle() {
+        if rest.is_empty() {
+            break;
+        }
+        let (piece, remaining) = rest.split_at(size.min(rest.len()));
+        writer.write_all(piece).await.unwrap();
+        if flush {
+            writer.flush().await.unwrap();
+        }
+        rest = remaining;
+    }
+    writer.shutdown().await.unwrap();
+    sent
+}
+
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
+
+mod properties {
+    use super::{block_on, header, read_stream, read_stream_with, write_stream};
+    use crate::Builder;
+    use proptest::prelude::*;
+    use proptest::sample::Index;
+
+    /// Builders with small chunk sizes and rekey limits, to cross many chunk and key boundaries
+    fn builder() -> impl Strategy<Value = Builder> {
+        (
+            crate::MIN_CHUNK_SIZE..20_000,
+            any::<bool>(),
+            0_usize..4,
+            any::<bool>(),
+        )
+            .prop_map(|(chunk_size, defer, threads, padding)| {
+                Builder::new()
+                    .chunk_size(chunk_size)
+                    .rekey_limit(3, 10_000)
+                    .defer_flushes(defer)
+                    .pipeline_threads(threads)
+                    .padding(padding)
+            })
+    }
+
+    fn data() -> impl Strategy<Value = Vec<u8>> {
+        prop::collection::vec(any::<u8>(), 0..30_000)
+    }
+
+    fn writes() -> impl Strategy<Value = Vec<(usize, bool)>> {
+        prop::collection::vec((1_usize..20_000, any::<bool>()), 1..10)
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        /// Arbitrary bytes never decrypt, and never hang or panic the reader.
+        #[test]
+        fn arbitrary_bytes_are_rejected(
+            key in any::<[u8; 32]>(),
+            sent in prop::collection::vec(any::<u8>(), 0..30_000),
+        ) {
+            prop_assert!(block_on(read_stream(&sent, key)).is_err());
+
+            #[cfg(feature = "blocking")]
+            {
+                use std::io::Read;
+                let result = crate::blocking::EncryptedReader::new(&sent[..], key, false)
+                    .and_then(|mut reader| reader.read_to_end(&mut Vec::new()));
+                prop_assert!(result.is_err());
+            }
+        }
+
+        /// Arbitrary bytes framed with valid length prefixes never decrypt either.
+        #[test]
+        fn arbitrary_chunks_are_rejected(
+            key in any::<[u8; 32]>(),
+            chunks in prop::collection::vec(
+                (any::<u32>(), prop::collection::vec(any::<u8>(), 16..200)),
+                0..20,
+            ),
+            threads in 0_usize..3,
+        ) {
+            let mut sent = header();
+            for (flags, msg) in chunks {
+                let header = (flags & crate::CHUNK_FLAGS) | msg.len() as u32;
+                sent.extend_from_slice(&header.to_be_bytes());
+                sent.extend_from_slice(&msg);
+            }
+            let builder = Builder::new().pipeline_threads(threads);
+            prop_assert!(block_on(read_stream_with(&sent, key, builder)).is_err());
+        }
+
+        #[test]
+        fn round_trips(
+            key in any::<[u8; 32]>(),
+            data in data(),
+            writes in writes(),
+            builder in builder(),
+        ) {
+            let sent = block_on(write_stream(&data, &writes, key, builder));
+            prop_assert_eq!(block_on(read_stream_with(&sent, key, builder)).unwrap(), data);
+        }
+
+        #[test]
+        fn flipped_bit_is_rejected(
+            key in any::<[u8; 32]>(),
+            data in data(),
+            writes in writes(),
+            builder in builder(),
+            index in any::<Index>(),
+            bit in 0_u8..8,
+        ) {
+            let mut sent = block_on(write_stream(&data, &writes, key, builder));
+            let flipped = index.index(sent.len());
+            sent[flipped] ^= 1 << bit;
+            prop_assert!(block_on(read_stream_with(&sent, key, builder)).is_err());
+        }
+
+        #[test]
+        fn truncation_is_rejected(
+            key in any::<[u8; 32]>(),
+            data in data(),
+            writes in writes(),
+            builder in builder(),
+            index in any::<Index>(),
+        ) {
+            let sent = block_on(write_stream(&data, &writes, key, builder));
+            let truncated = &sent[..index.index(sent.len())];
+            prop_assert!(block_on(read_stream_with(truncated, key, builder)).is_err());
+        }
+    }
+}
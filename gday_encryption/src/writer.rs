@@ -1,13 +1,16 @@
-use chacha20poly1305::{aead::stream::EncryptorLE31, ChaCha20Poly1305};
+use bytes::BytesMut;
 use pin_project::pin_project;
 use std::{
-    io::ErrorKind,
+    io::IoSlice,
     pin::Pin,
     task::{ready, Context, Poll},
 };
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 
-use crate::{HelperBuf, CIPHERTEXT_OVERHEAD, MAX_CHUNK_SIZE};
+use crate::cipher::{shut_down_error, ChunkEncryptor};
+use crate::pipeline::WritePipeline;
+use crate::suite::Offer;
+use crate::{Builder, CipherSuite, HelperBuf};
 
 pub trait AsyncWritable: AsyncWrite + Send + Unpin {}
 impl<T: AsyncWrite + Send + Unpin> AsyncWritable for T {}
@@ -16,24 +19,140 @@ impl<T: AsyncWrite + Send + Unpin> AsyncWritable for T {}
 pub struct EncryptedWriter<T: AsyncWritable> {
     #[pin]
     writer: T,
-    encryptor: EncryptorLE31<ChaCha20Poly1305>,
+    /// Shut down by [`AsyncWrite::poll_shutdown()`]
+    encryptor: ChunkEncryptor,
     bytes: HelperBuf,
     is_flushing: bool,
+    /// Whether [`AsyncWrite::poll_flush()`] leaves a partial chunk buffered
+    defer_flushes: bool,
+    /// Encrypts chunks on other threads if [`Builder::pipeline_threads()`] isn't 0.
+    /// Then `bytes` only holds the chunk being filled, and `is_flushing` stays false.
+    pipeline: Option<WritePipeline>,
 }
 
 impl<T: AsyncWritable> EncryptedWriter<T> {
-    pub async fn new(mut writer: T, shared_key: [u8; 32]) -> std::io::Result<Self> {
-        let nonce: [u8; 8] = rand::random();
+    /// Encrypts with a key derived from `shared_key` for this peer's direction.
+    /// `is_creator` must be true on exactly one of the two peers,
+    /// whose [`EncryptedReader`](crate::EncryptedReader)s are given the same value.
+    ///
+    /// Uses the fastest cipher suite on this CPU, which the reader must allow,
+    /// since a lone writer can't hear the reader's offer.
+    /// Use an [`EncryptedStream`](crate::EncryptedStream) to negotiate one instead.
+    ///
+    /// Use a [`Builder`] to configure the chunk size and other options.
+    pub async fn new(writer: T, shared_key: [u8; 32], is_creator: bool) -> std::io::Result<Self> {
+        Self::with_builder(writer, shared_key, is_creator, &Builder::new(), None).await
+    }
+
+    pub(crate) async fn with_builder(
+        mut writer: T,
+        shared_key: [u8; 32],
+        is_creator: bool,
+        builder: &Builder,
+        peer_offer: Option<Offer>,
+    ) -> std::io::Result<Self> {
+        let (encryptor, header) = ChunkEncryptor::new(shared_key, is_creator, builder, peer_offer)?;
 
-        writer.write_all(&nonce).await?;
+        writer.write_all(&header).await?;
         writer.flush().await?;
-        let encryptor = EncryptorLE31::new(&shared_key.into(), &nonce.into());
+
+        let mut bytes = HelperBuf::with_capacity(encryptor.chunk_size());
+        let pipeline = match builder.pipeline_threads {
+            0 => None,
+            threads => {
+                bytes.buf.extend_from_slice(&[0, 0, 0, 0]);
+                Some(WritePipeline::new(threads))
+            }
+        };
+
         Ok(Self {
             writer,
+            bytes,
             encryptor,
-            bytes: HelperBuf::with_capacity(MAX_CHUNK_SIZE),
-            is_flushing: true,
+            is_flushing: pipeline.is_none(),
+            defer_flushes: builder.defer_flushes,
+            pipeline,
+        })
+    }
+
+    /// Switches to a new key after encrypting `chunks` chunks or `bytes` bytes
+    /// with the current one, whichever comes first.
+    /// The [`EncryptedReader`](crate::EncryptedReader) follows along without knowing the limits.
+    ///
+    /// Defaults to 2^24 chunks or 4 GiB.
+    pub fn set_rekey_limit(&mut self, chunks: u32, bytes: u64) {
+        self.encryptor.set_rekey_limit(chunks, bytes);
+    }
+
+    /// The cipher suite this writer encrypts with.
+    pub fn cipher_suite(&self) -> CipherSuite {
+        self.encryptor.suite()
+    }
+
+    /// While `defer` is true, flushing only sends full chunks,
+    /// and keeps a partial chunk buffered until it fills up or deferring stops.
+    /// Shutting down still sends everything.
+    ///
+    /// Turn this on during bulk transfers with [`tokio::io::copy()`],
+    /// which flushes every time its reader isn't ready,
+    /// and would otherwise make this writer send many small chunks.
+    /// Turn it off and flush once the transfer is done.
+    pub fn set_defer_flushes(&mut self, defer: bool) {
+        self.defer_flushes = defer;
+    }
+
+    /// While `padding` is true, pads each chunk to 256 bytes or the next power of 2,
+    /// up to the chunk size, so that its length doesn't reveal how much data it holds.
+    /// Takes effect from the next chunk.
+    ///
+    /// Turn this on for interactive traffic such as chat messages,
+    /// and off for bulk transfers, where padding only wastes bandwidth.
+    /// See [`EncryptedWriter::flush_or_cover()`] to also hide when data is sent.
+    pub fn set_padding(&mut self, padding: bool) {
+        self.encryptor.set_padding(padding);
+    }
+
+    /// Sends the buffered bytes as one padded chunk, then flushes.
+    /// If nothing is buffered, sends an empty padded chunk as cover traffic,
+    /// which the reader skips.
+    ///
+    /// Call this at a fixed cadence instead of flushing,
+    /// so that an eavesdropper sees chunks of the same size at the same times
+    /// whether or not any data is sent. Pads even if padding is off.
+    pub async fn flush_or_cover(&mut self) -> std::io::Result<()> {
+        // make room for another chunk
+        std::future::poll_fn(|cx| {
+            if self.is_flushing {
+                ready!(Pin::new(&mut *self).poll_flush_local(cx))?;
+            }
+            Pin::new(&mut *self).poll_pipeline(cx, false)
         })
+        .await?;
+        if self.encryptor.is_shut_down() {
+            return Err(shut_down_error());
+        }
+
+        let padding = self.encryptor.padding();
+        self.encryptor.set_padding(true);
+        let started = self.start_flushing(false);
+        self.encryptor.set_padding(padding);
+        started?;
+
+        self.flush().await
+    }
+
+    /// Writes out encrypted chunks from the pipeline, if there is one.
+    /// See [`WritePipeline::poll_write_out()`].
+    fn poll_pipeline(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        all: bool,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.project();
+        match this.pipeline {
+            Some(pipeline) => pipeline.poll_write_out(this.writer, cx, all),
+            None => Poll::Ready(Ok(())),
+        }
     }
 
     fn poll_flush_local(
@@ -54,18 +173,25 @@ impl<T: AsyncWritable> EncryptedWriter<T> {
         Poll::Ready(Ok(()))
     }
 
-    fn start_flushing(&mut self) -> std::io::Result<()> {
-        let mut msg = self.bytes.buf.split_off(4);
-
-        self.encryptor
-            .encrypt_next_in_place(&[], &mut msg)
-            .map_err(|_| std::io::Error::new(ErrorKind::InvalidData, "Decryption error"))?;
-
-        let len = u32::try_from(msg.len()).unwrap().to_be_bytes();
+    /// Encrypts the buffered bytes into a chunk, and starts flushing it.
+    /// If `is_last`, the chunk is marked as the final one, and nothing can be written after it.
+    /// Otherwise, switches to the next key after the chunk if the current one reached its limit.
+    ///
+    /// When pipelined, hands the chunk to the pipeline instead,
+    /// which must have room for it.
+    fn start_flushing(&mut self, is_last: bool) -> std::io::Result<()> {
+        if let Some(pipeline) = &mut self.pipeline {
+            let chunk_size = self.encryptor.chunk_size();
+            let mut chunk =
+                std::mem::replace(&mut self.bytes.buf, BytesMut::with_capacity(chunk_size));
+            self.bytes.buf.extend_from_slice(&[0, 0, 0, 0]);
 
-        self.bytes.buf.copy_from_slice(&len);
-        self.bytes.buf.unsplit(msg);
+            let job = self.encryptor.next_job(&mut chunk, is_last)?;
+            pipeline.encrypt(job, chunk);
+            return Ok(());
+        }
 
+        self.encryptor.encrypt_chunk(&mut self.bytes, is_last)?;
         self.is_flushing = true;
         Ok(())
     }
@@ -73,41 +199,95 @@ impl<T: AsyncWritable> EncryptedWriter<T> {
 
 impl<T: AsyncWritable> AsyncWrite for EncryptedWriter<T> {
     fn poll_write(
-        mut self: Pin<&mut Self>,
+        self: Pin<&mut Self>,
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<std::io::Result<usize>> {
-        debug_assert!(self.bytes.buf.capacity() == MAX_CHUNK_SIZE);
+        self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+    }
+
+    /// Buffers as many of the slices as fit in the current chunk.
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        debug_assert!(self.bytes.buf.capacity() == self.encryptor.chunk_size());
+        if self.encryptor.is_shut_down() {
+            return Poll::Ready(Err(shut_down_error()));
+        }
         if self.is_flushing {
             ready!(self.as_mut().poll_flush_local(cx))?;
         }
+        ready!(self.as_mut().poll_pipeline(cx, false))?;
 
-        let bytes_taken = std::cmp::min(
-            buf.len(),
-            self.bytes.spare_capacity_len() - CIPHERTEXT_OVERHEAD,
-        );
+        // padding may have been turned on after the chunk filled up,
+        // so send the chunk first rather than taking no bytes
+        let reserved_len = self.encryptor.reserved_len();
+        if self.bytes.spare_capacity_len() <= reserved_len && bufs.iter().any(|buf| !buf.is_empty())
+        {
+            self.start_flushing(false)?;
+            if self.is_flushing {
+                ready!(self.as_mut().poll_flush_local(cx))?;
+            }
+            ready!(self.as_mut().poll_pipeline(cx, false))?;
+        }
 
-        self.bytes.buf.extend_from_slice(&buf[0..bytes_taken]);
+        let mut bytes_taken = 0;
+        for buf in bufs {
+            let spare = self.bytes.spare_capacity_len().saturating_sub(reserved_len);
+            let taken = std::cmp::min(buf.len(), spare);
+            self.bytes.buf.extend_from_slice(&buf[0..taken]);
+            bytes_taken += taken;
 
-        if self.bytes.spare_capacity_len() == CIPHERTEXT_OVERHEAD {
-            self.start_flushing()?;
+            if taken < buf.len() {
+                break;
+            }
+        }
+
+        if self.bytes.spare_capacity_len() <= reserved_len {
+            self.start_flushing(false)?;
         }
 
         Poll::Ready(Ok(bytes_taken))
     }
 
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
-        if !self.is_flushing && !self.bytes.data().is_empty() {
-            self.start_flushing()?;
+        ready!(self.as_mut().poll_pipeline(cx, false))?;
+        // the 4-byte placeholder for the length prefix doesn't need a chunk
+        if !self.is_flushing
+            && !self.defer_flushes
+            && !self.encryptor.is_shut_down()
+            && self.bytes.data().len() > 4
+        {
+            self.start_flushing(false)?;
         }
         if self.is_flushing {
             ready!(self.as_mut().poll_flush_local(cx))?;
         }
+        ready!(self.as_mut().poll_pipeline(cx, true))?;
         self.project().writer.poll_flush(cx)
     }
 
+    /// Sends the buffered bytes as the authenticated final chunk,
+    /// so the reader can tell the stream ended here rather than being cut short.
     fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
-        ready!(self.as_mut().poll_flush(cx))?;
+        if self.is_flushing {
+            ready!(self.as_mut().poll_flush_local(cx))?;
+        }
+        ready!(self.as_mut().poll_pipeline(cx, false))?;
+        if !self.encryptor.is_shut_down() {
+            self.start_flushing(true)?;
+            if self.is_flushing {
+                ready!(self.as_mut().poll_flush_local(cx))?;
+            }
+        }
+        ready!(self.as_mut().poll_pipeline(cx, true))?;
+        ready!(self.as_mut().project().writer.poll_flush(cx))?;
         self.project().writer.poll_shutdown(cx)
     }
 }
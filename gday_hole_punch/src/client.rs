@@ -1,9 +1,16 @@
 mod contact_sharer;
+mod manual;
+mod network;
 mod peer_connector;
-
-use crate::SerializationError;
-pub use contact_sharer::ContactSharer;
-pub use peer_connector::{random_peer_secret, PeerConnector, PeerSecret};
+mod port_mapping;
+mod shared_listener;
+
+use crate::{RejectReason, SerializationError};
+pub use contact_sharer::{public_addr, ContactSharer, RoomExtender};
+pub use manual::{combine_secrets, decode_contact, encode_contact, manual_contact};
+pub use network::{Interface, Network, TcpNetwork};
+pub use peer_connector::{random_peer_secret, PeerConnection, PeerConnector, PeerPath, PeerSecret};
+pub use port_mapping::{MappingProtocol, PortMapping, PortMappingError};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -43,4 +50,10 @@ pub enum ClientError {
 
     #[error("Invalid utf-8")]
     InvalidUtf8(#[from] std::str::Utf8Error),
+
+    #[error("Server refused request: {0}")]
+    Rejected(#[from] RejectReason),
+
+    #[error("Invalid contact. Check that it was copied completely!")]
+    InvalidContact,
 }
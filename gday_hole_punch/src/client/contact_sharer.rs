@@ -1,40 +1,128 @@
+pub(super) mod local_candidates;
+mod port_prediction;
 mod server_connection;
 
-use super::{peer_connector::PeerConnector, ClientError};
-use crate::{ClientMessage, ServerMessage};
-use tokio::net::TcpStream;
-use tokio_rustls::client::TlsStream;
+use super::{
+    peer_connector::PeerConnector, shared_listener::SharedListener, ClientError, Network,
+    PortMapping, PortMappingError, TcpNetwork,
+};
+use crate::{
+    ClientMessage, PortAllocation, RoomEvent, SerializationError, ServerMessage, Transport,
+};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
 
+use local_candidates::local_candidates;
+pub use port_prediction::public_addr;
 use server_connection::ServerConnection;
 
-pub struct ContactSharer {
+pub struct ContactSharer<N: Network = TcpNetwork> {
     is_creator: bool,
     connection: ServerConnection,
+    /// When the room expires, if this client created it
+    expires_at: Option<Instant>,
+    /// How many peers the room accepts. Always 1 for a joiner.
+    max_joiners: u32,
+    /// True once this client's contact info has been sent to the server
+    sent_contacts: bool,
+    /// Whether to ask the router for a port mapping before sending contact info
+    map_port: bool,
+    /// Result of asking the router for a port mapping
+    port_mapping: Option<Result<Arc<PortMapping>, PortMappingError>>,
+    /// How this client's NAT allocates public IPv4 ports, if measured
+    port_allocation: Option<PortAllocation>,
+    /// Whether this client can only reach peers through the server's relay
+    relay_only: bool,
+    /// Forwards [`RoomEvent`]s received from the server
+    events_tx: mpsc::UnboundedSender<RoomEvent>,
+    /// Taken by [`ContactSharer::room_events()`]
+    events_rx: Option<mpsc::UnboundedReceiver<RoomEvent>>,
+    /// Sends requests to extend the room
+    extend_tx: mpsc::UnboundedSender<()>,
+    /// Receives requests to extend the room
+    extend_rx: mpsc::UnboundedReceiver<()>,
+    /// Shared by the connectors of every peer
+    listener: SharedListener,
+    /// The network whose interfaces are shared as candidates, and that peers are reached over
+    network: N,
 }
 
-type Stream = TlsStream<TcpStream>;
+/// Asks the server to keep a room open for longer.
+/// Get one with [`ContactSharer::room_extender()`].
+#[derive(Clone, Debug)]
+pub struct RoomExtender {
+    tx: mpsc::UnboundedSender<()>,
+}
+
+impl RoomExtender {
+    /// Asks the server to extend the room.
+    ///
+    /// The request is sent while [`ContactSharer::next_peer_connector()`] waits for a peer.
+    /// Once the server extends the room, [`RoomEvent::RoomExtended`] is reported.
+    pub fn extend(&self) {
+        // don't care about error, since it only means
+        // the peer's contact has already been received
+        let _ = self.tx.send(());
+    }
+}
+
+/// Something [`ContactSharer::next_peer_connector()`] has to react to
+/// while waiting for a peer.
+enum Incoming {
+    Message(Result<ServerMessage, SerializationError>),
+    ExtendRequest,
+}
+
+type Stream = Box<dyn Transport>;
 
 impl ContactSharer {
+    /// Creates a room on the server that up to `max_joiners` peers can join.
+    /// The server may limit this further; see [`ContactSharer::max_joiners()`].
+    ///
+    /// `access_key` is only needed by servers that restrict who can create rooms.
     pub async fn create_room(
         server_stream_v6: Option<Stream>,
         server_stream_v4: Option<Stream>,
+        access_key: Option<&str>,
+        max_joiners: u32,
     ) -> Result<(Self, u32), ClientError> {
         let mut connection = ServerConnection::new(server_stream_v6, server_stream_v4).await?;
 
         let messenger = connection.get_any_messenger();
-        messenger.write_msg(ClientMessage::CreateRoom).await?;
+
+        if let Some(access_key) = access_key {
+            messenger
+                .write_msg(ClientMessage::Authenticate(access_key.to_string()))
+                .await?;
+            match messenger.next_msg().await? {
+                ServerMessage::Authenticated => (),
+                ServerMessage::Rejected(reason) => return Err(reason.into()),
+                _ => return Err(ClientError::InvalidServerReply),
+            }
+        }
+
+        messenger
+            .write_msg(ClientMessage::CreateRoom { max_joiners })
+            .await?;
         let response = messenger.next_msg().await?;
 
-        if let ServerMessage::RoomCreated { room_id } = response {
-            Ok((
-                Self {
-                    is_creator: true,
-                    connection,
-                },
+        match response {
+            ServerMessage::RoomCreated {
                 room_id,
-            ))
-        } else {
-            Err(ClientError::InvalidServerReply)
+                seconds_left,
+                max_joiners,
+                link_token,
+            } => {
+                connection.link_others(room_id, link_token).await?;
+                let mut this = Self::new(true, connection);
+                this.expires_at = Some(Instant::now() + Duration::from_secs(seconds_left));
+                this.max_joiners = max_joiners;
+                Ok((this, room_id))
+            }
+            ServerMessage::Rejected(reason) => Err(reason.into()),
+            _ => Err(ClientError::InvalidServerReply),
         }
     }
 
@@ -51,37 +139,225 @@ impl ContactSharer {
             .await?;
         let response = messenger.next_msg().await?;
 
-        if ServerMessage::RoomJoined == response {
-            Ok(Self {
-                is_creator: false,
-                connection,
-            })
-        } else {
-            Err(ClientError::InvalidServerReply)
+        match response {
+            ServerMessage::RoomJoined { link_token } => {
+                connection.link_others(room_id, link_token).await?;
+                Ok(Self::new(false, connection))
+            }
+            ServerMessage::Rejected(reason) => Err(reason.into()),
+            _ => Err(ClientError::InvalidServerReply),
         }
     }
 
-    pub async fn get_peer_connector(mut self) -> Result<PeerConnector, ClientError> {
-        let mut conns = self.connection.get_all_messengers()?;
+    fn new(is_creator: bool, connection: ServerConnection) -> Self {
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        let (extend_tx, extend_rx) = mpsc::unbounded_channel();
+        Self {
+            is_creator,
+            connection,
+            expires_at: None,
+            max_joiners: 1,
+            sent_contacts: false,
+            map_port: false,
+            port_mapping: None,
+            port_allocation: None,
+            relay_only: false,
+            events_tx,
+            events_rx: Some(events_rx),
+            extend_tx,
+            extend_rx,
+            listener: SharedListener::default(),
+            network: TcpNetwork,
+        }
+    }
+}
 
-        for conn in &mut conns {
-            let msg = ClientMessage::SendPrivateAddr(Some(conn.local_addr()?));
-            conn.write_msg(msg).await?;
+impl<N: Network> ContactSharer<N> {
+    /// Gathers candidates from `network` instead, and reaches peers over it,
+    /// such as a simulated one.
+    pub fn with_network<M: Network>(self, network: M) -> ContactSharer<M> {
+        ContactSharer {
+            is_creator: self.is_creator,
+            connection: self.connection,
+            expires_at: self.expires_at,
+            max_joiners: self.max_joiners,
+            sent_contacts: self.sent_contacts,
+            map_port: self.map_port,
+            port_mapping: self.port_mapping,
+            port_allocation: self.port_allocation,
+            relay_only: self.relay_only,
+            events_tx: self.events_tx,
+            events_rx: self.events_rx,
+            extend_tx: self.extend_tx,
+            extend_rx: self.extend_rx,
+            listener: self.listener,
+            network,
         }
+    }
+
+    /// When the room expires, as reported by the server when the room was created.
+    /// Later changes are reported as [`RoomEvent`]s.
+    ///
+    /// Returns `None` if this client didn't create the room.
+    pub fn room_expiry(&self) -> Option<Instant> {
+        self.expires_at
+    }
+
+    /// How many peers can join the room, as accepted by the server.
+    /// Always 1 if this client didn't create the room.
+    pub fn max_joiners(&self) -> u32 {
+        self.max_joiners
+    }
+
+    /// Asks the router to forward a public port to the socket peers connect to,
+    /// using PCP, NAT-PMP or UPnP, before sharing this client's contact.
+    /// The mapped address is shared as an extra public address.
+    ///
+    /// Must be called before [`ContactSharer::next_peer_connector()`].
+    pub fn enable_port_mapping(&mut self) {
+        self.map_port = true;
+    }
+
+    /// The port mapping requested by [`ContactSharer::enable_port_mapping()`],
+    /// or why the router didn't make one.
+    ///
+    /// Returns `None` until [`ContactSharer::next_peer_connector()`] has requested it.
+    pub fn port_mapping(&self) -> Option<Result<&PortMapping, &PortMappingError>> {
+        self.port_mapping
+            .as_ref()
+            .map(|result| result.as_ref().map(|mapping| &**mapping))
+    }
+
+    /// Measures how this client's NAT allocates public ports, so the peer can
+    /// predict which port this client's connections to it will come from.
+    ///
+    /// `probes` must be at least 3 IPv4 connections to the same server,
+    /// opened one after another just before calling this.
+    /// They're only worth opening if [`public_addr()`] shows that the NAT rewrites ports.
+    /// Returns `None` if the ports weren't allocated in a regular pattern.
+    ///
+    /// Must be called before [`ContactSharer::next_peer_connector()`],
+    /// which shares the pattern with the peer.
+    pub async fn measure_port_allocation(
+        &mut self,
+        probes: Vec<Stream>,
+    ) -> Result<Option<PortAllocation>, ClientError> {
+        self.port_allocation = port_prediction::measure(probes).await?;
+        Ok(self.port_allocation)
+    }
+
+    /// Tells peers that this client can only be reached through the server's relay,
+    /// for example because it connects to the server through a proxy.
+    /// Both peers then connect with [`PeerConnector::connect_via_relay()`].
+    ///
+    /// Must be called before [`ContactSharer::next_peer_connector()`].
+    pub fn require_relay(&mut self) {
+        self.relay_only = true;
+    }
+
+    /// Returns a handle that can extend the room
+    /// while [`ContactSharer::next_peer_connector()`] waits for a peer.
+    /// Only the room's creator can extend it.
+    pub fn room_extender(&self) -> RoomExtender {
+        RoomExtender {
+            tx: self.extend_tx.clone(),
+        }
+    }
+
+    /// Returns a receiver of the [`RoomEvent`]s the server reports
+    /// while [`ContactSharer::next_peer_connector()`] waits for a peer.
+    /// Only the room's creator receives events.
+    ///
+    /// Returns `None` if the receiver was already taken.
+    pub fn room_events(&mut self) -> Option<mpsc::UnboundedReceiver<RoomEvent>> {
+        self.events_rx.take()
+    }
+
+    /// Waits for the peer's contact, and returns a [`PeerConnector`] to connect to it.
+    pub async fn get_peer_connector(mut self) -> Result<PeerConnector<N>, ClientError> {
+        self.next_peer_connector().await
+    }
+
+    /// Waits for the next peer's contact, and returns a [`PeerConnector`] to connect to it.
+    ///
+    /// The room's creator can call this once for each of the
+    /// [`ContactSharer::max_joiners()`] peers, in the order they become ready.
+    pub async fn next_peer_connector(&mut self) -> Result<PeerConnector<N>, ClientError> {
+        if !self.sent_contacts {
+            let server_addrs = self
+                .connection
+                .get_all_messengers()?
+                .iter()
+                .map(|conn| conn.local_addr())
+                .collect::<std::io::Result<Vec<_>>>()?;
+            let candidates = local_candidates(&self.network, &server_addrs);
+
+            // peers connect to the socket listening on the first IPv4 candidate
+            let first_v4 = candidates
+                .iter()
+                .find_map(|candidate| match candidate.addr {
+                    SocketAddr::V4(addr) => Some(addr),
+                    SocketAddr::V6(_) => None,
+                });
+            if let (true, Some(local)) = (self.map_port, first_v4) {
+                self.port_mapping = Some(PortMapping::request(local).await.map(Arc::new));
+            }
+
+            let conn = self.connection.get_any_messenger();
+            conn.write_msg(ClientMessage::SendPrivateAddrs(candidates))
+                .await?;
+            if let Some(Ok(mapping)) = &self.port_mapping {
+                conn.write_msg(ClientMessage::SendMappedAddr(mapping.external_addr()))
+                    .await?;
+            }
+            if let Some(allocation) = self.port_allocation {
+                conn.write_msg(ClientMessage::SendPortAllocation(allocation))
+                    .await?;
+            }
+            if self.relay_only {
+                conn.write_msg(ClientMessage::RequireRelay).await?;
+            }
+            conn.write_msg(ClientMessage::DoneSending).await?;
+            self.sent_contacts = true;
+        }
+
+        let conn = self.connection.get_any_messenger();
 
-        conns[0].write_msg(ClientMessage::DoneSending).await?;
+        let response = loop {
+            let incoming = tokio::select! {
+                msg = conn.next_msg() => Incoming::Message(msg),
+                Some(()) = self.extend_rx.recv(), if self.is_creator => Incoming::ExtendRequest,
+            };
 
-        let response: ServerMessage = conns[0].next_msg().await?;
+            match incoming {
+                Incoming::Message(Ok(ServerMessage::RoomEvent(event))) => {
+                    // don't care if nobody is listening for events
+                    let _ = self.events_tx.send(event);
+                }
+                Incoming::Message(response) => break response?,
+                Incoming::ExtendRequest => conn.write_msg(ClientMessage::ExtendRoom).await?,
+            }
+        };
 
         if let ServerMessage::SharePeerContacts {
             client_contact: local,
             peer_contact: peer,
+            joiner_id,
+            relay_token,
         } = response
         {
             Ok(PeerConnector {
                 local,
                 peer,
                 is_creator: self.is_creator,
+                joiner_id,
+                relay_token: Some(relay_token),
+                port_mapping: self
+                    .port_mapping
+                    .as_ref()
+                    .and_then(|r| r.as_ref().ok().cloned()),
+                network: self.network.clone(),
+                listener: self.listener.clone(),
             })
         } else {
             Err(ClientError::InvalidServerReply)
@@ -0,0 +1,153 @@
+use crate::client::{Interface, Network};
+use crate::{Candidate, MAX_PRIVATE_CANDIDATES};
+use std::cmp::Reverse;
+use std::net::{IpAddr, SocketAddr};
+
+/// Priority of an address used to reach the server, since its route is known to work.
+const PRIORITY_SERVER_ROUTE: u32 = 300;
+
+/// Priority of addresses on physical interfaces, such as Ethernet and Wi-Fi.
+const PRIORITY_PHYSICAL: u32 = 200;
+
+/// Priority of addresses on VPN tunnels.
+const PRIORITY_VPN: u32 = 100;
+
+/// Priority of addresses on virtual bridges, such as those of Docker and virtual machines.
+const PRIORITY_VIRTUAL: u32 = 0;
+
+/// Name prefixes of VPN interfaces.
+const VPN_PREFIXES: &[&str] = &["tun", "tap", "wg", "utun", "ppp", "tailscale", "zt"];
+
+/// Name prefixes of virtual bridge interfaces.
+const VIRTUAL_PREFIXES: &[&str] = &[
+    "docker", "br-", "veth", "virbr", "vmnet", "vboxnet", "lxc", "cni",
+];
+
+/// Returns the private candidates of this host on `network`, highest priority first.
+///
+/// `server_addrs` are the local addresses of the connections to the server.
+/// Each one is the first candidate of its IP family, followed by the
+/// addresses of the host's other interfaces with the same port.
+/// Each family has at most half of [`MAX_PRIVATE_CANDIDATES`].
+pub fn local_candidates(network: &impl Network, server_addrs: &[SocketAddr]) -> Vec<Candidate> {
+    // without interfaces, the server routes are still candidates
+    let interfaces = network.interfaces();
+    let mut candidates = Vec::new();
+
+    for &server_addr in server_addrs {
+        let first = Candidate {
+            addr: server_addr,
+            priority: PRIORITY_SERVER_ROUTE,
+        };
+        candidates.extend(family_candidates(
+            &interfaces,
+            vec![first],
+            server_addr.is_ipv6(),
+            server_addr.port(),
+        ));
+    }
+
+    candidates.sort_by_key(|candidate| Reverse(candidate.priority));
+    candidates
+}
+
+/// Returns the addresses of this host's interfaces with `port`, highest priority first,
+/// for when there's no server connection to tell which routes work.
+/// Each family has at most half of [`MAX_PRIVATE_CANDIDATES`].
+pub fn interface_candidates(network: &impl Network, port: u16) -> Vec<Candidate> {
+    let interfaces = network.interfaces();
+    let mut candidates = Vec::new();
+
+    for ipv6 in [true, false] {
+        candidates.extend(family_candidates(&interfaces, Vec::new(), ipv6, port));
+    }
+
+    candidates.sort_by_key(|candidate| Reverse(candidate.priority));
+    candidates
+}
+
+/// Adds the usable `interfaces` of one IP family to `family`, with `port`,
+/// and returns the highest priority ones.
+fn family_candidates(
+    interfaces: &[Interface],
+    mut family: Vec<Candidate>,
+    ipv6: bool,
+    port: u16,
+) -> Vec<Candidate> {
+    for interface in interfaces {
+        let ip = interface.ip;
+        if ip.is_ipv6() != ipv6
+            || !is_usable(ip)
+            || family.iter().any(|candidate| candidate.addr.ip() == ip)
+        {
+            continue;
+        }
+
+        family.push(Candidate {
+            addr: SocketAddr::new(ip, port),
+            priority: priority(&interface.name),
+        });
+    }
+
+    family.sort_by_key(|candidate| Reverse(candidate.priority));
+    family.truncate(MAX_PRIVATE_CANDIDATES / 2);
+    family
+}
+
+/// Guesses how likely a peer can reach the interface called `name`.
+fn priority(name: &str) -> u32 {
+    if VIRTUAL_PREFIXES
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+    {
+        PRIORITY_VIRTUAL
+    } else if VPN_PREFIXES.iter().any(|prefix| name.starts_with(prefix)) {
+        PRIORITY_VPN
+    } else {
+        PRIORITY_PHYSICAL
+    }
+}
+
+/// True if a peer could reach `ip` without knowing which interface it's on.
+fn is_usable(ip: IpAddr) -> bool {
+    if ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() {
+        return false;
+    }
+    match ip {
+        IpAddr::V4(ip) => !ip.is_link_local(),
+        // link-local IPv6 addresses need a scope id
+        IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 != 0xfe80,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::TcpNetwork;
+
+    #[test]
+    fn server_routes_come_first() {
+        let v6: SocketAddr = "[2001:db8::1]:5000".parse().unwrap();
+        let v4: SocketAddr = "192.0.2.1:5000".parse().unwrap();
+
+        let candidates = local_candidates(&TcpNetwork, &[v6, v4]);
+
+        assert_eq!(candidates[0].addr, v6);
+        assert_eq!(candidates[1].addr, v4);
+        assert!(candidates.len() <= MAX_PRIVATE_CANDIDATES);
+        assert!(candidates
+            .iter()
+            .all(|candidate| candidate.addr.port() == 5000));
+        assert!(candidates
+            .iter()
+            .all(|candidate| !candidate.addr.ip().is_loopback()));
+    }
+
+    #[test]
+    fn physical_interfaces_beat_tunnels_and_bridges() {
+        assert!(priority("eth0") > priority("wg0"));
+        assert!(priority("wlan0") > priority("tun0"));
+        assert!(priority("tun0") > priority("docker0"));
+        assert!(priority("enp3s0") > priority("br-1a2b3c"));
+    }
+}
@@ -0,0 +1,125 @@
+use super::{ClientError, Stream};
+use crate::{ClientMessage, Messenger, PortAllocation, ServerMessage, MESSENGER_BUF_SIZE};
+use std::net::SocketAddr;
+
+/// Largest port delta considered a pattern rather than random allocation.
+const MAX_DELTA: i32 = 64;
+
+/// Asks the server which public port each of the `probes` came from,
+/// and returns the pattern in which the NAT allocated those ports.
+///
+/// Returns `None` if the NAT kept each probe's local port, or allocated ports irregularly.
+pub async fn measure(probes: Vec<Stream>) -> Result<Option<PortAllocation>, ClientError> {
+    let mut local_ports = Vec::with_capacity(probes.len());
+    let mut public_ports = Vec::with_capacity(probes.len());
+
+    for stream in probes {
+        let local = stream.local_addr()?;
+        if !local.is_ipv4() {
+            return Err(ClientError::ExpectedIPv4);
+        }
+
+        let (_stream, public) = public_addr(stream).await?;
+        local_ports.push(local.port());
+        public_ports.push(public.port());
+    }
+
+    if local_ports == public_ports {
+        return Ok(None);
+    }
+
+    Ok(allocation_pattern(&public_ports))
+}
+
+/// Asks the server which public address `stream` comes from,
+/// and returns the stream to be used for anything else.
+///
+/// If the NAT rewrote the port of a connection to the server,
+/// it may be symmetric, and measuring its port allocation
+/// with [`ContactSharer::measure_port_allocation()`](super::ContactSharer::measure_port_allocation)
+/// helps the peer reach this client. Otherwise, port probes aren't needed.
+///
+/// Must be called before the stream is used to create or join a room.
+pub async fn public_addr(stream: Stream) -> Result<(Stream, SocketAddr), ClientError> {
+    let mut messenger = Messenger::with_capacity(stream, MESSENGER_BUF_SIZE);
+    messenger.write_msg(ClientMessage::GetPublicAddr).await?;
+    let public = match messenger.next_msg().await? {
+        ServerMessage::PublicAddr(public) => public,
+        ServerMessage::Rejected(reason) => return Err(reason.into()),
+        _ => return Err(ClientError::InvalidServerReply),
+    };
+    // the server sends nothing else until asked, so nothing buffered is lost
+    Ok((messenger.into_inner(), public))
+}
+
+/// Returns the constant difference between consecutive `ports`,
+/// if there is one and there are at least 3 ports.
+fn allocation_pattern(ports: &[u16]) -> Option<PortAllocation> {
+    let mut deltas = ports
+        .windows(2)
+        .map(|pair| i32::from(pair[1]) - i32::from(pair[0]));
+
+    let delta = deltas.next()?;
+    let regular = deltas.len() > 0 && deltas.all(|d| d == delta);
+
+    if regular && delta != 0 && delta.abs() <= MAX_DELTA {
+        Some(PortAllocation {
+            last_port: *ports.last()?,
+            delta,
+        })
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_sequential_allocation() {
+        let allocation = allocation_pattern(&[40000, 40002, 40004]).unwrap();
+        assert_eq!(
+            allocation,
+            PortAllocation {
+                last_port: 40004,
+                delta: 2
+            }
+        );
+        let predicted: Vec<u16> = allocation.predict(3).collect();
+        assert_eq!(predicted, [40006, 40008, 40010]);
+
+        let allocation = allocation_pattern(&[40004, 40003, 40002]).unwrap();
+        assert_eq!(allocation.delta, -1);
+
+        // predictions stop at the end of the port range
+        let allocation = allocation_pattern(&[65531, 65533, 65535]).unwrap();
+        assert_eq!(allocation.predict(3).count(), 0);
+    }
+
+    /// A peer's allocation is untrusted, so a huge delta must not overflow.
+    #[test]
+    fn predicts_nothing_from_huge_delta() {
+        for delta in [i32::MAX, i32::MIN, 65536] {
+            let allocation = PortAllocation {
+                last_port: 40000,
+                delta,
+            };
+            assert_eq!(allocation.predict(16).count(), 0);
+            assert!(!allocation.is_valid());
+        }
+        assert!(PortAllocation {
+            last_port: 40000,
+            delta: -65535
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn ignores_irregular_allocation() {
+        assert_eq!(allocation_pattern(&[40000, 40002]), None);
+        assert_eq!(allocation_pattern(&[40000, 40002, 40005]), None);
+        assert_eq!(allocation_pattern(&[40000, 40000, 40000]), None);
+        assert_eq!(allocation_pattern(&[1000, 23000, 45000]), None);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{Contact, Messenger};
+use crate::{ClientMessage, Contact, Messenger, ServerMessage, MESSENGER_BUF_SIZE};
 use socket2::SockRef;
 use std::net::{
     SocketAddr::{V4, V6},
@@ -24,16 +24,14 @@ impl ServerConnection {
         let mut this = Self { v6: None, v4: None };
 
         if let Some(stream) = server_addr_v6 {
-            let tcp = stream.get_ref().0;
-            if !matches!(tcp.local_addr()?, V6(_)) {
+            if !matches!(stream.local_addr()?, V6(_)) {
                 return Err(ClientError::ExpectedIPv6);
-            }; 
+            };
             this.v6 = Some(configure_stream(stream));
         }
 
         if let Some(stream) = server_addr_v4 {
-            let tcp = stream.get_ref().0;
-            if !matches!(tcp.local_addr()?, V4(_)) {
+            if !matches!(stream.local_addr()?, V4(_)) {
                 return Err(ClientError::ExpectedIPv4);
             };
             this.v4 = Some(configure_stream(stream));
@@ -51,6 +49,27 @@ impl ServerConnection {
         }
     }
 
+    /// Links the connection [`ServerConnection::get_any_messenger()`] doesn't return
+    /// to the client's room, so the server learns its public address too.
+    pub(super) async fn link_others(
+        &mut self,
+        room_id: u32,
+        link_token: u64,
+    ) -> Result<(), ClientError> {
+        if let (Some(_), Some(v4)) = (&self.v6, &mut self.v4) {
+            v4.write_msg(ClientMessage::LinkConnection {
+                room_id,
+                link_token,
+            })
+            .await?;
+            match v4.next_msg().await? {
+                ServerMessage::Linked => (),
+                _ => return Err(ClientError::InvalidServerReply),
+            }
+        }
+        Ok(())
+    }
+
     pub(super) fn get_all_messengers(&mut self) -> std::io::Result<Vec<&mut Messenger>> {
         let mut messengers = Vec::new();
 
@@ -93,8 +112,10 @@ impl ServerConnection {
 }
 
 fn configure_stream(stream: Stream) -> Messenger {
-    let sock = SockRef::from(stream.get_ref().0);
-    let _ = sock.set_reuse_address(true);
-    let _ = sock.set_reuse_port(true);
-    Messenger::with_capacity(stream, 68)
+    if let Some(tcp_stream) = stream.tcp_stream() {
+        let sock = SockRef::from(tcp_stream);
+        let _ = sock.set_reuse_address(true);
+        let _ = sock.set_reuse_port(true);
+    }
+    Messenger::with_capacity(stream, MESSENGER_BUF_SIZE)
 }
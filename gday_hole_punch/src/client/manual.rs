@@ -0,0 +1,113 @@
+use super::contact_sharer::local_candidates::interface_candidates;
+use super::{ClientError, PeerSecret, TcpNetwork};
+use crate::{Contact, FullContact};
+use std::net::{IpAddr, SocketAddr};
+
+/// Returns this host's contact, for peers to connect to on `port`,
+/// without asking a server.
+///
+/// The private candidates are the addresses of this host's interfaces.
+/// Without a server to report them, the public addresses are guessed:
+/// the first globally routable address of each family, which is
+/// the public address when there's no NAT, as is common with IPv6.
+pub fn manual_contact(port: u16) -> FullContact {
+    let private = interface_candidates(&TcpNetwork, port);
+    let mut public = Contact::default();
+
+    for candidate in private.iter().filter(|c| is_global(c.addr.ip())) {
+        match candidate.addr {
+            SocketAddr::V6(addr) => public.v6 = public.v6.or(Some(addr)),
+            SocketAddr::V4(addr) => public.v4 = public.v4.or(Some(addr)),
+        }
+    }
+
+    FullContact {
+        private,
+        public,
+        ..FullContact::default()
+    }
+}
+
+/// Encodes `contact` and this peer's half of the shared secret
+/// as text the user can copy to the peer.
+pub fn encode_contact(contact: &FullContact, secret: PeerSecret) -> String {
+    let bytes = postcard::to_stdvec(&(contact, secret)).expect("contact is serializable");
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Decodes text made by [`encode_contact()`].
+///
+/// Combine the secret with this peer's own using [`combine_secrets()`].
+pub fn decode_contact(text: &str) -> Result<(FullContact, PeerSecret), ClientError> {
+    let text = text.trim();
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return Err(ClientError::InvalidContact);
+    }
+
+    let bytes = (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| ClientError::InvalidContact)?;
+
+    postcard::from_bytes(&bytes).map_err(|_| ClientError::InvalidContact)
+}
+
+/// Returns the secret both peers share after exchanging
+/// their halves through [`encode_contact()`].
+pub fn combine_secrets(a: PeerSecret, b: PeerSecret) -> PeerSecret {
+    a ^ b
+}
+
+/// True if `ip` is reachable from the public internet.
+fn is_global(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            // 100.64.0.0/10 is shared by carrier-grade NATs
+            let shared = a == 100 && (b & 0xc0) == 64;
+            !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || shared)
+        }
+        // 2000::/3 is the global unicast range
+        IpAddr::V6(ip) => ip.segments()[0] & 0xe000 == 0x2000,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Candidate;
+
+    #[test]
+    fn contact_round_trips() {
+        let contact = FullContact {
+            private: vec![Candidate {
+                addr: "192.168.1.20:5000".parse().unwrap(),
+                priority: 200,
+            }],
+            public: Contact {
+                v6: Some("[2001:db8::1]:5000".parse().unwrap()),
+                v4: None,
+            },
+            ..FullContact::default()
+        };
+
+        let text = encode_contact(&contact, 1234);
+        assert_eq!(
+            decode_contact(&format!(" {text}\n")).unwrap(),
+            (contact, 1234)
+        );
+
+        assert!(decode_contact(&text[1..]).is_err());
+        assert!(decode_contact(&text[..text.len() - 2]).is_err());
+    }
+
+    #[test]
+    fn guesses_global_addresses() {
+        assert!(is_global("203.0.113.7".parse().unwrap()));
+        assert!(is_global("2001:db8::1".parse().unwrap()));
+        assert!(!is_global("10.0.0.2".parse().unwrap()));
+        assert!(!is_global("100.100.1.1".parse().unwrap()));
+        assert!(!is_global("fd00::1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,95 @@
+use crate::Transport;
+use socket2::{SockRef, TcpKeepalive};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+/// The network a [`PeerConnector`](super::PeerConnector) reaches its peer over.
+///
+/// [`TcpNetwork`] uses the operating system's TCP sockets.
+/// Tests use a simulated network of NATs instead.
+pub trait Network: Clone + Send + Sync + 'static {
+    type Stream: Transport + 'static;
+    type Listener: Send + 'static;
+
+    /// Connects from `local` to `peer`.
+    /// Other connections and a listener may share `local`.
+    fn connect(
+        &self,
+        local: SocketAddr,
+        peer: SocketAddr,
+    ) -> impl Future<Output = std::io::Result<Self::Stream>> + Send;
+
+    /// Listens on `local`, which outgoing connections may share.
+    fn listen(&self, local: SocketAddr) -> std::io::Result<Self::Listener>;
+
+    /// Waits for the next connection to `listener`.
+    fn accept(
+        listener: &mut Self::Listener,
+    ) -> impl Future<Output = std::io::Result<Self::Stream>> + Send;
+
+    /// Returns the addresses of this host's network interfaces.
+    fn interfaces(&self) -> Vec<Interface>;
+}
+
+/// An address of one of a host's network interfaces.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Interface {
+    /// Name of the interface, such as `eth0` or `wg0`
+    pub name: String,
+    pub ip: IpAddr,
+}
+
+/// The operating system's TCP sockets.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TcpNetwork;
+
+impl Network for TcpNetwork {
+    type Stream = TcpStream;
+    type Listener = TcpListener;
+
+    async fn connect(&self, local: SocketAddr, peer: SocketAddr) -> std::io::Result<TcpStream> {
+        get_local_socket(local)?.connect(peer).await
+    }
+
+    fn listen(&self, local: SocketAddr) -> std::io::Result<TcpListener> {
+        get_local_socket(local)?.listen(1024)
+    }
+
+    async fn accept(listener: &mut TcpListener) -> std::io::Result<TcpStream> {
+        Ok(listener.accept().await?.0)
+    }
+
+    fn interfaces(&self) -> Vec<Interface> {
+        if_addrs::get_if_addrs()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|interface| Interface {
+                ip: interface.ip(),
+                name: interface.name,
+            })
+            .collect()
+    }
+}
+
+fn get_local_socket(local_addr: SocketAddr) -> std::io::Result<TcpSocket> {
+    let socket = match local_addr {
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+    };
+
+    let sock2 = SockRef::from(&socket);
+
+    let _ = sock2.set_reuse_address(true);
+    let _ = sock2.set_reuse_port(true);
+
+    let keepalive = TcpKeepalive::new()
+        .with_time(Duration::from_secs(10))
+        .with_interval(Duration::from_secs(1))
+        .with_retries(10);
+    let _ = sock2.set_tcp_keepalive(&keepalive);
+
+    socket.bind(local_addr)?;
+    Ok(socket)
+}
@@ -1,71 +1,400 @@
+use futures::stream::{FuturesUnordered, StreamExt};
+use hkdf::Hkdf;
 use rand::Rng;
-use sha2::{Digest, Sha256};
-use socket2::{SockRef, TcpKeepalive};
+use sha2::Sha256;
 use spake2::{Ed25519Group, Identity, Password, Spake2};
-use std::{net::SocketAddr, pin::Pin, time::Duration};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpSocket, TcpStream},
+use std::{
+    cmp::Reverse,
+    collections::VecDeque,
+    net::{SocketAddr, SocketAddrV4},
+    pin::Pin,
+    time::Duration,
 };
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-use crate::FullContact;
+use crate::{FullContact, Transport, RELAY_MAGIC};
 use std::future::Future;
+use std::sync::Arc;
 
-use super::ClientError;
+use super::shared_listener::{Incoming, SharedListener};
+use super::{ClientError, Network, PortMapping, TcpNetwork};
 
 pub type PeerSecret = u32;
 
-type PeerConnection = (TcpStream, [u8; 32]);
+/// A verified connection to a peer.
+#[derive(Debug)]
+pub struct PeerConnection {
+    /// Stream to the peer, however the connection was made
+    pub stream: Box<dyn Transport>,
+    /// Secret only the two peers know, to encrypt `stream` with
+    pub shared_secret: [u8; 32],
+    /// How the connection reaches the peer
+    pub path: PeerPath,
+    /// True if the connection runs over IPv6, false if over IPv4
+    pub ipv6: bool,
+}
+
+/// How a [`PeerConnection`] reaches the peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerPath {
+    /// Connected to one of the peer's private addresses, such as on the same LAN.
+    Private,
+    /// Connected through the peer's NAT, to the public address the server saw.
+    Public,
+    /// Connected to the port the peer's router mapped for it.
+    Mapped,
+    /// Connected to a public port the peer's NAT was predicted to allocate.
+    Predicted,
+    /// Accepted from the peer on one of this client's local candidates.
+    Accepted,
+    /// Forwarded by the server's relay.
+    Relay,
+}
+
+/// A connection attempt that resolves once the peer on the other end has been verified.
+type Attempt = Pin<Box<dyn Future<Output = std::io::Result<PeerConnection>> + Send>>;
+
+/// Time between starting consecutive connection attempts,
+/// so that higher priority paths get a head start.
+const ATTEMPT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Time to wait before connecting again to an address
+/// that answered, but not as the peer.
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+/// How many of the peer's predicted public ports to try
+/// if its NAT allocates a new port to each connection.
+const PREDICTION_WINDOW: u16 = 16;
 
-pub struct PeerConnector {
+/// Sent by the creator on the one verified connection both peers should keep.
+const NOMINATE: u8 = 1;
+
+/// Sent by the joiner to accept the nominated connection.
+const NOMINATION_ACK: u8 = 2;
+
+/// Sent by the creator once the joiner accepted in time.
+/// Only then do both peers keep the connection, so a late
+/// [`NOMINATION_ACK`] can't leave the joiner on a connection the creator gave up on.
+const NOMINATION_CONFIRM: u8 = 3;
+
+/// How long the creator waits for the joiner to acknowledge a nomination
+/// before giving up on the connection and nominating the next one.
+const NOMINATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the joiner waits for a nomination on a verified connection.
+/// Longer than [`NOMINATION_TIMEOUT`], since the creator may first
+/// wait out a silent connection before nominating this one.
+const NOMINATION_WAIT: Duration = Duration::from_secs(30);
+
+pub struct PeerConnector<N: Network = TcpNetwork> {
     pub(super) local: FullContact,
     pub(super) peer: FullContact,
     pub(super) is_creator: bool,
+    /// Tells apart the joiners of a room, so that the creator only
+    /// accepts a connection from the joiner this connector is for.
+    pub(super) joiner_id: u32,
+    /// Lets both peers connect through the server's relay.
+    /// `None` if the contacts weren't shared by a server.
+    pub(super) relay_token: Option<u64>,
+    /// Keeps the router forwarding [`FullContact::mapped`] to this client
+    /// until every connector using it is dropped.
+    pub(super) port_mapping: Option<Arc<PortMapping>>,
+    /// The network to reach the peer over
+    pub(super) network: N,
+    /// Shared by the connectors of every joiner, which listen on the same addresses
+    pub(super) listener: SharedListener,
 }
 
 impl PeerConnector {
-    pub fn get_local_contact(&self) -> FullContact {
-        self.local
+    /// Creates a connector from contacts exchanged without a server,
+    /// such as with [`encode_contact()`](super::encode_contact).
+    ///
+    /// Exactly one of the two peers must be the creator.
+    pub fn new(local: FullContact, peer: FullContact, is_creator: bool) -> Self {
+        Self {
+            local,
+            peer,
+            is_creator,
+            joiner_id: 0,
+            relay_token: None,
+            port_mapping: None,
+            network: TcpNetwork,
+            listener: SharedListener::default(),
+        }
+    }
+}
+
+impl<N: Network> PeerConnector<N> {
+    /// Reaches the peer over `network` instead, such as a simulated one.
+    pub fn with_network<M: Network>(self, network: M) -> PeerConnector<M> {
+        PeerConnector {
+            local: self.local,
+            peer: self.peer,
+            is_creator: self.is_creator,
+            joiner_id: self.joiner_id,
+            relay_token: self.relay_token,
+            port_mapping: self.port_mapping,
+            network,
+            listener: self.listener,
+        }
+    }
+
+    pub fn get_local_contact(&self) -> &FullContact {
+        &self.local
+    }
+
+    pub fn get_peer_contact(&self) -> &FullContact {
+        &self.peer
+    }
+
+    /// True if this peer created the room.
+    /// Tells the two ends of the connection apart, such as to derive a key for each direction.
+    pub fn is_creator(&self) -> bool {
+        self.is_creator
+    }
+
+    /// Id of the joiner this connector connects to or from.
+    /// Tells apart the peers of a room with several joiners.
+    pub fn joiner_id(&self) -> u32 {
+        self.joiner_id
+    }
+
+    /// The port mapping this client's router made for the connection, if any.
+    /// Keep a clone to hold the mapping open after [`PeerConnector::connect_to_peer()`].
+    pub fn port_mapping(&self) -> Option<Arc<PortMapping>> {
+        self.port_mapping.clone()
     }
 
-    pub fn get_peer_contact(&self) -> FullContact {
-        self.peer
+    /// True if either peer can only be reached through the server's relay,
+    /// so both must connect with [`PeerConnector::connect_via_relay()`].
+    pub fn needs_relay(&self) -> bool {
+        self.relay_token.is_some() && (self.local.relay_only || self.peer.relay_only)
     }
 
+    /// Connects to the peer through the server's relay, over `server`:
+    /// a new plain TCP connection to the server, possibly through a proxy.
+    ///
+    /// The server only forwards the bytes, which the peers verify
+    /// each other with just like a direct connection.
+    pub async fn connect_via_relay(
+        self,
+        mut server: impl Transport + 'static,
+        shared_secret: PeerSecret,
+    ) -> std::io::Result<PeerConnection> {
+        let Some(relay_token) = self.relay_token else {
+            return Err(std::io::ErrorKind::Unsupported.into());
+        };
+
+        let mut header = vec![RELAY_MAGIC];
+        header.extend_from_slice(&relay_token.to_be_bytes());
+        server.write_all(&header).await?;
+
+        let peer_id = PeerId {
+            secret: shared_secret,
+            joiner_id: self.joiner_id,
+        };
+        let ipv6 = server.local_addr().is_ok_and(|addr| addr.is_ipv6());
+        let (stream, shared_secret) = verify_peer(peer_id, server, self.is_creator)
+            .await
+            .map_err(std::io::Error::other)?;
+
+        Ok(PeerConnection {
+            stream: Box::new(stream),
+            shared_secret,
+            path: PeerPath::Relay,
+            ipv6,
+        })
+    }
+
+    /// Tries every known path to the peer at once, and returns a single
+    /// verified connection.
+    ///
+    /// Several paths may lead to a verified connection, so the creator nominates
+    /// one of them, the joiner accepts it, and the creator confirms it.
+    /// Both peers keep that connection and close the rest.
     pub async fn connect_to_peer(
         self,
         shared_secret: PeerSecret,
     ) -> std::io::Result<PeerConnection> {
-        let c = self.is_creator;
-        let p = shared_secret;
-        let mut futs: Vec<Pin<Box<dyn Future<Output = std::io::Result<PeerConnection>>>>> =
-            Vec::with_capacity(6);
+        let candidates = self.attempts(shared_secret);
 
-        if let Some(local) = self.local.private.v6 {
-            futs.push(Box::pin(try_accept(local, p, c)));
+        if self.is_creator {
+            nominate(candidates).await
+        } else {
+            accept_nomination(candidates).await
+        }
+    }
+
+    /// Returns a connection attempt for every path to the peer.
+    ///
+    /// Listens on every local candidate right away, and connects from each
+    /// local candidate to each peer candidate of the same IP family in priority order,
+    /// starting one every [`ATTEMPT_INTERVAL`]. The peer's public and mapped
+    /// addresses come last, followed by its predicted public ports.
+    fn attempts(&self, shared_secret: PeerSecret) -> Vec<Attempt> {
+        let c = self.is_creator;
+        let p = PeerId {
+            secret: shared_secret,
+            joiner_id: self.joiner_id,
+        };
+        let mut futs: Vec<Attempt> = Vec::new();
+
+        for local in &self.local.private {
+            match self
+                .listener
+                .subscribe(&self.network, local.addr, self.joiner_id)
+            {
+                Ok(incoming) => futs.push(Box::pin(try_accept(incoming, p, c))),
+                Err(err) => futs.push(Box::pin(std::future::ready(Err(err)))),
+            }
+        }
 
-            if let Some(peer) = self.peer.private.v6 {
-                futs.push(Box::pin(try_connect(local, peer, p, c)));
+        // (priority, local address, peer address, path)
+        let mut pairs = Vec::new();
+        for local in &self.local.private {
+            for peer in &self.peer.private {
+                if local.addr.is_ipv6() == peer.addr.is_ipv6() {
+                    let priority = local.priority + peer.priority;
+                    pairs.push((priority, local.addr, peer.addr, PeerPath::Private));
+                }
             }
-            if let Some(peer) = self.peer.public.v6 {
-                futs.push(Box::pin(try_connect(local, peer, p, c)));
+        }
+        pairs.sort_by_key(|&(priority, _, _, _)| Reverse(priority));
+
+        // the peer's public addresses are reached through
+        // the same local addresses used to reach the server
+        if let (Some(local), Some(peer)) = (self.local.first_private(true), self.peer.public.v6) {
+            pairs.push((0, local, peer.into(), PeerPath::Public));
+        }
+        if let (Some(local), Some(peer)) = (self.local.first_private(false), self.peer.public.v4) {
+            pairs.push((0, local, peer.into(), PeerPath::Public));
+        }
+        if let (Some(local), Some(peer)) = (self.local.first_private(false), self.peer.mapped) {
+            pairs.push((0, local, peer.into(), PeerPath::Mapped));
+        }
+        if let (Some(local), Some(peer), Some(allocation)) = (
+            self.local.first_private(false),
+            self.peer.public.v4,
+            self.peer.port_allocation,
+        ) {
+            for port in allocation.predict(PREDICTION_WINDOW) {
+                let peer = SocketAddrV4::new(*peer.ip(), port).into();
+                pairs.push((0, local, peer, PeerPath::Predicted));
             }
         }
 
-        if let Some(local) = self.local.private.v4 {
-            futs.push(Box::pin(try_accept(local, p, c)));
+        for (delay, (_, local, peer, path)) in (0..).zip(pairs) {
+            let network = self.network.clone();
+            futs.push(Box::pin(async move {
+                tokio::time::sleep(ATTEMPT_INTERVAL * delay).await;
+                try_connect(network, local, peer, path, p, c).await
+            }));
+        }
 
-            if let Some(peer) = self.peer.private.v4 {
-                futs.push(Box::pin(try_connect(local, peer, p, c)));
+        futs
+    }
+}
+
+/// Nominates the first candidate that gets verified,
+/// moving on to the next one if the joiner doesn't accept it in time.
+///
+/// Only one candidate is nominated at a time, but the others keep
+/// getting verified meanwhile, and wait their turn in verification order.
+async fn nominate(candidates: Vec<Attempt>) -> std::io::Result<PeerConnection> {
+    let mut candidates: FuturesUnordered<Attempt> = candidates.into_iter().collect();
+    let mut verified = VecDeque::new();
+    let mut nomination: Option<Attempt> = None;
+    let mut last_err = None;
+
+    loop {
+        if nomination.is_none() {
+            if let Some(mut connection) = verified.pop_front() {
+                nomination = Some(Box::pin(async move {
+                    send_nomination(&mut connection.stream).await?;
+                    Ok(connection)
+                }));
             }
+        }
 
-            if let Some(peer) = self.peer.public.v4 {
-                futs.push(Box::pin(try_connect(local, peer, p, c)));
+        tokio::select! {
+            result = async { nomination.as_mut().unwrap().await }, if nomination.is_some() => {
+                nomination = None;
+                match result {
+                    Ok(connection) => return Ok(connection),
+                    Err(err) => last_err = Some(err),
+                }
             }
+            Some(candidate) = candidates.next() => match candidate {
+                Ok(connection) => verified.push_back(connection),
+                Err(err) => last_err = Some(err),
+            },
+            else => break,
         }
+    }
 
-        Ok(futures::future::select_ok(futs).await?.0)
+    Err(last_err.unwrap_or_else(|| std::io::ErrorKind::NotConnected.into()))
+}
+
+/// Waits for the creator to nominate and confirm one of the verified candidates.
+/// Candidates that get verified but aren't confirmed are closed once one is.
+async fn accept_nomination(candidates: Vec<Attempt>) -> std::io::Result<PeerConnection> {
+    let mut candidates: FuturesUnordered<_> = candidates
+        .into_iter()
+        .map(|candidate| async move {
+            let mut connection = candidate.await?;
+            receive_nomination(&mut connection.stream).await?;
+            Ok::<_, std::io::Error>(connection)
+        })
+        .collect();
+    let mut last_err = None;
+
+    while let Some(candidate) = candidates.next().await {
+        match candidate {
+            Ok(connection) => return Ok(connection),
+            Err(err) => last_err = Some(err),
+        }
     }
+
+    Err(last_err.unwrap_or_else(|| std::io::ErrorKind::NotConnected.into()))
+}
+
+async fn send_nomination(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+) -> std::io::Result<()> {
+    tokio::time::timeout(NOMINATION_TIMEOUT, async {
+        stream.write_all(&[NOMINATE]).await?;
+        if stream.read_u8().await? == NOMINATION_ACK {
+            Ok(())
+        } else {
+            Err(std::io::ErrorKind::InvalidData.into())
+        }
+    })
+    .await??;
+    stream.write_all(&[NOMINATION_CONFIRM]).await
+}
+
+async fn receive_nomination(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+) -> std::io::Result<()> {
+    tokio::time::timeout(NOMINATION_WAIT, async {
+        if stream.read_u8().await? != NOMINATE {
+            return Err(std::io::ErrorKind::InvalidData.into());
+        }
+        stream.write_all(&[NOMINATION_ACK]).await?;
+        if stream.read_u8().await? == NOMINATION_CONFIRM {
+            Ok(())
+        } else {
+            Err(std::io::ErrorKind::InvalidData.into())
+        }
+    })
+    .await?
+}
+
+/// What a peer must know to pass [`verify_peer()`].
+#[derive(Clone, Copy)]
+struct PeerId {
+    secret: PeerSecret,
+    joiner_id: u32,
 }
 
 pub fn random_peer_secret() -> PeerSecret {
@@ -73,47 +402,67 @@ pub fn random_peer_secret() -> PeerSecret {
     rng.gen_range(0..32768)
 }
 
-async fn try_connect<T: Into<SocketAddr>>(
-    local: T,
-    peer: T,
-    peer_id: PeerSecret,
+async fn try_connect<N: Network>(
+    network: N,
+    local: SocketAddr,
+    peer: SocketAddr,
+    path: PeerPath,
+    peer_id: PeerId,
     is_creator: bool,
 ) -> std::io::Result<PeerConnection> {
-    let local = local.into();
-    let peer = peer.into();
     loop {
-        let local_socket = get_local_socket(local)?;
-        let stream = local_socket.connect(peer).await?;
-        if let Ok(connection) = verify_peer(peer_id, stream, is_creator).await {
-            return Ok(connection);
+        let mut stream = network.connect(local, peer).await?;
+        // tells the peer's shared listener which of its connectors this is for.
+        // The peer answers with the same id, either from its listener or,
+        // in a simultaneous open, from its own `try_connect()`.
+        stream.write_u32(peer_id.joiner_id).await?;
+        if matches!(stream.read_u32().await, Ok(id) if id == peer_id.joiner_id) {
+            if let Ok((stream, shared_secret)) = verify_peer(peer_id, stream, is_creator).await {
+                return Ok(PeerConnection {
+                    stream: Box::new(stream),
+                    shared_secret,
+                    path,
+                    ipv6: local.is_ipv6(),
+                });
+            }
         }
+
+        // something answered that isn't the peer, or isn't ready for this joiner yet
+        tokio::time::sleep(RECONNECT_DELAY).await;
     }
 }
 
 async fn try_accept(
-    local: impl Into<SocketAddr>,
-    peer_id: PeerSecret,
+    mut incoming: Incoming,
+    peer_id: PeerId,
     is_creator: bool,
 ) -> std::io::Result<PeerConnection> {
-    let local = local.into();
-    let local_socket = get_local_socket(local)?;
-    let listener = local_socket.listen(1024)?;
     loop {
-        let (stream, _addr) = listener.accept().await?;
-        if let Ok(connection) = verify_peer(peer_id, stream, is_creator).await {
-            return Ok(connection);
+        let stream = incoming.next().await?;
+        if let Ok((stream, shared_secret)) = verify_peer(peer_id, stream, is_creator).await {
+            return Ok(PeerConnection {
+                stream,
+                shared_secret,
+                path: PeerPath::Accepted,
+                ipv6: incoming.local_addr().is_ipv6(),
+            });
         }
     }
 }
 
-async fn verify_peer(
-    peer_id: PeerSecret,
-    mut stream: TcpStream,
+/// Runs a password-authenticated key exchange with the peer over `stream`,
+/// and returns the stream along with a secret for the channel if the peer knew the secret.
+///
+/// The verification codes and the channel's secret are each derived
+/// from the exchanged key with HKDF, so none of them reveals another.
+async fn verify_peer<S: AsyncRead + AsyncWrite + Unpin>(
+    peer_id: PeerId,
+    mut stream: S,
     is_creator: bool,
-) -> Result<PeerConnection, ClientError> {
+) -> Result<(S, [u8; 32]), ClientError> {
     let (spake, outbound_msg) = Spake2::<Ed25519Group>::start_symmetric(
-        &Password::new(peer_id.to_be_bytes()),
-        &Identity::new(b"psend peer"),
+        &Password::new(peer_id.secret.to_be_bytes()),
+        &Identity::new(format!("psend peer {}", peer_id.joiner_id).as_bytes()),
     );
 
     stream.write_all(&outbound_msg).await?;
@@ -121,10 +470,11 @@ async fn verify_peer(
     let mut inbound_message = [0; 33];
     stream.read_exact(&mut inbound_message).await?;
 
-    let shared_secret: [u8; 32] = spake.finish(&inbound_message)?.try_into().unwrap();
+    let exchanged_key = spake.finish(&inbound_message)?;
+    let hkdf = Hkdf::<Sha256>::new(None, &exchanged_key);
 
-    let my_code = get_verification_code(shared_secret, is_creator);
-    let peer_code = get_verification_code(shared_secret, !is_creator);
+    let my_code = get_verification_code(&hkdf, is_creator);
+    let peer_code = get_verification_code(&hkdf, !is_creator);
 
     stream.write_all(&my_code).await?;
 
@@ -133,37 +483,243 @@ async fn verify_peer(
     stream.read_exact(&mut received).await?;
 
     if received == peer_code {
-        Ok((stream, shared_secret))
+        Ok((stream, expand(&hkdf, b"gday peer channel")))
     } else {
         Err(ClientError::PeerAuthenticationFailed)
     }
 }
 
-fn get_verification_code(key: [u8; 32], is_creator: bool) -> [u8; 32] {
-    Sha256::new()
-        .chain_update(key)
-        .chain_update([u8::from(is_creator)])
-        .finalize()
-        .into()
+fn get_verification_code(hkdf: &Hkdf<Sha256>, is_creator: bool) -> [u8; 32] {
+    if is_creator {
+        expand(hkdf, b"gday peer verification creator")
+    } else {
+        expand(hkdf, b"gday peer verification joiner")
+    }
 }
 
-fn get_local_socket(local_addr: SocketAddr) -> std::io::Result<TcpSocket> {
-    let socket = match local_addr {
-        SocketAddr::V6(_) => TcpSocket::new_v6()?,
-        SocketAddr::V4(_) => TcpSocket::new_v4()?,
-    };
+fn expand(hkdf: &Hkdf<Sha256>, info: &[u8]) -> [u8; 32] {
+    let mut key = [0; 32];
+    hkdf.expand(info, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Candidate, Contact};
+    use futures::future::join_all;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// Returns `n` pairs of connected loopback streams.
+    async fn loopback_pairs(n: usize) -> Vec<(TcpStream, TcpStream)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut pairs = Vec::with_capacity(n);
+        for _ in 0..n {
+            let (a, b) = tokio::join!(TcpStream::connect(addr), listener.accept());
+            pairs.push((a.unwrap(), b.unwrap().0));
+        }
+        pairs
+    }
+
+    /// A candidate that gets verified after `millis` milliseconds.
+    fn verified_after(stream: TcpStream, millis: u64) -> Attempt {
+        Box::pin(async move {
+            tokio::time::sleep(Duration::from_millis(millis)).await;
+            Ok(PeerConnection {
+                stream: Box::new(stream),
+                shared_secret: [0; 32],
+                path: PeerPath::Private,
+                ipv6: false,
+            })
+        })
+    }
+
+    /// Asserts that `a` and `b` are the two ends of the same connection.
+    async fn assert_same_connection(mut a: Box<dyn Transport>, mut b: Box<dyn Transport>) {
+        assert_eq!(a.local_addr().unwrap(), b.peer_addr().unwrap());
+        a.write_all(b"hi").await.unwrap();
+        let mut buf = [0; 2];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hi");
+    }
+
+    /// The peers verify the candidates in opposite orders,
+    /// so keeping the first verified one would leave them on different connections.
+    #[tokio::test]
+    async fn peers_agree_on_one_connection() {
+        let mut creator = Vec::new();
+        let mut joiner = Vec::new();
+        for (i, (a, b)) in (0..).zip(loopback_pairs(3).await) {
+            creator.push(verified_after(a, 20 * i));
+            joiner.push(verified_after(b, 20 * (2 - i)));
+        }
+
+        let (creator, joiner) = tokio::join!(nominate(creator), accept_nomination(joiner));
+        assert_same_connection(creator.unwrap().stream, joiner.unwrap().stream).await;
+    }
+
+    /// If the nominated connection breaks, the creator nominates the next one.
+    #[tokio::test]
+    async fn renominates_after_broken_connection() {
+        let mut pairs = loopback_pairs(2).await;
+        let (a1, b1) = pairs.pop().unwrap();
+        let (a0, b0) = pairs.pop().unwrap();
+        drop(b0);
+
+        let creator = vec![verified_after(a0, 0), verified_after(a1, 20)];
+        let joiner = vec![verified_after(b1, 0)];
+
+        let (creator, joiner) = tokio::join!(nominate(creator), accept_nomination(joiner));
+        assert_same_connection(creator.unwrap().stream, joiner.unwrap().stream).await;
+    }
+
+    /// If the nominated connection stays silent, the creator nominates the next one.
+    #[tokio::test(start_paused = true)]
+    async fn renominates_after_silent_connection() {
+        let mut pairs = loopback_pairs(2).await;
+        let (a1, b1) = pairs.pop().unwrap();
+        let (a0, _b0) = pairs.pop().unwrap();
+
+        let creator = vec![verified_after(a0, 0), verified_after(a1, 20)];
+        let joiner = vec![verified_after(b1, 0)];
+
+        let (creator, joiner) = tokio::join!(nominate(creator), accept_nomination(joiner));
+        assert_same_connection(creator.unwrap().stream, joiner.unwrap().stream).await;
+    }
+
+    /// Candidates keep getting verified while another one is nominated.
+    #[tokio::test(start_paused = true)]
+    async fn verifies_while_nominating() {
+        let mut pairs = loopback_pairs(2).await;
+        let (a1, b1) = pairs.pop().unwrap();
+        let (a0, _b0) = pairs.pop().unwrap();
+
+        let start = tokio::time::Instant::now();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let second: Attempt = Box::pin(async move {
+            let connection = verified_after(a1, 20).await;
+            let _ = tx.send(start.elapsed());
+            connection
+        });
+        let creator = vec![verified_after(a0, 0), second];
+        let joiner = vec![verified_after(b1, 0)];
+
+        let (creator, joiner) = tokio::join!(nominate(creator), accept_nomination(joiner));
+        assert_same_connection(creator.unwrap().stream, joiner.unwrap().stream).await;
+        // verified before the nomination of the silent connection timed out
+        assert!(rx.await.unwrap() < NOMINATION_TIMEOUT);
+    }
+
+    /// If the joiner accepts a nomination after the creator gave up on it,
+    /// the creator doesn't confirm it, so the joiner doesn't keep it.
+    #[tokio::test(start_paused = true)]
+    async fn ignores_late_nomination_ack() {
+        let mut pairs = loopback_pairs(2).await;
+        let (a1, b1) = pairs.pop().unwrap();
+        let (a0, mut b0) = pairs.pop().unwrap();
+
+        let creator = vec![verified_after(a0, 0), verified_after(a1, 20)];
+        let joiner = vec![verified_after(b1, 0)];
+        let late_joiner = async {
+            tokio::time::sleep(NOMINATION_TIMEOUT * 2).await;
+            receive_nomination(&mut b0).await
+        };
+
+        let (creator, joiner, late) =
+            tokio::join!(nominate(creator), accept_nomination(joiner), late_joiner);
+        assert!(late.is_err());
+        assert_same_connection(creator.unwrap().stream, joiner.unwrap().stream).await;
+    }
 
-    let sock2 = SockRef::from(&socket);
+    /// Both peers connect to each other over loopback at the same time.
+    #[tokio::test]
+    async fn connect_on_loopback() {
+        for _ in 0..10 {
+            let [creator_addr, joiner_addr] = [free_addr().await, free_addr().await];
+            let creator = PeerConnector::new(
+                private_contact(creator_addr),
+                private_contact(joiner_addr),
+                true,
+            );
+            let joiner = PeerConnector::new(
+                private_contact(joiner_addr),
+                private_contact(creator_addr),
+                false,
+            );
+
+            let (creator, joiner) =
+                tokio::join!(creator.connect_to_peer(7), joiner.connect_to_peer(7));
+            let (creator, joiner) = (creator.unwrap(), joiner.unwrap());
+            assert_eq!(creator.shared_secret, joiner.shared_secret);
+            assert!(!creator.ipv6 && !joiner.ipv6);
+            assert_same_connection(creator.stream, joiner.stream).await;
+        }
+    }
 
-    let _ = sock2.set_reuse_address(true);
-    let _ = sock2.set_reuse_port(true);
+    /// The connectors for several joiners listen on the same address,
+    /// and each gets the connection of its own joiner.
+    #[tokio::test]
+    async fn joiners_share_one_listener() {
+        let creator_addr = free_addr().await;
+        let listener = SharedListener::default();
+        let mut creators = Vec::new();
+        let mut joiners = Vec::new();
+
+        for joiner_id in 1..=3 {
+            let joiner_addr = free_addr().await;
+            // the creator only accepts, since it doesn't know the joiners' addresses
+            let mut creator = PeerConnector::new(private_contact(creator_addr), no_contact(), true);
+            creator.joiner_id = joiner_id;
+            creator.listener = listener.clone();
+            let mut joiner = PeerConnector::new(
+                private_contact(joiner_addr),
+                private_contact(creator_addr),
+                false,
+            );
+            joiner.joiner_id = joiner_id;
+
+            // each joiner has its own secret, so a misrouted connection isn't verified
+            creators.push(creator.connect_to_peer(joiner_id));
+            joiners.push(joiner.connect_to_peer(joiner_id));
+        }
 
-    let keepalive = TcpKeepalive::new()
-        .with_time(Duration::from_secs(10))
-        .with_interval(Duration::from_secs(1))
-        .with_retries(10);
-    let _ = sock2.set_tcp_keepalive(&keepalive);
+        let (creators, joiners) = tokio::join!(join_all(creators), join_all(joiners));
+        for (creator, joiner) in creators.into_iter().zip(joiners) {
+            let (creator, joiner) = (creator.unwrap(), joiner.unwrap());
+            assert_eq!(creator.path, PeerPath::Accepted);
+            assert_eq!(creator.shared_secret, joiner.shared_secret);
+            assert_same_connection(creator.stream, joiner.stream).await;
+        }
+    }
 
-    socket.bind(local_addr)?;
-    Ok(socket)
+    async fn free_addr() -> SocketAddrV4 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, listener.local_addr().unwrap().port())
+    }
+
+    fn no_contact() -> FullContact {
+        FullContact {
+            private: Vec::new(),
+            public: Contact::default(),
+            mapped: None,
+            port_allocation: None,
+            relay_only: false,
+        }
+    }
+
+    fn private_contact(addr: SocketAddrV4) -> FullContact {
+        FullContact {
+            private: vec![Candidate {
+                addr: addr.into(),
+                priority: 0,
+            }],
+            public: Contact::default(),
+            mapped: None,
+            port_allocation: None,
+            relay_only: false,
+        }
+    }
 }
@@ -0,0 +1,527 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::net::UdpSocket;
+
+/// How long a mapping lasts, in seconds, if it isn't removed.
+const MAPPING_LIFETIME: u32 = 60 * 60;
+
+/// Port that PCP and NAT-PMP gateways listen on.
+const GATEWAY_PORT: u16 = 5351;
+
+/// How long to wait for each attempt's reply from a PCP or NAT-PMP gateway.
+const RETRY_TIMEOUTS: [Duration; 3] = [
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+    Duration::from_millis(1000),
+];
+
+/// How long to search for a UPnP gateway.
+#[cfg(feature = "upnp")]
+const UPNP_SEARCH_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// PCP opcode of a MAP request
+const PCP_MAP: u8 = 1;
+
+/// NAT-PMP opcode of an external address request
+const NAT_PMP_EXTERNAL_ADDR: u8 = 0;
+
+/// NAT-PMP opcode of a TCP mapping request
+const NAT_PMP_MAP_TCP: u8 = 2;
+
+/// IANA protocol number of TCP
+const TCP: u8 = 6;
+
+#[derive(Error, Debug)]
+pub enum PortMappingError {
+    #[error("IO Error: {0}")]
+    IO(#[from] std::io::Error),
+
+    #[error("The router didn't reply to the port mapping request.")]
+    NoReply,
+
+    #[error("The router sent an invalid reply to the port mapping request.")]
+    InvalidReply,
+
+    #[error("The router refused the port mapping request with result code {0}.")]
+    Refused(u16),
+
+    #[cfg(feature = "upnp")]
+    #[error("UPnP error: {0}")]
+    Upnp(#[from] igd_next::Error),
+}
+
+/// The protocol a [`PortMapping`] was requested with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingProtocol {
+    /// Port Control Protocol (RFC 6887)
+    Pcp,
+    /// NAT Port Mapping Protocol (RFC 6886)
+    NatPmp,
+    /// UPnP Internet Gateway Device
+    #[cfg(feature = "upnp")]
+    Upnp,
+}
+
+/// A port the router forwards to a local TCP socket,
+/// so that peers can connect to the socket from outside the local network.
+///
+/// The mapping is removed when this is dropped, as long as a tokio runtime is still running.
+/// Otherwise the router removes it after an hour.
+#[derive(Debug)]
+pub struct PortMapping {
+    mapping: Mapping,
+    external: SocketAddrV4,
+    removed: AtomicBool,
+}
+
+/// What's needed to remove a mapping.
+#[derive(Debug, Clone)]
+enum Mapping {
+    Pcp {
+        gateway: SocketAddr,
+        local: SocketAddrV4,
+        /// Identifies the mapping to the gateway
+        nonce: [u8; 12],
+    },
+    NatPmp {
+        gateway: SocketAddr,
+        local: SocketAddrV4,
+    },
+    #[cfg(feature = "upnp")]
+    Upnp {
+        gateway: igd_next::aio::Gateway<igd_next::aio::tokio::Tokio>,
+        external_port: u16,
+    },
+}
+
+impl PortMapping {
+    /// Asks the router to forward TCP connections from one of its public ports to `local`.
+    ///
+    /// Tries PCP and NAT-PMP with the default gateway, then UPnP if the `upnp` feature is enabled.
+    pub async fn request(local: SocketAddrV4) -> Result<Self, PortMappingError> {
+        let gateway = SocketAddr::new(default_gateway(*local.ip()).into(), GATEWAY_PORT);
+        Self::request_from(gateway, local).await
+    }
+
+    /// Like [`PortMapping::request()`], but sends the PCP and NAT-PMP requests to `gateway`.
+    pub async fn request_from(
+        gateway: SocketAddr,
+        local: SocketAddrV4,
+    ) -> Result<Self, PortMappingError> {
+        let pcp_err = match request_pcp(gateway, local).await {
+            Ok(mapping) => return Ok(mapping),
+            Err(err) => err,
+        };
+
+        let nat_pmp_err = match request_nat_pmp(gateway, local).await {
+            Ok(mapping) => return Ok(mapping),
+            Err(err) => err,
+        };
+
+        #[cfg(feature = "upnp")]
+        if let Ok(mapping) = request_upnp(local).await {
+            return Ok(mapping);
+        }
+
+        // a gateway that doesn't support PCP may still reply to NAT-PMP
+        if matches!(nat_pmp_err, PortMappingError::NoReply) {
+            Err(pcp_err)
+        } else {
+            Err(nat_pmp_err)
+        }
+    }
+
+    /// The public address the router forwards to the local socket.
+    pub fn external_addr(&self) -> SocketAddrV4 {
+        self.external
+    }
+
+    /// The protocol this mapping was requested with.
+    pub fn protocol(&self) -> MappingProtocol {
+        match self.mapping {
+            Mapping::Pcp { .. } => MappingProtocol::Pcp,
+            Mapping::NatPmp { .. } => MappingProtocol::NatPmp,
+            #[cfg(feature = "upnp")]
+            Mapping::Upnp { .. } => MappingProtocol::Upnp,
+        }
+    }
+
+    /// Asks the router to remove this mapping.
+    pub async fn remove(&self) -> Result<(), PortMappingError> {
+        if self.removed.swap(true, Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.mapping.remove().await
+    }
+}
+
+impl Drop for PortMapping {
+    fn drop(&mut self) {
+        if self.removed.load(Ordering::Relaxed) {
+            return;
+        }
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let mapping = self.mapping.clone();
+            runtime.spawn(async move {
+                // don't care about error, since the mapping expires anyway
+                let _ = mapping.remove().await;
+            });
+        }
+    }
+}
+
+impl Mapping {
+    async fn remove(&self) -> Result<(), PortMappingError> {
+        match self {
+            Mapping::Pcp {
+                gateway,
+                local,
+                nonce,
+            } => {
+                let request = pcp_map_request(*local, *nonce, 0);
+                let reply = transact(*gateway, &request).await?;
+                parse_pcp_reply(&reply, *nonce).map(|_| ())
+            }
+            Mapping::NatPmp { gateway, local } => {
+                let request = nat_pmp_map_request(*local, 0);
+                let reply = transact(*gateway, &request).await?;
+                parse_nat_pmp_map_reply(&reply).map(|_| ())
+            }
+            #[cfg(feature = "upnp")]
+            Mapping::Upnp {
+                gateway,
+                external_port,
+            } => gateway
+                .remove_port(igd_next::PortMappingProtocol::TCP, *external_port)
+                .await
+                .map_err(|err| igd_next::Error::from(err).into()),
+        }
+    }
+}
+
+async fn request_pcp(
+    gateway: SocketAddr,
+    local: SocketAddrV4,
+) -> Result<PortMapping, PortMappingError> {
+    let nonce = rand::random();
+    let request = pcp_map_request(local, nonce, MAPPING_LIFETIME);
+    let reply = transact(gateway, &request).await?;
+    let external = parse_pcp_reply(&reply, nonce)?;
+
+    Ok(PortMapping {
+        mapping: Mapping::Pcp {
+            gateway,
+            local,
+            nonce,
+        },
+        external,
+        removed: AtomicBool::new(false),
+    })
+}
+
+async fn request_nat_pmp(
+    gateway: SocketAddr,
+    local: SocketAddrV4,
+) -> Result<PortMapping, PortMappingError> {
+    let reply = transact(gateway, &[0, NAT_PMP_EXTERNAL_ADDR]).await?;
+    let external_ip = parse_nat_pmp_addr_reply(&reply)?;
+
+    let request = nat_pmp_map_request(local, MAPPING_LIFETIME);
+    let reply = transact(gateway, &request).await?;
+    let external_port = parse_nat_pmp_map_reply(&reply)?;
+
+    Ok(PortMapping {
+        mapping: Mapping::NatPmp { gateway, local },
+        external: SocketAddrV4::new(external_ip, external_port),
+        removed: AtomicBool::new(false),
+    })
+}
+
+#[cfg(feature = "upnp")]
+async fn request_upnp(local: SocketAddrV4) -> Result<PortMapping, PortMappingError> {
+    use igd_next::PortMappingProtocol::TCP as UPNP_TCP;
+
+    let options = igd_next::SearchOptions {
+        timeout: Some(UPNP_SEARCH_TIMEOUT),
+        ..Default::default()
+    };
+    let gateway = igd_next::aio::tokio::search_gateway(options)
+        .await
+        .map_err(igd_next::Error::from)?;
+
+    let IpAddr::V4(external_ip) = gateway
+        .get_external_ip()
+        .await
+        .map_err(igd_next::Error::from)?
+    else {
+        return Err(PortMappingError::InvalidReply);
+    };
+
+    // prefer the same port as the local socket, but accept any
+    let external_port = if gateway
+        .add_port(
+            UPNP_TCP,
+            local.port(),
+            local.into(),
+            MAPPING_LIFETIME,
+            "gday",
+        )
+        .await
+        .is_ok()
+    {
+        local.port()
+    } else {
+        gateway
+            .add_any_port(UPNP_TCP, local.into(), MAPPING_LIFETIME, "gday")
+            .await
+            .map_err(igd_next::Error::from)?
+    };
+
+    Ok(PortMapping {
+        mapping: Mapping::Upnp {
+            gateway,
+            external_port,
+        },
+        external: SocketAddrV4::new(external_ip, external_port),
+        removed: AtomicBool::new(false),
+    })
+}
+
+/// Sends `request` to `gateway` until it replies, and returns the reply.
+async fn transact(gateway: SocketAddr, request: &[u8]) -> Result<Vec<u8>, PortMappingError> {
+    let unspecified: IpAddr = match gateway {
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind((unspecified, 0)).await?;
+    socket.connect(gateway).await?;
+
+    let mut buf = [0; 1100];
+    for timeout in RETRY_TIMEOUTS {
+        socket.send(request).await?;
+        if let Ok(len) = tokio::time::timeout(timeout, socket.recv(&mut buf)).await {
+            return Ok(buf[..len?].to_vec());
+        }
+    }
+
+    Err(PortMappingError::NoReply)
+}
+
+/// Returns a PCP MAP request for a TCP mapping to `local`.
+/// A `lifetime` of 0 removes the mapping.
+fn pcp_map_request(local: SocketAddrV4, nonce: [u8; 12], lifetime: u32) -> Vec<u8> {
+    let mut request = Vec::with_capacity(60);
+
+    // common header
+    request.extend_from_slice(&[2, PCP_MAP, 0, 0]);
+    request.extend_from_slice(&lifetime.to_be_bytes());
+    request.extend_from_slice(&local.ip().to_ipv6_mapped().octets());
+
+    // MAP opcode
+    request.extend_from_slice(&nonce);
+    request.extend_from_slice(&[TCP, 0, 0, 0]);
+    request.extend_from_slice(&local.port().to_be_bytes());
+    // suggest the same external port
+    request.extend_from_slice(&local.port().to_be_bytes());
+    request.extend_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+
+    request
+}
+
+/// Returns the external address in a PCP MAP reply.
+fn parse_pcp_reply(reply: &[u8], nonce: [u8; 12]) -> Result<SocketAddrV4, PortMappingError> {
+    if reply.len() < 60 || reply[0] != 2 || reply[1] != 0x80 | PCP_MAP {
+        return Err(PortMappingError::InvalidReply);
+    }
+    if reply[3] != 0 {
+        return Err(PortMappingError::Refused(reply[3].into()));
+    }
+    if reply[24..36] != nonce {
+        return Err(PortMappingError::InvalidReply);
+    }
+
+    let port = u16::from_be_bytes([reply[42], reply[43]]);
+    let ip: [u8; 16] = reply[44..60].try_into().unwrap();
+    let ip = Ipv6Addr::from(ip)
+        .to_ipv4_mapped()
+        .ok_or(PortMappingError::InvalidReply)?;
+
+    Ok(SocketAddrV4::new(ip, port))
+}
+
+/// Returns a NAT-PMP request for a TCP mapping to `local`.
+/// A `lifetime` of 0 removes the mapping.
+fn nat_pmp_map_request(local: SocketAddrV4, lifetime: u32) -> Vec<u8> {
+    let mut request = Vec::with_capacity(12);
+    request.extend_from_slice(&[0, NAT_PMP_MAP_TCP, 0, 0]);
+    request.extend_from_slice(&local.port().to_be_bytes());
+    // suggest the same external port, or none when removing
+    let external_port = if lifetime == 0 { 0 } else { local.port() };
+    request.extend_from_slice(&external_port.to_be_bytes());
+    request.extend_from_slice(&lifetime.to_be_bytes());
+    request
+}
+
+/// Checks the header of a NAT-PMP reply to a request with `opcode`.
+fn check_nat_pmp_reply(reply: &[u8], opcode: u8, len: usize) -> Result<(), PortMappingError> {
+    if reply.len() < len || reply[0] != 0 || reply[1] != 128 + opcode {
+        return Err(PortMappingError::InvalidReply);
+    }
+    let result = u16::from_be_bytes([reply[2], reply[3]]);
+    if result != 0 {
+        return Err(PortMappingError::Refused(result));
+    }
+    Ok(())
+}
+
+/// Returns the external IP address in a NAT-PMP reply.
+fn parse_nat_pmp_addr_reply(reply: &[u8]) -> Result<Ipv4Addr, PortMappingError> {
+    check_nat_pmp_reply(reply, NAT_PMP_EXTERNAL_ADDR, 12)?;
+    let ip: [u8; 4] = reply[8..12].try_into().unwrap();
+    Ok(ip.into())
+}
+
+/// Returns the external port in a NAT-PMP mapping reply.
+fn parse_nat_pmp_map_reply(reply: &[u8]) -> Result<u16, PortMappingError> {
+    check_nat_pmp_reply(reply, NAT_PMP_MAP_TCP, 16)?;
+    Ok(u16::from_be_bytes([reply[10], reply[11]]))
+}
+
+/// Returns the address of the default gateway.
+///
+/// Read from the routing table on Linux. Elsewhere, or if that fails,
+/// guesses the first address of the `/24` network `local` is in.
+fn default_gateway(local: Ipv4Addr) -> Ipv4Addr {
+    #[cfg(target_os = "linux")]
+    if let Some(gateway) = linux_default_gateway() {
+        return gateway;
+    }
+
+    let [a, b, c, _] = local.octets();
+    Ipv4Addr::new(a, b, c, 1)
+}
+
+/// Reads the default gateway from `/proc/net/route`.
+#[cfg(target_os = "linux")]
+fn linux_default_gateway() -> Option<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+
+    // columns are: Iface Destination Gateway ...
+    routes.lines().skip(1).find_map(|line| {
+        let mut columns = line.split_whitespace().skip(1);
+        let destination = columns.next()?;
+        let gateway = columns.next()?;
+        if destination != "00000000" {
+            return None;
+        }
+        // the kernel prints the address' bytes as a number in host byte order
+        let gateway = u32::from_str_radix(gateway, 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_ne_bytes()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    const EXTERNAL_IP: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 7);
+
+    /// Starts a gateway on loopback that grants every mapping,
+    /// speaking PCP if `pcp` is true or only NAT-PMP otherwise.
+    /// Returns its address, and a receiver of the lifetime of each mapping request.
+    async fn mock_gateway(pcp: bool) -> (SocketAddr, mpsc::UnboundedReceiver<u32>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let mut buf = [0; 1100];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let request = &buf[..len];
+                let reply = match (request[0], request[1]) {
+                    (2, PCP_MAP) if pcp => {
+                        let lifetime = u32::from_be_bytes(request[4..8].try_into().unwrap());
+                        tx.send(lifetime).unwrap();
+                        let mut reply = request.to_vec();
+                        reply[1] = 0x80 | PCP_MAP;
+                        // external port is internal port + 1
+                        let port = u16::from_be_bytes([request[40], request[41]]) + 1;
+                        reply[42..44].copy_from_slice(&port.to_be_bytes());
+                        reply[44..60].copy_from_slice(&EXTERNAL_IP.to_ipv6_mapped().octets());
+                        reply
+                    }
+                    // NAT-PMP reply to a PCP request: unsupported version
+                    (2, _) => vec![0, 0x80 | request[1], 0, 1, 0, 0, 0, 0],
+                    (0, NAT_PMP_EXTERNAL_ADDR) => {
+                        let mut reply = vec![0, 128, 0, 0, 0, 0, 0, 0];
+                        reply.extend_from_slice(&EXTERNAL_IP.octets());
+                        reply
+                    }
+                    (0, NAT_PMP_MAP_TCP) => {
+                        let lifetime = u32::from_be_bytes(request[8..12].try_into().unwrap());
+                        tx.send(lifetime).unwrap();
+                        let mut reply = vec![0, 130, 0, 0, 0, 0, 0, 0];
+                        reply.extend_from_slice(&request[4..6]);
+                        let port = u16::from_be_bytes([request[4], request[5]]) + 1;
+                        reply.extend_from_slice(&port.to_be_bytes());
+                        reply.extend_from_slice(&request[8..12]);
+                        reply
+                    }
+                    _ => continue,
+                };
+                socket.send_to(&reply, from).await.unwrap();
+            }
+        });
+
+        (addr, rx)
+    }
+
+    const LOCAL: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 20), 5000);
+
+    #[tokio::test]
+    async fn maps_with_pcp() {
+        let (gateway, mut requests) = mock_gateway(true).await;
+
+        let mapping = PortMapping::request_from(gateway, LOCAL).await.unwrap();
+        assert_eq!(mapping.protocol(), MappingProtocol::Pcp);
+        assert_eq!(
+            mapping.external_addr(),
+            SocketAddrV4::new(EXTERNAL_IP, 5001)
+        );
+        assert_eq!(requests.recv().await, Some(MAPPING_LIFETIME));
+
+        mapping.remove().await.unwrap();
+        assert_eq!(requests.recv().await, Some(0));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_nat_pmp() {
+        let (gateway, mut requests) = mock_gateway(false).await;
+
+        let mapping = PortMapping::request_from(gateway, LOCAL).await.unwrap();
+        assert_eq!(mapping.protocol(), MappingProtocol::NatPmp);
+        assert_eq!(
+            mapping.external_addr(),
+            SocketAddrV4::new(EXTERNAL_IP, 5001)
+        );
+        assert_eq!(requests.recv().await, Some(MAPPING_LIFETIME));
+
+        mapping.remove().await.unwrap();
+        assert_eq!(requests.recv().await, Some(0));
+    }
+
+    #[tokio::test]
+    async fn removes_mapping_when_dropped() {
+        let (gateway, mut requests) = mock_gateway(true).await;
+
+        let mapping = PortMapping::request_from(gateway, LOCAL).await.unwrap();
+        assert_eq!(requests.recv().await, Some(MAPPING_LIFETIME));
+
+        drop(mapping);
+        assert_eq!(requests.recv().await, Some(0));
+    }
+}
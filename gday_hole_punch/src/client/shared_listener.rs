@@ -0,0 +1,145 @@
+use std::collections::{hash_map::Entry, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+
+use super::Network;
+use crate::Transport;
+
+/// How long a connection to a [`SharedListener`] has to say which joiner it comes from.
+const JOINER_ID_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Routes = Mutex<HashMap<SocketAddr, Route>>;
+
+/// Listens on the local candidates of every [`PeerConnector`](super::PeerConnector)
+/// a client gets from one [`ContactSharer`](super::ContactSharer),
+/// and hands each accepted connection to the connector of the joiner it comes from.
+///
+/// The connectors of a room with several joiners all listen on the same addresses.
+/// Separate listeners sharing a port would each get some of the connections,
+/// including ones meant for another joiner, so they share one listener instead.
+/// Both ends of a connection start by sending the joiner's id as a big-endian `u32`:
+/// the listener answers with the id once it has routed the connection,
+/// just like a connector does when both peers connect at once.
+/// The connectors then verify the peer, whose identity includes the id.
+#[derive(Clone, Default)]
+pub(super) struct SharedListener {
+    routes: Arc<Routes>,
+}
+
+/// A listening address.
+struct Route {
+    /// Connectors waiting for connections, by the id of their joiner
+    joiners: HashMap<u32, mpsc::UnboundedSender<Box<dyn Transport>>>,
+    /// Accepts connections until no connector waits for them
+    task: AbortHandle,
+}
+
+/// Connections to one address from one joiner.
+/// Stops listening once the last connector waiting on the address drops its `Incoming`.
+pub(super) struct Incoming {
+    rx: mpsc::UnboundedReceiver<Box<dyn Transport>>,
+    local: SocketAddr,
+    joiner_id: u32,
+    routes: Arc<Routes>,
+}
+
+impl SharedListener {
+    /// Waits for connections to `local` from the joiner `joiner_id`,
+    /// listening on `local` over `network` unless a connector already does.
+    pub fn subscribe<N: Network>(
+        &self,
+        network: &N,
+        local: SocketAddr,
+        joiner_id: u32,
+    ) -> std::io::Result<Incoming> {
+        let mut routes = self.routes.lock().unwrap();
+        let route = match routes.entry(local) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let listener = network.listen(local)?;
+                let routes = Arc::downgrade(&self.routes);
+                let task = tokio::spawn(accept::<N>(listener, local, routes));
+                entry.insert(Route {
+                    joiners: HashMap::new(),
+                    task: task.abort_handle(),
+                })
+            }
+        };
+
+        if route.joiners.contains_key(&joiner_id) {
+            return Err(std::io::ErrorKind::AddrInUse.into());
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        route.joiners.insert(joiner_id, tx);
+
+        Ok(Incoming {
+            rx,
+            local,
+            joiner_id,
+            routes: self.routes.clone(),
+        })
+    }
+}
+
+impl Incoming {
+    /// The address the connections were accepted on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local
+    }
+
+    /// Waits for the next connection that claims to come from the joiner.
+    pub async fn next(&mut self) -> std::io::Result<Box<dyn Transport>> {
+        self.rx
+            .recv()
+            .await
+            .ok_or_else(|| std::io::ErrorKind::NotConnected.into())
+    }
+}
+
+impl Drop for Incoming {
+    fn drop(&mut self) {
+        let mut routes = self.routes.lock().unwrap();
+        if let Entry::Occupied(mut entry) = routes.entry(self.local) {
+            entry.get_mut().joiners.remove(&self.joiner_id);
+            if entry.get().joiners.is_empty() {
+                entry.remove().task.abort();
+            }
+        }
+    }
+}
+
+/// Accepts connections to `listener`, and routes each to the joiner it names.
+/// If the listener fails, every connector waiting on it gets an error.
+async fn accept<N: Network>(mut listener: N::Listener, local: SocketAddr, routes: Weak<Routes>) {
+    while let Ok(mut stream) = N::accept(&mut listener).await {
+        let routes = routes.clone();
+        tokio::spawn(async move {
+            let Ok(Ok(joiner_id)) =
+                tokio::time::timeout(JOINER_ID_TIMEOUT, stream.read_u32()).await
+            else {
+                return;
+            };
+            let Some(routes) = routes.upgrade() else {
+                return;
+            };
+            let tx = routes
+                .lock()
+                .unwrap()
+                .get(&local)
+                .and_then(|route| route.joiners.get(&joiner_id).cloned());
+            if let Some(tx) = tx {
+                if stream.write_u32(joiner_id).await.is_ok() {
+                    let _ = tx.send(Box::new(stream));
+                }
+            }
+        });
+    }
+
+    if let Some(routes) = routes.upgrade() {
+        routes.lock().unwrap().remove(&local);
+    }
+}
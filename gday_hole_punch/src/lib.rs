@@ -7,15 +7,24 @@
 //! - Test
 //! # Examples
 //! A simple test:
-//! ```
-//! use gday_hole_punch::{client, RoomId, ContactSharer};
+//! ```no_run
+//! # #[cfg(feature = "client")]
+//! # async fn example(
+//! #     server_v6: Option<Box<dyn gday_hole_punch::Transport>>,
+//! #     server_v4: Option<Box<dyn gday_hole_punch::Transport>>,
+//! # ) -> Result<(), gday_hole_punch::client::ClientError> {
+//! use gday_hole_punch::client::{random_peer_secret, ContactSharer, PeerConnection};
+//!
+//! // `server_v6` and `server_v4` are TLS connections to the server
+//! let (sharer, room_id) = ContactSharer::create_room(server_v6, server_v4, None, 1).await?;
+//! let peer_secret = random_peer_secret();
+//! println!("Tell your peer the room id {room_id} and the secret {peer_secret}");
 //!
-//! let (sharer, room_id) = ContactSharer::create_room(server_addr)?;
-//! println!("Here's the id of my room: {room_id}");
-//! let connector = sharer.get_peer_connector()?;
-//! let weak_shared_secret = b"A5P";
-//! connector.connect_to_peer(weak_shared_secret);
-//! let (peer_tcp_stream, strong_shared_secret) = connector.connect_to_peer()?;
+//! let connector = sharer.get_peer_connector().await?;
+//! let PeerConnection { stream, shared_secret, .. } =
+//!     connector.connect_to_peer(peer_secret).await?;
+//! # Ok(())
+//! # }
 //! ```
 //! It works!
 
@@ -24,10 +33,9 @@ use serde::{Deserialize, Serialize};
 use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
 use thiserror::Error;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
 };
-use tokio_rustls::TlsStream;
 
 #[doc(cfg(feature = "server"))]
 #[cfg(feature = "server")]
@@ -37,38 +45,154 @@ pub mod server;
 #[cfg(feature = "client")]
 pub mod client;
 
+#[cfg(all(test, feature = "client", feature = "server"))]
+mod simulator;
+
+mod websocket;
+
+pub use websocket::{WebSocket, WEBSOCKET_PATH};
+
+/// Size of the buffer each [`Messenger`] uses to encode and decode messages.
+const MESSENGER_BUF_SIZE: usize = 1024;
+
+/// Maximum number of private [`Candidate`]s a client can share,
+/// so that [`ServerMessage::SharePeerContacts`] fits in a [`Messenger`]'s buffer.
+pub const MAX_PRIVATE_CANDIDATES: usize = 8;
+
+/// Number of extra IPv4 connections to the server a client opens
+/// to measure how its NAT allocates ports, if the NAT rewrites them.
+/// See `ContactSharer::measure_port_allocation()`.
+pub const PORT_PROBES: usize = 3;
+
+/// First byte of a plain TCP connection that asks the server to relay it to a peer.
+/// Any other connection starts with a TLS handshake record, whose first byte is 22.
+const RELAY_MAGIC: u8 = b'R';
+
 /// Both peers send the server the same [`RoomId`] to get each other's contacts.
 ///
 /// 6 random ascii characters. Each character will be an uppercase letter A through Z or a digit 0 through 9.
 
-
 /// A message from [`client`] -> [`server`]
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
 enum ClientMessage {
-    /// Request the server to create a room
-    CreateRoom,
+    /// Ask which public address the server sees this connection coming from.
+    /// A client sends this on several short-lived connections to measure
+    /// how its NAT allocates ports.
+    GetPublicAddr,
+    /// Present an access key to a server that only lets
+    /// authorized users create rooms. Must be sent before [`ClientMessage::CreateRoom`].
+    Authenticate(String),
+    /// Request the server to create a room that up to `max_joiners` peers can join.
+    /// The server may accept fewer.
+    CreateRoom {
+        max_joiners: u32,
+    },
     JoinRoom(u32),
-    SendPrivateAddr(Option<SocketAddr>),
+    /// Sent as the first message of a client's additional server connection
+    /// (e.g. over the other IP family), so the server also learns that connection's public address.
+    /// `link_token` is the one the server replied to the room's creation or joining with.
+    LinkConnection {
+        room_id: u32,
+        link_token: u64,
+    },
+    /// The client's private addresses, highest priority first.
+    /// The server only keeps the first [`MAX_PRIVATE_CANDIDATES`].
+    SendPrivateAddrs(Vec<Candidate>),
+
+    /// A public address the client's router forwards to its first private IPv4 candidate.
+    SendMappedAddr(SocketAddrV4),
+
+    /// How the client's NAT allocated the public IPv4 ports of its recent connections.
+    SendPortAllocation(PortAllocation),
+
+    /// The client can only reach its peer through the server's relay,
+    /// for example because it connects to the server through a proxy.
+    RequireRelay,
 
     /// (room_id, user is creator of room?)
     DoneSending,
+
+    /// Ask the server to keep the room open for longer.
+    /// Only the room's creator can extend it.
+    ExtendRoom,
 }
 
 /// A message from [`server`] -> [`client`]
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
 enum ServerMessage {
-    /// Room successfully created
-    RoomCreated{ room_id: u32 },
-    RoomJoined,
+    /// Room successfully created, and will expire in `seconds_left`
+    /// unless extended with [`ClientMessage::ExtendRoom`].
+    /// Accepts up to `max_joiners` peers.
+    /// `link_token` lets other connections join with [`ClientMessage::LinkConnection`].
+    RoomCreated {
+        room_id: u32,
+        seconds_left: u64,
+        max_joiners: u32,
+        link_token: u64,
+    },
+    RoomJoined {
+        link_token: u64,
+    },
+    /// The connection was linked to the client's room
+    Linked,
     /// (full contact info of peer)
+    ///
+    /// The room's creator receives one of these for each joiner.
+    /// `joiner_id` tells apart the joiners of a room.
+    /// `relay_token` lets both peers connect through the server if they can't connect directly.
     SharePeerContacts {
         client_contact: FullContact,
         peer_contact: FullContact,
+        joiner_id: u32,
+        relay_token: u64,
     },
+    /// Reply to [`ClientMessage::GetPublicAddr`]
+    PublicAddr(SocketAddr),
+    /// The access key was accepted
+    Authenticated,
+    /// Something happened in the room this client created
+    RoomEvent(RoomEvent),
+    /// The server refused the request
+    Rejected(RejectReason),
     SyntaxError,
     ErrorNoSuchRoomID,
 }
 
+/// Why a server refused a client's request.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy, Error)]
+pub enum RejectReason {
+    #[error("This server requires an access key to create rooms.")]
+    AuthenticationRequired,
+
+    #[error("The server doesn't recognize this access key.")]
+    InvalidKey,
+
+    #[error("This access key has reached its quota of rooms. Try again later.")]
+    QuotaExceeded,
+
+    #[error("This room already has as many peers as it accepts.")]
+    RoomFull,
+
+    #[error("Too many connections from this address. Try again later.")]
+    TooManyConnections,
+}
+
+/// Something that happened in a room, which the server reports to the room's creator
+/// while the creator waits for a peer.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy)]
+pub enum RoomEvent {
+    /// A peer joined the room
+    PeerJoined,
+    /// A peer disconnected before its contact was shared
+    PeerDisconnected,
+    /// Someone tried to join the room, but was refused
+    JoinAttemptFailed,
+    /// The room will be removed soon
+    RoomExpiring { seconds_left: u64 },
+    /// The room was extended, and will now expire in `seconds_left`
+    RoomExtended { seconds_left: u64 },
+}
+
 /// The addresses of a single network endpoint.
 ///
 /// An endpoint may have IPv6, IPv4, none, or both.
@@ -80,40 +204,193 @@ pub struct Contact {
     pub v4: Option<SocketAddrV4>,
 }
 
+/// A private address an entity may be reachable at.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Candidate {
+    /// The socket address on one of the entity's network interfaces
+    pub addr: SocketAddr,
+    /// Candidates with a higher priority are tried first
+    pub priority: u32,
+}
+
+/// How an entity's NAT allocates public ports to new connections.
+///
+/// Symmetric NATs give each new connection a new public port, often the last one plus a constant.
+/// So the port a peer's connection will come from can be predicted, even though
+/// it differs from the one the server saw.
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy)]
+pub struct PortAllocation {
+    /// The public port of the last connection measured
+    pub last_port: u16,
+    /// Difference between the public ports of consecutive connections
+    pub delta: i32,
+}
+
+impl PortAllocation {
+    /// Returns the public ports the next `window` connections will probably get.
+    ///
+    /// The allocation may come from an untrusted peer,
+    /// so predictions stop at the first one that isn't a port.
+    pub fn predict(self, window: u16) -> impl Iterator<Item = u16> {
+        (1..=i32::from(window))
+            .map_while(move |i| {
+                let port = self.delta.checked_mul(i)?;
+                let port = port.checked_add(i32::from(self.last_port))?;
+                u16::try_from(port).ok()
+            })
+            .filter(|&port| port != 0)
+    }
+
+    /// True if `delta` could be the difference between two ports.
+    pub fn is_valid(self) -> bool {
+        (-i32::from(u16::MAX)..=i32::from(u16::MAX)).contains(&self.delta)
+    }
+}
+
 /// The public and private contacts of an entity.
 ///
 /// `public` is different from `private` when the entity is behind [NAT (network address translation)](https://en.wikipedia.org/wiki/Network_address_translation).
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Copy, Default)]
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone, Default)]
 pub struct FullContact {
-    /// The peer's private contact in it's local network.
-    pub private: Contact,
+    /// The peer's private addresses in its local networks, highest priority first.
+    ///
+    /// The first candidate of each IP family is the address the peer
+    /// used to reach the server, which its `public` contact maps to.
+    pub private: Vec<Candidate>,
     /// The entity's public contact visible to the public internet.
     pub public: Contact,
+    /// A port its router forwards to its first private IPv4 candidate, if it requested one.
+    pub mapped: Option<SocketAddrV4>,
+    /// How its NAT allocates public IPv4 ports, if it measured a regular pattern.
+    pub port_allocation: Option<PortAllocation>,
+    /// True if it can only be reached through the server's relay.
+    pub relay_only: bool,
+}
+
+impl FullContact {
+    /// Returns the highest priority private candidate with the given IP family.
+    pub fn first_private(&self, ipv6: bool) -> Option<SocketAddr> {
+        self.private
+            .iter()
+            .map(|candidate| candidate.addr)
+            .find(|addr| addr.is_ipv6() == ipv6)
+    }
+}
+
+/// A connection between a client and the server that [`Messenger`] exchanges messages over,
+/// such as TLS over TCP, or a [`WebSocket`] inside TLS.
+/// Also the stream of a connection between peers, however it was made.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + Sync + std::fmt::Debug {
+    /// The TCP connection this transport runs over,
+    /// or `None` if it doesn't run over the operating system's TCP, like in tests.
+    fn tcp_stream(&self) -> Option<&TcpStream>;
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.tcp_stream()
+            .ok_or(std::io::ErrorKind::Unsupported)?
+            .local_addr()
+    }
+
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.tcp_stream()
+            .ok_or(std::io::ErrorKind::Unsupported)?
+            .peer_addr()
+    }
+}
+
+impl Transport for TcpStream {
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        Some(self)
+    }
+}
+
+impl Transport for tokio_rustls::TlsStream<TcpStream> {
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        Some(self.get_ref().0)
+    }
+}
+
+impl Transport for tokio_rustls::client::TlsStream<TcpStream> {
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        Some(self.get_ref().0)
+    }
+}
+
+impl Transport for tokio_rustls::server::TlsStream<TcpStream> {
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        Some(self.get_ref().0)
+    }
 }
 
 #[derive(Debug)]
 struct Messenger {
-    stream: TlsStream<TcpStream>,
+    stream: Box<dyn Transport>,
+    /// Buffer for encoding outgoing messages
     buf: Vec<u8>,
+    /// Received bytes that haven't been returned as a message yet.
+    /// Kept between calls so that [`Messenger::next_msg()`] is cancel safe.
+    read_buf: Vec<u8>,
+    /// Number of bytes of `read_buf` that hold received data
+    filled: usize,
+    /// Length of the message at the start of `read_buf`
+    /// that was returned by the last call to [`Messenger::next_msg()`]
+    consumed: usize,
 }
 
 impl Messenger {
-    pub fn with_capacity(stream: impl Into<TlsStream<TcpStream>>, capacity: usize) -> Self {
+    pub fn with_capacity(stream: Box<dyn Transport>, capacity: usize) -> Self {
         Self {
-            stream: stream.into(),
+            stream,
             buf: vec![0; capacity],
+            read_buf: vec![0; capacity],
+            filled: 0,
+            consumed: 0,
         }
     }
 
+    /// Receives the next message.
+    ///
+    /// Cancel safe: if the future is dropped before completing,
+    /// no received data is lost.
     pub async fn next_msg<'a, U: Deserialize<'a>>(&'a mut self) -> Result<U, SerializationError> {
-        let length = self.stream.read_u32().await? as usize;
+        // discard the previously returned message
+        self.read_buf.copy_within(self.consumed..self.filled, 0);
+        self.filled -= self.consumed;
+        self.consumed = 0;
+
+        let length = loop {
+            if let Some(length) = self.buffered_msg_len()? {
+                break length;
+            }
+
+            let bytes_read = self.stream.read(&mut self.read_buf[self.filled..]).await?;
+            if bytes_read == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            self.filled += bytes_read;
+        };
 
-        if self.buf.len() < length {
+        self.consumed = 4 + length;
+        Ok(from_bytes(&self.read_buf[4..4 + length])?)
+    }
+
+    /// Returns the length of the message at the start of `read_buf`
+    /// if it has been fully received.
+    fn buffered_msg_len(&self) -> Result<Option<usize>, SerializationError> {
+        let Some(length) = self.read_buf[0..self.filled].get(0..4) else {
+            return Ok(None);
+        };
+        let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+
+        if self.read_buf.len() < 4 + length {
             return Err(SerializationError::TmpBufTooSmall);
         }
 
-        self.stream.read_exact(&mut self.buf[0..length]).await?;
-        Ok(from_bytes(&self.buf[0..length])?)
+        if self.filled >= 4 + length {
+            Ok(Some(length))
+        } else {
+            Ok(None)
+        }
     }
 
     pub async fn write_msg(&mut self, msg: impl Serialize) -> Result<(), SerializationError> {
@@ -125,16 +402,18 @@ impl Messenger {
         Ok(())
     }
 
-    pub fn inner_stream(&self) -> &TcpStream {
-        self.stream.get_ref().0
+    /// Returns the stream, and drops any received bytes
+    /// that haven't been returned as a message yet.
+    pub fn into_inner(self) -> Box<dyn Transport> {
+        self.stream
     }
 
     pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
-        self.stream.get_ref().0.local_addr()
+        self.stream.local_addr()
     }
 
     pub fn peer_addr(&self) -> std::io::Result<SocketAddr> {
-        self.stream.get_ref().0.peer_addr()
+        self.stream.peer_addr()
     }
 }
 
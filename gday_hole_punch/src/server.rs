@@ -1,17 +1,23 @@
+mod access_list;
 mod connection_handler;
 mod global_state;
+mod relay;
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     net::IpAddr,
     sync::{Arc, Mutex},
     time::Duration,
 };
 
-use crate::SerializationError;
+use crate::{
+    Messenger, RejectReason, SerializationError, ServerMessage, Transport, WebSocket,
+    MESSENGER_BUF_SIZE, RELAY_MAGIC,
+};
 
-use self::global_state::State;
-use connection_handler::ConnectionHandler;
+pub use self::access_list::{AccessList, AccessListError};
+pub(crate) use self::global_state::State;
+pub(crate) use connection_handler::ConnectionHandler;
 use thiserror::Error;
 use tokio::net::{TcpListener, TcpStream};
 use tokio_rustls::TlsAcceptor;
@@ -32,42 +38,108 @@ pub enum ServerError {
 
     #[error("No such room id exists")]
     ReceivedIncorrectMessage,
+
+    #[error("No such relay token exists")]
+    NoSuchRelay,
+
+    #[error("Rejected client: {0}")]
+    Rejected(#[from] RejectReason),
+}
+
+/// Number of connections a client opens to the server at once:
+/// one over IPv6, one over IPv4, the port probes if its NAT rewrites ports,
+/// and one to the relay.
+const CONNECTIONS_PER_CLIENT: u32 = 3 + crate::PORT_PROBES as u32;
+
+/// How many connections an IP address can open within 5 seconds by default
+/// before the rest are delayed. Enough for a sender and a recipient
+/// behind the same address to open all their connections,
+/// while still slowing down anyone who opens connections much faster.
+pub const DEFAULT_CONNECTION_BURST: u32 = 2 * CONNECTIONS_PER_CLIENT;
+
+/// Connections recently opened by an IP address.
+#[derive(Default)]
+struct RecentConnections {
+    /// Number of connections served in the current 5 seconds
+    served: u32,
+    /// Connections over the limit, served in order in the following 5 seconds,
+    /// and whether they arrived on the WebSocket listener
+    waiting: VecDeque<(TcpStream, bool)>,
 }
 
 #[derive(Clone)]
 struct GlobalData {
     state: State,
-    blocked: Arc<Mutex<HashMap<IpAddr, Option<TcpStream>>>>,
+    blocked: Arc<Mutex<HashMap<IpAddr, RecentConnections>>>,
     tls_acceptor: TlsAcceptor,
+    access_list: Option<AccessList>,
+    max_joiners: u32,
+    connection_burst: u32,
 }
 
-pub async fn run(listener: TcpListener, tls_acceptor: TlsAcceptor) -> Result<(), ServerError> {
+/// Serves clients that connect to `listener`.
+///
+/// If `websocket_listener` is `Some`, also serves clients that connect to it
+/// with the protocol tunneled through a [`WebSocket`] inside TLS.
+/// Listening on a standard HTTPS port lets clients behind strict firewalls connect.
+///
+/// If `access_list` is `Some`, only clients that present one of its keys
+/// can create rooms. Anyone with a valid room code can still join a room.
+///
+/// Each room accepts as many joiners as its creator asks for, but at most `max_joiners`.
+///
+/// Each IP address can open `connection_burst` connections every 5 seconds.
+/// Later ones wait for the next 5 seconds, and are refused once as many are waiting.
+/// See [`DEFAULT_CONNECTION_BURST`].
+pub async fn run(
+    listener: TcpListener,
+    websocket_listener: Option<TcpListener>,
+    tls_acceptor: TlsAcceptor,
+    access_list: Option<AccessList>,
+    max_joiners: u32,
+    connection_burst: u32,
+) -> Result<(), ServerError> {
     let global_data = GlobalData {
         state: State::default(),
         blocked: Arc::new(Mutex::new(HashMap::new())),
         tls_acceptor,
+        access_list,
+        max_joiners,
+        connection_burst,
     };
 
+    if let Some(websocket_listener) = websocket_listener {
+        tokio::spawn(accept_clients(
+            websocket_listener,
+            true,
+            global_data.clone(),
+        ));
+    }
+    accept_clients(listener, false, global_data).await;
+    Ok(())
+}
+
+/// Admits each client that connects to `listener`.
+/// `websocket` tells whether its clients tunnel through a [`WebSocket`].
+async fn accept_clients(listener: TcpListener, websocket: bool, global_data: GlobalData) {
     loop {
-        let (stream, addr) = match listener.accept().await {
-            Ok(ok) => ok,
+        let stream = match listener.accept().await {
+            Ok((stream, _addr)) => stream,
             Err(err) => {
                 println!("Error accepting connection: {err}");
                 continue;
             }
         };
 
-        let mut guard = global_data.blocked.lock().unwrap();
-        if let Some(stream_option) = guard.get_mut(&addr.ip()) {
-            *stream_option = Some(stream);
-        } else {
-            drop(guard);
-            serve_client(stream, global_data.clone());
-        }
+        admit_client(stream, websocket, global_data.clone());
     }
 }
 
-fn serve_client(tcp_stream: TcpStream, global_data: GlobalData) {
+/// Serves `tcp_stream` unless its IP address has opened
+/// [`GlobalData::connection_burst`] connections in the last 5 seconds.
+/// Otherwise, queues it to be served after that,
+/// or refuses it if as many connections are already queued.
+fn admit_client(tcp_stream: TcpStream, websocket: bool, global_data: GlobalData) {
     let addr = match tcp_stream.peer_addr() {
         Ok(ok) => ok,
         Err(err) => {
@@ -76,31 +148,115 @@ fn serve_client(tcp_stream: TcpStream, global_data: GlobalData) {
         }
     }
     .ip();
-    global_data.blocked.lock().unwrap().insert(addr, None);
 
-    let global_data2 = global_data.clone();
+    let mut guard = global_data.blocked.lock().unwrap();
+    let recent = guard.entry(addr).or_default();
+
+    if recent.served >= global_data.connection_burst {
+        if recent.waiting.len() < global_data.connection_burst as usize {
+            recent.waiting.push_back((tcp_stream, websocket));
+        } else {
+            drop(guard);
+            refuse_client(tcp_stream, websocket, global_data);
+        }
+        return;
+    }
+
+    if recent.served == 0 {
+        let global_data = global_data.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            let mut guard = global_data.blocked.lock().unwrap();
+            let maybe_recent = guard.remove(&addr);
+            drop(guard);
+
+            // admitted into the next 5 seconds, or queued again
+            for (tcp_stream, websocket) in maybe_recent.into_iter().flat_map(|r| r.waiting) {
+                admit_client(tcp_stream, websocket, global_data.clone());
+            }
+        });
+    }
+
+    recent.served += 1;
+    drop(guard);
+    serve_client(tcp_stream, websocket, global_data);
+}
+
+/// Tells a client that opened too many connections to try again later,
+/// rather than leaving it waiting.
+fn refuse_client(tcp_stream: TcpStream, websocket: bool, global_data: GlobalData) {
     tokio::spawn(async move {
-        tokio::time::sleep(Duration::from_secs(5)).await;
-        println!("done waiting!");
-        let mut guard = global_data.blocked.lock().unwrap();
-        let maybe_stream = guard.remove(&addr);
-        drop(guard);
-
-        if let Some(Some(tcp_stream)) = maybe_stream {
-            println!("serving");
-            serve_client(tcp_stream, global_data2);
+        // relay connections don't speak the protocol, and just see the connection close
+        let mut first_byte = [0];
+        if let Ok(1) = tcp_stream.peek(&mut first_byte).await {
+            if first_byte[0] == RELAY_MAGIC {
+                return;
+            }
+        }
+
+        let Some(stream) = accept_transport(tcp_stream, websocket, &global_data).await else {
+            return;
+        };
+        let mut messenger = Messenger::with_capacity(stream, MESSENGER_BUF_SIZE);
+        let reason = RejectReason::TooManyConnections;
+        if let Err(err) = messenger.write_msg(ServerMessage::Rejected(reason)).await {
+            println!("{err}");
         }
     });
+}
+
+fn serve_client(tcp_stream: TcpStream, websocket: bool, global_data: GlobalData) {
     tokio::spawn(async move {
-        let tls_stream = match global_data.tls_acceptor.accept(tcp_stream).await {
-            Ok(ok) => ok,
-            Err(err) => {
-                println!("TLS connector error: {err}");
+        let mut first_byte = [0];
+        if let Ok(1) = tcp_stream.peek(&mut first_byte).await {
+            if first_byte[0] == RELAY_MAGIC {
+                if let Err(err) = relay::relay(tcp_stream, global_data.state).await {
+                    println!("{err}");
+                }
                 return;
             }
+        }
+
+        let Some(stream) = accept_transport(tcp_stream, websocket, &global_data).await else {
+            return;
         };
-        if let Err(err) = ConnectionHandler::start(global_data.state, tls_stream).await {
+
+        if let Err(err) = ConnectionHandler::start(
+            global_data.state,
+            global_data.access_list,
+            global_data.max_joiners,
+            stream,
+        )
+        .await
+        {
             println!("{err}")
         }
     });
 }
+
+/// Accepts the TLS connection of a client, and its [`WebSocket`] if `websocket`.
+async fn accept_transport(
+    tcp_stream: TcpStream,
+    websocket: bool,
+    global_data: &GlobalData,
+) -> Option<Box<dyn Transport>> {
+    let tls_stream = match global_data.tls_acceptor.accept(tcp_stream).await {
+        Ok(ok) => ok,
+        Err(err) => {
+            println!("TLS connector error: {err}");
+            return None;
+        }
+    };
+
+    if websocket {
+        match WebSocket::accept(tls_stream).await {
+            Ok(ok) => Some(Box::new(ok)),
+            Err(err) => {
+                println!("WebSocket error: {err}");
+                None
+            }
+        }
+    } else {
+        Some(Box::new(tls_stream))
+    }
+}
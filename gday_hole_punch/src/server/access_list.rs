@@ -0,0 +1,213 @@
+use crate::RejectReason;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// How long the window for counting each key's created rooms lasts.
+const QUOTA_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Error, Debug)]
+pub enum AccessListError {
+    #[error("IO Error: {0}")]
+    IO(#[from] std::io::Error),
+
+    #[error("Invalid quota on line {0} of the access key file")]
+    InvalidQuota(usize),
+}
+
+/// Usage of a single access key.
+struct KeyUsage {
+    /// Maximum number of rooms this key can create per [`QUOTA_WINDOW`].
+    /// `None` if unlimited.
+    quota: Option<u32>,
+    /// When the current quota window started.
+    window_start: Instant,
+    /// Number of rooms created in the current quota window.
+    rooms_created: u32,
+}
+
+/// The access keys allowed to create rooms on a private server.
+#[derive(Clone)]
+pub struct AccessList {
+    keys: Arc<Mutex<HashMap<String, KeyUsage>>>,
+}
+
+impl AccessList {
+    /// Reads the allowed keys from a file.
+    ///
+    /// Each line holds one key, optionally followed by a space
+    /// and the maximum number of rooms the key can create per hour.
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, AccessListError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parses the contents of a key file, as described in [`AccessList::from_file()`].
+    pub(crate) fn parse(contents: &str) -> Result<Self, AccessListError> {
+        let mut keys = HashMap::new();
+
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            let key = words.next().unwrap_or_default().to_string();
+            let quota = match words.next() {
+                Some(quota) => Some(
+                    quota
+                        .parse()
+                        .map_err(|_| AccessListError::InvalidQuota(i + 1))?,
+                ),
+                None => None,
+            };
+
+            keys.insert(
+                key,
+                KeyUsage {
+                    quota,
+                    window_start: Instant::now(),
+                    rooms_created: 0,
+                },
+            );
+        }
+
+        Ok(Self {
+            keys: Arc::new(Mutex::new(keys)),
+        })
+    }
+
+    /// Number of keys in this list.
+    pub fn len(&self) -> usize {
+        self.keys.lock().unwrap().len()
+    }
+
+    /// True if this list holds no keys.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Checks that `key` is in this list.
+    pub(super) fn check_key(&self, key: &str) -> Result<(), RejectReason> {
+        if self.keys.lock().unwrap().contains_key(key) {
+            Ok(())
+        } else {
+            Err(RejectReason::InvalidKey)
+        }
+    }
+
+    /// Counts a new room against `key`'s quota,
+    /// or returns an error if the quota is used up.
+    pub(super) fn use_room(&self, key: &str) -> Result<(), RejectReason> {
+        let mut keys = self.keys.lock().unwrap();
+        let usage = keys.get_mut(key).ok_or(RejectReason::InvalidKey)?;
+
+        if usage.window_start.elapsed() >= QUOTA_WINDOW {
+            usage.window_start = Instant::now();
+            usage.rooms_created = 0;
+        }
+
+        if let Some(quota) = usage.quota {
+            if usage.rooms_created >= quota {
+                return Err(RejectReason::QuotaExceeded);
+            }
+        }
+
+        usage.rooms_created += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_FILE: &str = "
+        # keys of the team
+        alice 2
+        bob
+
+        carol 0
+    ";
+
+    #[test]
+    fn parses_key_file() {
+        let list = AccessList::parse(KEY_FILE).unwrap();
+        assert_eq!(list.len(), 3);
+
+        let keys = list.keys.lock().unwrap();
+        assert_eq!(keys["alice"].quota, Some(2));
+        assert_eq!(keys["bob"].quota, None);
+        assert_eq!(keys["carol"].quota, Some(0));
+        assert!(!keys.contains_key("#"));
+    }
+
+    #[test]
+    fn rejects_invalid_quota() {
+        assert!(matches!(
+            AccessList::parse("alice 2\nbob many"),
+            Err(AccessListError::InvalidQuota(2))
+        ));
+    }
+
+    #[test]
+    fn checks_keys() {
+        let list = AccessList::parse(KEY_FILE).unwrap();
+        assert_eq!(list.check_key("bob"), Ok(()));
+        assert_eq!(list.check_key("dave"), Err(RejectReason::InvalidKey));
+        assert_eq!(list.use_room("dave"), Err(RejectReason::InvalidKey));
+    }
+
+    #[test]
+    fn quota_runs_out_until_window_ends() {
+        let list = AccessList::parse(KEY_FILE).unwrap();
+        assert_eq!(list.use_room("alice"), Ok(()));
+        assert_eq!(list.use_room("alice"), Ok(()));
+        assert_eq!(list.use_room("alice"), Err(RejectReason::QuotaExceeded));
+        assert_eq!(list.use_room("carol"), Err(RejectReason::QuotaExceeded));
+        for _ in 0..10 {
+            assert_eq!(list.use_room("bob"), Ok(()));
+        }
+
+        // start the window an hour ago
+        let window_start = Instant::now().checked_sub(QUOTA_WINDOW).unwrap();
+        list.keys
+            .lock()
+            .unwrap()
+            .get_mut("alice")
+            .unwrap()
+            .window_start = window_start;
+        assert_eq!(list.use_room("alice"), Ok(()));
+        assert_eq!(list.use_room("alice"), Ok(()));
+        assert_eq!(list.use_room("alice"), Err(RejectReason::QuotaExceeded));
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test(start_paused = true)]
+    async fn create_room_requires_authentication() {
+        use crate::client::{ClientError, ContactSharer};
+        use crate::simulator::Simulator;
+
+        let sim = Simulator::new(0.0, 0);
+        sim.start_private_server(AccessList::parse(KEY_FILE).unwrap());
+        let host = sim.add_ipv6_host();
+
+        for (key, expected) in [
+            (None, Err(RejectReason::AuthenticationRequired)),
+            (Some("dave"), Err(RejectReason::InvalidKey)),
+            (Some("carol"), Err(RejectReason::QuotaExceeded)),
+            (Some("bob"), Ok(())),
+        ] {
+            let server = host.connect_to_server(true).await;
+            let result = ContactSharer::create_room(server, None, key, 1).await;
+            let result = result.map(|_| ()).map_err(|err| match err {
+                ClientError::Rejected(reason) => reason,
+                err => panic!("unexpected error: {err}"),
+            });
+            assert_eq!(result, expected);
+        }
+    }
+}
@@ -1,92 +1,254 @@
-use std::net::SocketAddr;
-
-use crate::server::global_state::State;
-use crate::{ClientMessage, ServerMessage};
-use crate::{Messenger, SerializationError};
-use tokio::net::TcpStream;
-use tokio_rustls::server::TlsStream;
+use crate::server::global_state::{JoinError, Member, RoomKey, SharedContacts, State};
+use crate::server::AccessList;
+use crate::{ClientMessage, RejectReason, RoomEvent, ServerMessage};
+use crate::{Messenger, SerializationError, Transport, MESSENGER_BUF_SIZE};
+use tokio::sync::mpsc;
 
 use super::ServerError;
 
 pub struct ConnectionHandler {
     state: State,
     messenger: Messenger,
-    room_id: u32,
-    is_creator: bool,
+    room: RoomKey,
+    member: Member,
+    /// Events to forward to the client. `Some` only for the room's creator.
+    events: Option<mpsc::UnboundedReceiver<RoomEvent>>,
+    /// Receives the contacts of each peer
+    /// once the client is done sending its contact info.
+    contacts: Option<mpsc::UnboundedReceiver<SharedContacts>>,
+    /// Number of peers whose contacts were shared with this client
+    shared: u32,
+    /// True once the contacts have been shared, and this client no longer needs the room.
+    /// The creator needs the room until it's removed.
+    done: bool,
+}
+
+/// Something the [`ConnectionHandler`] has to react to.
+enum Incoming {
+    Message(Result<ClientMessage, SerializationError>),
+    Event(RoomEvent),
+    /// `None` once the room no longer shares contacts
+    Contacts(Option<SharedContacts>),
 }
 
 impl ConnectionHandler {
-    pub async fn start(mut state: State, stream: TlsStream<TcpStream>) -> Result<(), ServerError> {
-        let mut messenger = Messenger::with_capacity(stream, 68);
-
-        let (room_id, is_creator) = match messenger.next_msg().await {
-            Ok(ClientMessage::CreateRoom) => {
-                let room_id = state.create_room();
-                messenger
-                    .write_msg(ServerMessage::RoomCreated { room_id })
-                    .await?;
-                (room_id, true)
-            }
-            Ok(ClientMessage::JoinRoom(room_id)) => {
-                if state.room_exists(room_id) {
-                    messenger.write_msg(ServerMessage::RoomJoined).await?;
-                } else {
+    pub async fn start(
+        mut state: State,
+        access_list: Option<AccessList>,
+        max_joiners: u32,
+        stream: Box<dyn Transport>,
+    ) -> Result<(), ServerError> {
+        let mut messenger = Messenger::with_capacity(stream, MESSENGER_BUF_SIZE);
+
+        // The access key this client authenticated with, if any.
+        let mut access_key = None;
+
+        let (room, member, events) = loop {
+            match messenger.next_msg().await {
+                Ok(ClientMessage::GetPublicAddr) => {
+                    let public = messenger.peer_addr()?;
                     messenger
-                        .write_msg(ServerMessage::ErrorNoSuchRoomID)
+                        .write_msg(ServerMessage::PublicAddr(public))
                         .await?;
-                    return Err(ServerError::NoSuchRoomId);
                 }
-                (room_id, false)
-            }
-            Ok(_msg) => {
-                messenger.write_msg(ServerMessage::SyntaxError).await?;
-                return Err(ServerError::ReceivedIncorrectMessage);
-            }
-            Err(err) => {
-                messenger.write_msg(ServerMessage::SyntaxError).await?;
-                return Err(err.into());
+                Ok(ClientMessage::Authenticate(key)) => {
+                    if let Some(access_list) = &access_list {
+                        if let Err(reason) = access_list.check_key(&key) {
+                            messenger.write_msg(ServerMessage::Rejected(reason)).await?;
+                            return Err(reason.into());
+                        }
+                    }
+                    messenger.write_msg(ServerMessage::Authenticated).await?;
+                    access_key = Some(key);
+                }
+                Ok(ClientMessage::CreateRoom {
+                    max_joiners: requested,
+                }) => {
+                    if let Some(access_list) = &access_list {
+                        let result = access_key
+                            .as_deref()
+                            .ok_or(RejectReason::AuthenticationRequired)
+                            .and_then(|key| access_list.use_room(key));
+
+                        if let Err(reason) = result {
+                            messenger.write_msg(ServerMessage::Rejected(reason)).await?;
+                            return Err(reason.into());
+                        }
+                    }
+
+                    let max_joiners = requested.clamp(1, max_joiners);
+                    let link_token = rand::random();
+                    let (room, time_left, events) = state.create_room(max_joiners, link_token);
+                    messenger
+                        .write_msg(ServerMessage::RoomCreated {
+                            room_id: room.id,
+                            seconds_left: time_left.as_secs(),
+                            max_joiners,
+                            link_token,
+                        })
+                        .await?;
+                    break (room, Member::Creator, Some(events));
+                }
+                Ok(ClientMessage::JoinRoom(room_id)) => {
+                    let link_token = rand::random();
+                    let (room, joiner_id) = match state.join_room(room_id, link_token) {
+                        Ok(joined) => joined,
+                        Err(JoinError::NoSuchRoomId) => {
+                            messenger
+                                .write_msg(ServerMessage::ErrorNoSuchRoomID)
+                                .await?;
+                            return Err(ServerError::NoSuchRoomId);
+                        }
+                        Err(JoinError::Rejected(reason)) => {
+                            messenger.write_msg(ServerMessage::Rejected(reason)).await?;
+                            return Err(reason.into());
+                        }
+                    };
+                    messenger
+                        .write_msg(ServerMessage::RoomJoined { link_token })
+                        .await?;
+                    break (room, Member::Joiner(joiner_id), None);
+                }
+                Ok(ClientMessage::LinkConnection {
+                    room_id,
+                    link_token,
+                }) => {
+                    let public = messenger.peer_addr()?;
+                    if state.link_connection(room_id, link_token, public).is_err() {
+                        messenger
+                            .write_msg(ServerMessage::ErrorNoSuchRoomID)
+                            .await?;
+                        return Err(ServerError::NoSuchRoomId);
+                    }
+                    messenger.write_msg(ServerMessage::Linked).await?;
+
+                    // A linked connection only shows the server its public address,
+                    // and stays open to keep its NAT mapping alive.
+                    while messenger.next_msg::<ClientMessage>().await.is_ok() {}
+                    return Ok(());
+                }
+                Ok(_msg) => {
+                    messenger.write_msg(ServerMessage::SyntaxError).await?;
+                    return Err(ServerError::ReceivedIncorrectMessage);
+                }
+                Err(err) => {
+                    messenger.write_msg(ServerMessage::SyntaxError).await?;
+                    return Err(err.into());
+                }
             }
         };
 
         let mut this = Self {
             state,
             messenger,
-            room_id,
-            is_creator,
+            room,
+            member,
+            events,
+            contacts: None,
+            shared: 0,
+            done: false,
+        };
+
+        let err = loop {
+            if let Err(err) = this.handle_incoming().await {
+                break err;
+            }
         };
 
-        loop {
-            this.handle_message().await?;
+        if !this.done {
+            this.state.leave_room(this.room, this.member);
         }
+
+        Err(err)
     }
 
-    async fn handle_message(&mut self) -> Result<(), ServerError> {
-        let msg: Result<_, _> = self.messenger.next_msg().await;
+    async fn handle_incoming(&mut self) -> Result<(), ServerError> {
+        let incoming = tokio::select! {
+            msg = self.messenger.next_msg() => Incoming::Message(msg),
+            Some(event) = next_event(&mut self.events) => Incoming::Event(event),
+            Some(contacts) = next_contacts(&mut self.contacts) => Incoming::Contacts(contacts),
+        };
+
+        match incoming {
+            Incoming::Message(msg) => self.handle_message(msg).await,
+            Incoming::Event(event) => Ok(self.send(ServerMessage::RoomEvent(event)).await?),
+            Incoming::Contacts(Some(contacts)) => {
+                self.shared += 1;
+                if let Member::Joiner(_) = self.member {
+                    self.done = true;
+                }
+                self.send(ServerMessage::SharePeerContacts {
+                    client_contact: contacts.client,
+                    peer_contact: contacts.peer,
+                    joiner_id: contacts.joiner_id,
+                    relay_token: contacts.relay_token,
+                })
+                .await?;
+                Ok(())
+            }
+            // the room was removed after sharing every contact it could
+            Incoming::Contacts(None) if self.shared > 0 => {
+                self.contacts = None;
+                Ok(())
+            }
+            Incoming::Contacts(None) => Err(ServerError::RoomTimedOut),
+        }
+    }
 
+    async fn handle_message(
+        &mut self,
+        msg: Result<ClientMessage, SerializationError>,
+    ) -> Result<(), ServerError> {
         match msg {
-            Ok(ClientMessage::SendPrivateAddr(private_addr)) => {
-                self.update_client(self.messenger.peer_addr()?, true)
-                    .await?;
+            Ok(ClientMessage::SendPrivateAddrs(candidates)) => {
+                let public = self.messenger.peer_addr()?;
+                let room = self.room;
+                let member = self.member;
 
-                if let Some(addr) = private_addr {
-                    self.update_client(addr, false).await?;
+                if self.state.set_public(room, member, public).is_err()
+                    || self.state.set_private(room, member, candidates).is_err()
+                {
+                    self.send_no_such_room().await?;
+                }
+            }
+            Ok(ClientMessage::SendMappedAddr(addr)) => {
+                if self.state.set_mapped(self.room, self.member, addr).is_err() {
+                    self.send_no_such_room().await?;
+                }
+            }
+            Ok(ClientMessage::SendPortAllocation(allocation)) => {
+                let room = self.room;
+                let member = self.member;
+                if self
+                    .state
+                    .set_port_allocation(room, member, allocation)
+                    .is_err()
+                {
+                    self.send_no_such_room().await?;
+                }
+            }
+            Ok(ClientMessage::RequireRelay) => {
+                if self.state.set_relay_only(self.room, self.member).is_err() {
+                    self.send_no_such_room().await?;
                 }
             }
             Ok(ClientMessage::DoneSending) => {
-                if let Ok(rx) = self.state.set_client_done(self.room_id, self.is_creator) {
-                    let Ok((client_contact, peer_contact)) = rx.await else {
-                        return Err(ServerError::RoomTimedOut);
-                    };
-                    self.send(ServerMessage::SharePeerContacts {
-                        client_contact,
-                        peer_contact,
-                    })
-                    .await?;
-                    return Ok(());
+                if let Ok(rx) = self.state.set_client_done(self.room, self.member) {
+                    self.contacts = Some(rx);
                 } else {
                     self.send_no_such_room().await?;
                 };
             }
+            Ok(ClientMessage::ExtendRoom) if self.member == Member::Creator => {
+                if let Ok(time_left) = self.state.extend_room(self.room) {
+                    self.send(ServerMessage::RoomEvent(RoomEvent::RoomExtended {
+                        seconds_left: time_left.as_secs(),
+                    }))
+                    .await?;
+                } else {
+                    self.send_no_such_room().await?;
+                }
+            }
             Ok(_msg) => {
                 self.send(ServerMessage::SyntaxError).await?;
                 return Err(ServerError::ReceivedIncorrectMessage);
@@ -104,20 +266,26 @@ impl ConnectionHandler {
         self.messenger.write_msg(msg).await
     }
 
-    async fn update_client(&mut self, addr: SocketAddr, public: bool) -> Result<(), ServerError> {
-        if self
-            .state
-            .update_client(self.room_id, self.is_creator, addr, public)
-            .is_err()
-        {
-            self.send_no_such_room().await
-        } else {
-            Ok(())
-        }
-    }
-
     async fn send_no_such_room(&mut self) -> Result<(), ServerError> {
         self.send(ServerMessage::ErrorNoSuchRoomID).await?;
         Err(ServerError::NoSuchRoomId)
     }
 }
+
+/// Waits for the next event, or forever if there are no events to wait for.
+async fn next_event(events: &mut Option<mpsc::UnboundedReceiver<RoomEvent>>) -> Option<RoomEvent> {
+    match events {
+        Some(events) => events.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits for the next shared contacts, or forever if the client isn't done sending its own.
+async fn next_contacts(
+    contacts: &mut Option<mpsc::UnboundedReceiver<SharedContacts>>,
+) -> Option<Option<SharedContacts>> {
+    match contacts {
+        Some(rx) => Some(rx.recv().await),
+        None => std::future::pending().await,
+    }
+}
@@ -1,52 +1,237 @@
-use crate::FullContact;
+use crate::{
+    Candidate, FullContact, PortAllocation, RejectReason, RoomEvent, MAX_PRIVATE_CANDIDATES,
+};
 use rand::Rng;
 use std::collections::{HashMap, HashSet};
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use thiserror::Error;
-use tokio::sync::oneshot;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// How long a room lasts after being created or extended.
+const ROOM_LIFETIME: Duration = Duration::from_secs(60 * 10);
+
+/// How long a room can last in total, no matter how often it's extended.
+const MAX_ROOM_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// How long before a room expires its creator is warned.
+const EXPIRY_WARNING: Duration = Duration::from_secs(60);
+
+/// How long peers have to connect to a relay after their contacts are shared.
+const RELAY_TIMEOUT: Duration = Duration::from_secs(60 * 10);
 
 #[derive(Error, Debug)]
 #[error("No room with this id exists.")]
 pub struct NoSuchRoomId;
 
+#[derive(Error, Debug)]
+#[error("No relay with this token exists.")]
+pub struct NoSuchRelay;
+
+/// Identifies one specific room.
+///
+/// Room ids are reused after a room is removed,
+/// so `instance` tells apart rooms that had the same id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RoomKey {
+    pub id: u32,
+    instance: u64,
+}
+
+#[derive(Error, Debug)]
+pub enum JoinError {
+    #[error("No room with this id exists.")]
+    NoSuchRoomId,
+
+    #[error("{0}")]
+    Rejected(RejectReason),
+}
+
+/// The role of a client in a [`Room`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Member {
+    /// The client that created the room
+    Creator,
+    /// A client that joined the room, with the id the room gave it
+    Joiner(u32),
+}
+
+/// Contacts shared with a client once it and one of its peers are both done
+/// sending their contact info.
+#[derive(Clone, Debug)]
+pub struct SharedContacts {
+    /// The client's own contact, as seen by the server
+    pub client: FullContact,
+    /// The peer's contact
+    pub peer: FullContact,
+    /// Id of the joiner involved: either the client or its peer
+    pub joiner_id: u32,
+    /// Lets the client and its peer connect through the server's relay
+    pub relay_token: u64,
+}
+
 /// Information about a client in a [`Room`].
 #[derive(Default)]
 struct Client {
     /// The known private and public IP addresses of this client
     contact: FullContact,
+    /// Lets the client's other connections to the server link to this client
+    link_token: u64,
     /// - `None` if the client is still sending their contact info
     /// - `Some` if the client is done sending their contact info.
-    /// Once the peer is also done, this channel sends
-    /// (this client's contact, peer's contact) to the connection thread.
-    sender: Option<oneshot::Sender<(FullContact, FullContact)>>,
+    ///   Whenever a peer is also done, this channel sends
+    ///   the contacts of both to the connection thread.
+    sender: Option<mpsc::UnboundedSender<SharedContacts>>,
 }
 
-/// A room holds 2 [Client]s that want to exchange their contact info
-#[derive(Default)]
+/// A room holds a creator and up to `max_joiners` [Client]s
+/// that want to exchange their contact info with the creator.
 struct Room {
+    /// Unique among all rooms ever created by this server
+    instance: u64,
     /// The client that created this room
     creator: Client,
-    /// The client that joined this room
-    joiner: Client,
+    /// The joiners whose contacts haven't been shared yet, by joiner id
+    joiners: HashMap<u32, Client>,
+    /// Id to give the next joiner
+    next_joiner_id: u32,
+    /// Number of joiners whose contacts were shared with the creator
+    shared: u32,
+    /// Maximum number of joiners this room accepts
+    max_joiners: u32,
+    /// Sends [`RoomEvent`]s to the creator's connection
+    events: mpsc::UnboundedSender<RoomEvent>,
+    /// When this room was created
+    created_at: Instant,
+    /// When this room will be removed
+    expires_at: Instant,
+    /// True if the creator has been warned that the room will expire at `expires_at`
+    warned: bool,
 }
 
 impl Room {
-    fn get_client(&mut self, is_creator: bool) -> &Client {
-        if is_creator {
-            &self.creator
-        } else {
-            &self.joiner
+    fn new(
+        instance: u64,
+        max_joiners: u32,
+        link_token: u64,
+        events: mpsc::UnboundedSender<RoomEvent>,
+    ) -> Self {
+        let now = Instant::now();
+        Self {
+            instance,
+            creator: Client {
+                link_token,
+                ..Client::default()
+            },
+            joiners: HashMap::new(),
+            next_joiner_id: 0,
+            shared: 0,
+            max_joiners,
+            events,
+            created_at: now,
+            expires_at: now + ROOM_LIFETIME,
+            warned: false,
         }
     }
 
-    fn get_client_mut(&mut self, is_creator: bool) -> &mut Client {
-        if is_creator {
-            &mut self.creator
-        } else {
-            &mut self.joiner
+    /// Time left until this room expires.
+    fn time_left(&self) -> Duration {
+        self.expires_at.saturating_duration_since(Instant::now())
+    }
+
+    /// Notifies the room's creator of an event.
+    fn send_event(&self, event: RoomEvent) {
+        // don't care about error, since it only means
+        // the creator has disconnected
+        let _ = self.events.send(event);
+    }
+
+    /// True if no more joiners can join this room.
+    fn is_full(&self) -> bool {
+        self.joiners.len() as u32 + self.shared >= self.max_joiners
+    }
+
+    /// True once the contacts of every joiner this room accepts have been shared.
+    fn is_finished(&self) -> bool {
+        self.shared >= self.max_joiners
+    }
+
+    /// Returns the member whose link token is `link_token`.
+    fn find_link(&self, link_token: u64) -> Option<Member> {
+        if self.creator.link_token == link_token {
+            return Some(Member::Creator);
+        }
+        self.joiners
+            .iter()
+            .find(|(_, joiner)| joiner.link_token == link_token)
+            .map(|(&id, _)| Member::Joiner(id))
+    }
+
+    fn get_client_mut(&mut self, member: Member) -> Option<&mut Client> {
+        match member {
+            Member::Creator => Some(&mut self.creator),
+            Member::Joiner(id) => self.joiners.get_mut(&id),
+        }
+    }
+
+    /// Shares contacts between the creator and every joiner,
+    /// if both are done sending their contact info.
+    /// Returns the relay token given to each pair.
+    fn share_contacts(&mut self) -> Vec<u64> {
+        let Some(creator_sender) = &self.creator.sender else {
+            return Vec::new();
+        };
+        let creator = &self.creator.contact;
+
+        let mut done = Vec::new();
+        let mut relay_tokens = Vec::new();
+        for (&joiner_id, joiner) in &self.joiners {
+            if let Some(joiner_sender) = &joiner.sender {
+                let relay_token = rand::random();
+                // don't care about error, since nothing critical happens
+                // if the receiver has been dropped.
+                let _ = creator_sender.send(SharedContacts {
+                    client: creator.clone(),
+                    peer: joiner.contact.clone(),
+                    joiner_id,
+                    relay_token,
+                });
+                let _ = joiner_sender.send(SharedContacts {
+                    client: joiner.contact.clone(),
+                    peer: creator.clone(),
+                    joiner_id,
+                    relay_token,
+                });
+                done.push(joiner_id);
+                relay_tokens.push(relay_token);
+            }
+        }
+
+        // the joiners no longer need the room
+        for joiner_id in done {
+            self.joiners.remove(&joiner_id);
+            self.shared += 1;
         }
+
+        relay_tokens
+    }
+}
+
+/// Returns the room identified by `key`, if it still exists.
+fn get_room(rooms: &mut HashMap<u32, Room>, key: RoomKey) -> Option<&mut Room> {
+    rooms
+        .get_mut(&key.id)
+        .filter(|room| room.instance == key.instance)
+}
+
+/// Removes the room identified by `key`, if it still exists.
+fn remove_room(rooms: &mut HashMap<u32, Room>, key: RoomKey) {
+    if get_room(rooms, key).is_some() {
+        rooms.remove(&key.id);
     }
 }
 
@@ -55,7 +240,13 @@ pub struct State {
     /// Maps room_id to clients
     rooms: Arc<Mutex<HashMap<u32, Room>>>,
 
+    /// The instance number to give the next created room
+    next_instance: Arc<AtomicU64>,
+
     blocked: Arc<Mutex<HashSet<IpAddr>>>,
+
+    /// Maps relay tokens to the connection of the first peer that used it, if any
+    relays: Arc<Mutex<HashMap<u64, Option<TcpStream>>>>,
 }
 
 impl State {
@@ -64,7 +255,15 @@ impl State {
         blocked.insert(addr);
     }
 
-    pub fn create_room(&mut self) -> u32 {
+    /// Creates a room that accepts up to `max_joiners` joiners, and returns its key,
+    /// the time until it expires, and a receiver of events that happen in the room.
+    ///
+    /// The creator's other connections can link to it with `link_token`.
+    pub fn create_room(
+        &mut self,
+        max_joiners: u32,
+        link_token: u64,
+    ) -> (RoomKey, Duration, mpsc::UnboundedReceiver<RoomEvent>) {
         let mut rooms = self.rooms.lock().unwrap();
 
         let mut rng = rand::thread_rng();
@@ -73,86 +272,294 @@ impl State {
             room_id = rng.gen_range(0..1_048_576);
         }
 
-        rooms.insert(room_id, Room::default());
-        self.room_timeout(room_id);
+        let instance = self.next_instance.fetch_add(1, Ordering::Relaxed);
+        let key = RoomKey {
+            id: room_id,
+            instance,
+        };
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let room = Room::new(instance, max_joiners, link_token, tx);
+        let time_left = room.time_left();
+        rooms.insert(room_id, room);
+        self.room_timeout(key);
 
-        room_id
+        (key, time_left, rx)
     }
 
-    pub fn room_exists(&self, room_id: u32) -> bool {
-        self.rooms.lock().unwrap().contains_key(&room_id)
+    /// Adds a joiner to the room, unless it's full.
+    /// Returns the room's key and the joiner's id.
+    ///
+    /// The joiner's other connections can link to it with `link_token`.
+    pub fn join_room(
+        &mut self,
+        room_id: u32,
+        link_token: u64,
+    ) -> Result<(RoomKey, u32), JoinError> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = rooms.get_mut(&room_id).ok_or(JoinError::NoSuchRoomId)?;
+
+        if room.is_full() {
+            room.send_event(RoomEvent::JoinAttemptFailed);
+            return Err(JoinError::Rejected(RejectReason::RoomFull));
+        }
+
+        let joiner_id = room.next_joiner_id;
+        room.next_joiner_id += 1;
+        room.joiners.insert(
+            joiner_id,
+            Client {
+                link_token,
+                ..Client::default()
+            },
+        );
+        room.send_event(RoomEvent::PeerJoined);
+
+        let key = RoomKey {
+            id: room_id,
+            instance: room.instance,
+        };
+        Ok((key, joiner_id))
     }
 
-    pub fn update_client(
+    /// Pushes back the room's expiry to [`ROOM_LIFETIME`] from now,
+    /// but no later than [`MAX_ROOM_LIFETIME`] after the room was created.
+    /// Returns the time until the room expires.
+    pub fn extend_room(&mut self, key: RoomKey) -> Result<Duration, NoSuchRoomId> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = get_room(&mut rooms, key).ok_or(NoSuchRoomId)?;
+
+        let new_expiry = std::cmp::min(
+            Instant::now() + ROOM_LIFETIME,
+            room.created_at + MAX_ROOM_LIFETIME,
+        );
+
+        if new_expiry > room.expires_at {
+            room.expires_at = new_expiry;
+            room.warned = false;
+        }
+
+        Ok(room.time_left())
+    }
+
+    /// Called when a client disconnects before its contacts were shared.
+    /// Removes the room if the creator left, or frees the joiner's spot otherwise.
+    pub fn leave_room(&mut self, key: RoomKey, member: Member) {
+        let mut rooms = self.rooms.lock().unwrap();
+
+        match member {
+            Member::Creator => remove_room(&mut rooms, key),
+            Member::Joiner(id) => {
+                if let Some(room) = get_room(&mut rooms, key) {
+                    if room.joiners.remove(&id).is_some() {
+                        room.send_event(RoomEvent::PeerDisconnected);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Records the public address of another connection of the client with `link_token`.
+    pub fn link_connection(
         &mut self,
         room_id: u32,
-        is_creator: bool,
-        endpoint: SocketAddr,
-        public: bool,
+        link_token: u64,
+        public: SocketAddr,
     ) -> Result<(), NoSuchRoomId> {
         let mut rooms = self.rooms.lock().unwrap();
         let room = rooms.get_mut(&room_id).ok_or(NoSuchRoomId)?;
-        let contact = &mut room.get_client_mut(is_creator).contact;
-
-        let contact = if public {
-            &mut contact.public
-        } else {
-            &mut contact.private
+        let member = room.find_link(link_token).ok_or(NoSuchRoomId)?;
+        let key = RoomKey {
+            id: room_id,
+            instance: room.instance,
         };
+        drop(rooms);
+
+        self.set_public(key, member, public)
+    }
+
+    /// Records a public address of a client, as seen by the server.
+    pub fn set_public(
+        &mut self,
+        key: RoomKey,
+        member: Member,
+        endpoint: SocketAddr,
+    ) -> Result<(), NoSuchRoomId> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = get_room(&mut rooms, key).ok_or(NoSuchRoomId)?;
+        let contact = &mut room.get_client_mut(member).ok_or(NoSuchRoomId)?.contact;
 
         match endpoint {
             SocketAddr::V6(addr) => {
-                contact.v6 = Some(addr);
+                contact.public.v6 = Some(addr);
             }
             SocketAddr::V4(addr) => {
-                contact.v4 = Some(addr);
+                contact.public.v4 = Some(addr);
             }
         };
 
         Ok(())
     }
 
-    /// Returns a [`oneshot::Receiver`] that will send the other peer's contact info
+    /// Records the private addresses a client reported,
+    /// keeping at most [`MAX_PRIVATE_CANDIDATES`].
+    pub fn set_private(
+        &mut self,
+        key: RoomKey,
+        member: Member,
+        mut candidates: Vec<Candidate>,
+    ) -> Result<(), NoSuchRoomId> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = get_room(&mut rooms, key).ok_or(NoSuchRoomId)?;
+        let contact = &mut room.get_client_mut(member).ok_or(NoSuchRoomId)?.contact;
+
+        candidates.truncate(MAX_PRIVATE_CANDIDATES);
+        contact.private = candidates;
+
+        Ok(())
+    }
+
+    /// Records the address a client's router forwards to it.
+    pub fn set_mapped(
+        &mut self,
+        key: RoomKey,
+        member: Member,
+        addr: SocketAddrV4,
+    ) -> Result<(), NoSuchRoomId> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = get_room(&mut rooms, key).ok_or(NoSuchRoomId)?;
+        let contact = &mut room.get_client_mut(member).ok_or(NoSuchRoomId)?.contact;
+        contact.mapped = Some(addr);
+
+        Ok(())
+    }
+
+    /// Records how a client's NAT allocates ports,
+    /// ignoring an allocation whose delta no two ports could have.
+    pub fn set_port_allocation(
+        &mut self,
+        key: RoomKey,
+        member: Member,
+        allocation: PortAllocation,
+    ) -> Result<(), NoSuchRoomId> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = get_room(&mut rooms, key).ok_or(NoSuchRoomId)?;
+        let contact = &mut room.get_client_mut(member).ok_or(NoSuchRoomId)?.contact;
+        if allocation.is_valid() {
+            contact.port_allocation = Some(allocation);
+        }
+
+        Ok(())
+    }
+
+    /// Records that a client can only be reached through the server's relay.
+    pub fn set_relay_only(&mut self, key: RoomKey, member: Member) -> Result<(), NoSuchRoomId> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = get_room(&mut rooms, key).ok_or(NoSuchRoomId)?;
+        let contact = &mut room.get_client_mut(member).ok_or(NoSuchRoomId)?.contact;
+        contact.relay_only = true;
+
+        Ok(())
+    }
+
+    /// Returns a receiver that sends [`SharedContacts`] for each peer
     /// once that peer is also ready.
+    /// The creator receives one for each joiner, a joiner receives only one.
     pub fn set_client_done(
         &mut self,
-        room_id: u32,
-        is_creator: bool,
-    ) -> Result<oneshot::Receiver<(FullContact, FullContact)>, NoSuchRoomId> {
+        key: RoomKey,
+        member: Member,
+    ) -> Result<mpsc::UnboundedReceiver<SharedContacts>, NoSuchRoomId> {
         let mut rooms = self.rooms.lock().unwrap();
-        let room = rooms.get_mut(&room_id).ok_or(NoSuchRoomId)?;
-
-        let (tx, rx) = oneshot::channel();
+        let room = get_room(&mut rooms, key).ok_or(NoSuchRoomId)?;
 
-        room.get_client_mut(is_creator).sender = Some(tx);
+        let (tx, rx) = mpsc::unbounded_channel();
+        room.get_client_mut(member).ok_or(NoSuchRoomId)?.sender = Some(tx);
 
-        let client_info = room.get_client(is_creator).contact;
-        let peer_info = room.get_client(!is_creator).contact;
+        let relay_tokens = room.share_contacts();
 
-        // if both peers are waiting for each others' contact info
-        if let Some(client_sender) = room.get_client_mut(is_creator).sender.take() {
-            if let Some(peer_sender) = room.get_client_mut(!is_creator).sender.take() {
-                // exchange their info
-                // don't care about error, since nothing critical happens
-                // if the receiver has been dropped.
-                let _ = client_sender.send((client_info, peer_info));
-                let _ = peer_sender.send((peer_info, client_info));
+        // remove the room once every joiner has received the creator's contact
+        if room.is_finished() {
+            rooms.remove(&key.id);
+        }
+        drop(rooms);
 
-                // remove their room
-                rooms.remove(&room_id);
-            }
+        for token in relay_tokens {
+            self.open_relay(token);
         }
 
         Ok(rx)
     }
 
-    /// Removes the room with `room_id` from `self.rooms` after 10 minutes.
-    fn room_timeout(&self, room_id: u32) {
+    /// Lets the two peers given `token` connect through the server,
+    /// for up to [`RELAY_TIMEOUT`].
+    fn open_relay(&self, token: u64) {
+        self.relays.lock().unwrap().insert(token, None);
+
+        let relays = self.relays.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(RELAY_TIMEOUT).await;
+            relays.lock().unwrap().remove(&token);
+        });
+    }
+
+    /// Adds `stream` to the relay with `token`.
+    ///
+    /// Returns `None` if the other peer hasn't connected yet.
+    /// Otherwise returns the other peer's stream and `stream`,
+    /// and closes the relay to further connections.
+    pub fn join_relay(
+        &mut self,
+        token: u64,
+        stream: TcpStream,
+    ) -> Result<Option<(TcpStream, TcpStream)>, NoSuchRelay> {
+        let mut relays = self.relays.lock().unwrap();
+        let waiting = relays.get_mut(&token).ok_or(NoSuchRelay)?;
+
+        if let Some(other) = waiting.take() {
+            relays.remove(&token);
+            Ok(Some((other, stream)))
+        } else {
+            *waiting = Some(stream);
+            Ok(None)
+        }
+    }
+
+    /// Removes the room identified by `key` from `self.rooms` once it expires.
+    /// Warns the room's creator [`EXPIRY_WARNING`] before.
+    fn room_timeout(&self, key: RoomKey) {
         let state_rooms = self.rooms.clone();
         tokio::spawn(async move {
-            tokio::time::sleep(Duration::from_secs(60 * 10)).await;
-            let mut rooms = state_rooms.lock().unwrap();
-            rooms.remove(&room_id);
+            loop {
+                let wake_at = {
+                    let mut rooms = state_rooms.lock().unwrap();
+                    let Some(room) = get_room(&mut rooms, key) else {
+                        return;
+                    };
+
+                    let now = Instant::now();
+                    let warn_at = room.expires_at - EXPIRY_WARNING;
+
+                    if now >= room.expires_at {
+                        rooms.remove(&key.id);
+                        return;
+                    } else if room.warned {
+                        room.expires_at
+                    } else if now >= warn_at {
+                        room.warned = true;
+                        room.send_event(RoomEvent::RoomExpiring {
+                            seconds_left: room.time_left().as_secs(),
+                        });
+                        room.expires_at
+                    } else {
+                        warn_at
+                    }
+                };
+
+                // the room may have been extended while sleeping,
+                // so check it again after waking up
+                tokio::time::sleep_until(wake_at).await;
+            }
         });
     }
 
@@ -166,3 +573,117 @@ impl State {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn shares_contacts_with_every_joiner() {
+        let mut state = State::default();
+        let (key, _, mut events) = state.create_room(2, 1);
+
+        let (_, first) = state.join_room(key.id, 2).unwrap();
+        let (_, second) = state.join_room(key.id, 3).unwrap();
+        assert!(matches!(
+            state.join_room(key.id, 4),
+            Err(JoinError::Rejected(RejectReason::RoomFull))
+        ));
+        assert_eq!(events.recv().await, Some(RoomEvent::PeerJoined));
+        assert_eq!(events.recv().await, Some(RoomEvent::PeerJoined));
+        assert_eq!(events.recv().await, Some(RoomEvent::JoinAttemptFailed));
+
+        let mut creator = state.set_client_done(key, Member::Creator).unwrap();
+        let mut first_rx = state.set_client_done(key, Member::Joiner(first)).unwrap();
+        let shared = creator.recv().await.unwrap();
+        assert_eq!(shared.joiner_id, first);
+        assert_eq!(
+            first_rx.recv().await.unwrap().relay_token,
+            shared.relay_token
+        );
+
+        // the room stays open for the second joiner
+        assert!(state.extend_room(key).is_ok());
+
+        let mut second_rx = state.set_client_done(key, Member::Joiner(second)).unwrap();
+        let shared = creator.recv().await.unwrap();
+        assert_eq!(shared.joiner_id, second);
+        assert_eq!(
+            second_rx.recv().await.unwrap().relay_token,
+            shared.relay_token
+        );
+
+        // every joiner got the creator's contact, so the room is finished
+        assert!(state.extend_room(key).is_err());
+        assert!(matches!(
+            state.join_room(key.id, 5),
+            Err(JoinError::NoSuchRoomId)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn room_expires_after_warning() {
+        let mut state = State::default();
+        let (key, time_left, mut events) = state.create_room(1, 1);
+        assert_eq!(time_left, ROOM_LIFETIME);
+
+        let start = Instant::now();
+        assert_eq!(
+            events.recv().await,
+            Some(RoomEvent::RoomExpiring { seconds_left: 60 })
+        );
+        assert_eq!(start.elapsed(), ROOM_LIFETIME - EXPIRY_WARNING);
+
+        tokio::time::sleep(EXPIRY_WARNING + Duration::from_secs(1)).await;
+        assert!(state.extend_room(key).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn extending_delays_expiry() {
+        let mut state = State::default();
+        let (key, _, mut events) = state.create_room(1, 1);
+
+        tokio::time::sleep(Duration::from_secs(5 * 60)).await;
+        assert_eq!(state.extend_room(key).unwrap(), ROOM_LIFETIME);
+
+        // the warning comes a minute before the new expiry
+        let start = Instant::now();
+        assert_eq!(
+            events.recv().await,
+            Some(RoomEvent::RoomExpiring { seconds_left: 60 })
+        );
+        assert_eq!(start.elapsed(), ROOM_LIFETIME - EXPIRY_WARNING);
+
+        // but rooms don't last longer than an hour in total
+        for _ in 0..5 {
+            state.extend_room(key).unwrap();
+            tokio::time::sleep(Duration::from_secs(9 * 60)).await;
+        }
+        assert_eq!(start.elapsed(), Duration::from_secs(54 * 60));
+        assert_eq!(state.extend_room(key).unwrap(), Duration::from_secs(60));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timer_spares_new_room_with_same_id() {
+        let mut state = State::default();
+        let (old_key, _, _events) = state.create_room(1, 1);
+        state.leave_room(old_key, Member::Creator);
+
+        // a new room that happens to get the same id, a few minutes later
+        tokio::time::sleep(Duration::from_secs(3 * 60)).await;
+        let instance = state.next_instance.fetch_add(1, Ordering::Relaxed);
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let room = Room::new(instance, 1, 2, tx);
+        state.rooms.lock().unwrap().insert(old_key.id, room);
+        let new_key = RoomKey {
+            id: old_key.id,
+            instance,
+        };
+
+        // the old room would have expired by now, but the new one hasn't
+        tokio::time::sleep(ROOM_LIFETIME - Duration::from_secs(3 * 60) + Duration::from_secs(1))
+            .await;
+        assert!(state.extend_room(old_key).is_err());
+        assert!(state.extend_room(new_key).is_ok());
+    }
+}
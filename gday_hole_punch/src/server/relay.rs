@@ -0,0 +1,26 @@
+use super::global_state::State;
+use super::ServerError;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+/// Serves a plain TCP connection that asked to be relayed to a peer.
+///
+/// Reads the [`RELAY_MAGIC`](crate::RELAY_MAGIC) byte and the 8-byte relay token.
+/// Once the peer's connection with the same token arrives,
+/// copies bytes between the two until either is closed.
+pub async fn relay(mut stream: TcpStream, mut state: State) -> Result<(), ServerError> {
+    let mut header = [0; 9];
+    stream.read_exact(&mut header).await?;
+    let token = u64::from_be_bytes(header[1..].try_into().unwrap());
+
+    let Some((mut a, mut b)) = state
+        .join_relay(token, stream)
+        .map_err(|_| ServerError::NoSuchRelay)?
+    else {
+        // the other peer's connection will start relaying
+        return Ok(());
+    };
+
+    tokio::io::copy_bidirectional(&mut a, &mut b).await?;
+    Ok(())
+}
@@ -0,0 +1,799 @@
+//! A simulated network of hosts behind NATs, for testing hole punching in one process.
+//!
+//! Connections are in-memory streams. Only their setup is simulated:
+//! each connection attempt's SYN is translated by the NAT it leaves through,
+//! and dropped if it's lost or the NAT it arrives at doesn't let it through.
+//! Dropped SYNs are retransmitted, like TCP does, until the attempt times out.
+//! A SYN that reaches a socket that is itself connecting to the SYN's sender
+//! completes a simultaneous open.
+
+use crate::client::{public_addr, ContactSharer, Interface, Network, PeerConnection};
+use crate::server::{AccessList, ConnectionHandler, State};
+use crate::{Transport, PORT_PROBES};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+
+/// Time between retransmissions of a SYN that got no reply.
+const RETRANSMIT: Duration = Duration::from_millis(200);
+
+/// How long a connection attempt retransmits its SYN before giving up.
+const SYN_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// First port given to sockets bound to port 0.
+const EPHEMERAL_PORTS: u16 = 50000;
+
+/// First public port a symmetric NAT allocates.
+const SYMMETRIC_PORTS: u16 = 40000;
+
+/// Buffer size of each direction of a [`SimStream`].
+const STREAM_BUF: usize = 64 * 1024;
+
+const SERVER_PORT: u16 = 49870;
+const SERVER_V6: IpAddr = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+const SERVER_V4: IpAddr = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 1));
+
+/// How a NAT maps private endpoints to public ports,
+/// and which inbound connections it lets through to them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NatKind {
+    /// Keeps the private port, and lets anyone connect to it.
+    FullCone,
+    /// Keeps the private port, and only lets hosts the private endpoint contacted connect to it.
+    Restricted,
+    /// Keeps the private port, and only lets endpoints the private endpoint contacted connect to it.
+    PortRestricted,
+    /// Allocates the next public port to each remote endpoint a private endpoint contacts,
+    /// and only lets that remote endpoint connect to it.
+    Symmetric,
+}
+
+/// A simulated network. Clones refer to the same network.
+#[derive(Clone)]
+pub struct Simulator {
+    world: Arc<Mutex<World>>,
+}
+
+/// A host on a [`Simulator`]'s network.
+/// Implements [`Network`] to give [`PeerConnector`](crate::client::PeerConnector)s its sockets.
+#[derive(Clone)]
+pub struct Host {
+    world: Arc<Mutex<World>>,
+    id: usize,
+}
+
+struct World {
+    hosts: Vec<HostInfo>,
+    nats: Vec<Nat>,
+    /// Where connections to each listening endpoint are sent
+    listeners: HashMap<SocketAddr, mpsc::UnboundedSender<SimStream>>,
+    /// Connection attempts waiting for a reply, by (local endpoint, remote endpoint)
+    pending: HashMap<(SocketAddr, SocketAddr), oneshot::Sender<SimStream>>,
+    /// Probability that a SYN is lost
+    loss: f64,
+    rng: StdRng,
+}
+
+struct HostInfo {
+    addrs: Vec<IpAddr>,
+    /// Index of the NAT in front of the host's IPv4 address, if any
+    nat: Option<usize>,
+    next_ephemeral: u16,
+}
+
+struct Nat {
+    kind: NatKind,
+    public_ip: Ipv4Addr,
+    /// Public port of each (private endpoint, remote endpoint).
+    /// The remote endpoint is `None` unless the NAT is symmetric.
+    mappings: HashMap<(SocketAddr, Option<SocketAddr>), u16>,
+    /// Private endpoint of each public port
+    ports: HashMap<u16, SocketAddr>,
+    /// (public port, remote endpoint) pairs that have been contacted
+    contacted: HashSet<(u16, SocketAddr)>,
+    next_port: u16,
+}
+
+/// Result of sending a SYN.
+enum Syn {
+    Connected(SimStream),
+    /// Lost, or filtered by a NAT
+    Dropped,
+    /// Nothing listens on the endpoint
+    Refused,
+}
+
+impl Simulator {
+    /// Creates a network that loses each SYN with probability `loss`,
+    /// choosing which with a random number generator seeded with `seed`.
+    pub fn new(loss: f64, seed: u64) -> Self {
+        let world = World {
+            hosts: Vec::new(),
+            nats: Vec::new(),
+            listeners: HashMap::new(),
+            pending: HashMap::new(),
+            loss,
+            rng: StdRng::seed_from_u64(seed),
+        };
+        Self {
+            world: Arc::new(Mutex::new(world)),
+        }
+    }
+
+    /// Adds an IPv4-only host behind its own NAT.
+    pub fn add_host_behind_nat(&self, kind: NatKind) -> Host {
+        let mut world = self.world.lock().unwrap();
+        let n = u8::try_from(world.nats.len() + 1).unwrap();
+        world.nats.push(Nat {
+            kind,
+            public_ip: Ipv4Addr::new(198, 51, 100, n),
+            mappings: HashMap::new(),
+            ports: HashMap::new(),
+            contacted: HashSet::new(),
+            next_port: SYMMETRIC_PORTS,
+        });
+        let nat = world.nats.len() - 1;
+        drop(world);
+        self.add_host(vec![Ipv4Addr::new(10, 0, n, 2).into()], Some(nat))
+    }
+
+    /// Adds a host with only a public IPv6 address.
+    pub fn add_ipv6_host(&self) -> Host {
+        let n = u16::try_from(self.world.lock().unwrap().hosts.len() + 1).unwrap();
+        self.add_host(
+            vec![Ipv6Addr::new(0x2001, 0xdb8, n, 0, 0, 0, 0, 2).into()],
+            None,
+        )
+    }
+
+    /// Starts a server on a public host with both IPv6 and IPv4.
+    pub fn start_server(&self) {
+        self.start_server_with(None);
+    }
+
+    /// Starts a server that only lets clients with a key in `access_list` create rooms.
+    pub fn start_private_server(&self, access_list: AccessList) {
+        self.start_server_with(Some(access_list));
+    }
+
+    fn start_server_with(&self, access_list: Option<AccessList>) {
+        let host = self.add_host(vec![SERVER_V6, SERVER_V4], None);
+        let state = State::default();
+
+        for ip in [SERVER_V6, SERVER_V4] {
+            let mut listener = host.listen(SocketAddr::new(ip, SERVER_PORT)).unwrap();
+            let state = state.clone();
+            let access_list = access_list.clone();
+            tokio::spawn(async move {
+                while let Ok(stream) = Host::accept(&mut listener).await {
+                    let handler = ConnectionHandler::start(
+                        state.clone(),
+                        access_list.clone(),
+                        10,
+                        Box::new(stream),
+                    );
+                    tokio::spawn(handler);
+                }
+            });
+        }
+    }
+
+    fn add_host(&self, addrs: Vec<IpAddr>, nat: Option<usize>) -> Host {
+        let mut world = self.world.lock().unwrap();
+        world.hosts.push(HostInfo {
+            addrs,
+            nat,
+            next_ephemeral: EPHEMERAL_PORTS,
+        });
+        Host {
+            world: self.world.clone(),
+            id: world.hosts.len() - 1,
+        }
+    }
+}
+
+impl Host {
+    /// Connects to the server over IPv6 or IPv4 from a new port,
+    /// or returns `None` if this host has no address of that family.
+    pub async fn connect_to_server(&self, ipv6: bool) -> Option<Box<dyn Transport>> {
+        let (local, server) = if ipv6 {
+            (Ipv6Addr::UNSPECIFIED.into(), SERVER_V6)
+        } else {
+            (Ipv4Addr::UNSPECIFIED.into(), SERVER_V4)
+        };
+        let local = self.addr(local)?;
+        let stream = self
+            .connect(
+                SocketAddr::new(local, 0),
+                SocketAddr::new(server, SERVER_PORT),
+            )
+            .await
+            .unwrap();
+        Some(Box::new(stream))
+    }
+
+    /// Returns this host's address with the same family as `ip`, if any.
+    fn addr(&self, ip: IpAddr) -> Option<IpAddr> {
+        let world = self.world.lock().unwrap();
+        world.hosts[self.id]
+            .addrs
+            .iter()
+            .copied()
+            .find(|addr| addr.is_ipv6() == ip.is_ipv6())
+    }
+}
+
+impl Network for Host {
+    type Stream = SimStream;
+    type Listener = SimListener;
+
+    async fn connect(&self, local: SocketAddr, peer: SocketAddr) -> std::io::Result<SimStream> {
+        let (tx, mut rx) = oneshot::channel();
+        let guard = {
+            let mut world = self.world.lock().unwrap();
+            let local = world.bind(self.id, local)?;
+            if world.pending.contains_key(&(local, peer)) {
+                return Err(ErrorKind::AddrInUse.into());
+            }
+            world.pending.insert((local, peer), tx);
+            PendingGuard {
+                world: self.world.clone(),
+                key: (local, peer),
+            }
+        };
+        let local = guard.key.0;
+
+        let deadline = Instant::now() + SYN_TIMEOUT;
+        loop {
+            let syn = self.world.lock().unwrap().send_syn(self.id, local, peer);
+            match syn {
+                Syn::Connected(stream) => return Ok(stream),
+                Syn::Refused => return Err(ErrorKind::ConnectionRefused.into()),
+                Syn::Dropped => (),
+            }
+
+            // the peer's SYN may arrive first, completing a simultaneous open
+            tokio::select! {
+                stream = &mut rx => return stream.map_err(|_| ErrorKind::ConnectionAborted.into()),
+                () = tokio::time::sleep(RETRANSMIT) => (),
+            }
+            if Instant::now() >= deadline {
+                return Err(ErrorKind::TimedOut.into());
+            }
+        }
+    }
+
+    fn listen(&self, local: SocketAddr) -> std::io::Result<SimListener> {
+        let mut world = self.world.lock().unwrap();
+        let local = world.bind(self.id, local)?;
+        if world.listeners.contains_key(&local) {
+            return Err(ErrorKind::AddrInUse.into());
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        world.listeners.insert(local, tx);
+        Ok(SimListener {
+            world: self.world.clone(),
+            local,
+            rx,
+        })
+    }
+
+    async fn accept(listener: &mut SimListener) -> std::io::Result<SimStream> {
+        listener
+            .rx
+            .recv()
+            .await
+            .ok_or_else(|| ErrorKind::NotConnected.into())
+    }
+
+    fn interfaces(&self) -> Vec<Interface> {
+        let world = self.world.lock().unwrap();
+        world.hosts[self.id]
+            .addrs
+            .iter()
+            .map(|&ip| Interface {
+                name: "eth0".to_string(),
+                ip,
+            })
+            .collect()
+    }
+}
+
+impl World {
+    /// Checks that `local` is an address of `host`,
+    /// and gives it the next ephemeral port if its port is 0.
+    fn bind(&mut self, host: usize, mut local: SocketAddr) -> std::io::Result<SocketAddr> {
+        let info = &mut self.hosts[host];
+        if !info.addrs.contains(&local.ip()) {
+            return Err(ErrorKind::AddrNotAvailable.into());
+        }
+        if local.port() == 0 {
+            local.set_port(info.next_ephemeral);
+            info.next_ephemeral += 1;
+        }
+        Ok(local)
+    }
+
+    /// Sends a SYN from `local` on `host` to `remote`.
+    fn send_syn(&mut self, host: usize, local: SocketAddr, remote: SocketAddr) -> Syn {
+        let source = self.translate_outbound(host, local, remote);
+
+        if self.rng.gen_bool(self.loss) {
+            return Syn::Dropped;
+        }
+        let Some(dest) = self.translate_inbound(source, remote) else {
+            return Syn::Dropped;
+        };
+
+        let (ours, theirs) = tokio::io::duplex(STREAM_BUF);
+        let ours = SimStream {
+            inner: ours,
+            local,
+            peer: remote,
+        };
+        let mut theirs = SimStream {
+            inner: theirs,
+            local: dest,
+            peer: source,
+        };
+
+        if let Some(tx) = self.pending.remove(&(dest, source)) {
+            match tx.send(theirs) {
+                Ok(()) => return Syn::Connected(ours),
+                Err(returned) => theirs = returned,
+            }
+        }
+        if let Some(listener) = self.listeners.get(&dest) {
+            if listener.send(theirs).is_ok() {
+                return Syn::Connected(ours);
+            }
+        }
+        Syn::Refused
+    }
+
+    /// Returns the endpoint a connection from `local` on `host` to `remote`
+    /// appears to come from, after passing the host's NAT.
+    fn translate_outbound(
+        &mut self,
+        host: usize,
+        local: SocketAddr,
+        remote: SocketAddr,
+    ) -> SocketAddr {
+        let info = &self.hosts[host];
+        let (Some(nat), SocketAddr::V4(_)) = (info.nat, local) else {
+            return local;
+        };
+        if info.addrs.contains(&remote.ip()) {
+            return local;
+        }
+
+        let nat = &mut self.nats[nat];
+        let key = (local, (nat.kind == NatKind::Symmetric).then_some(remote));
+        let port = match nat.mappings.get(&key) {
+            Some(&port) => port,
+            None => {
+                let port =
+                    if nat.kind != NatKind::Symmetric && !nat.ports.contains_key(&local.port()) {
+                        local.port()
+                    } else {
+                        while nat.ports.contains_key(&nat.next_port) {
+                            nat.next_port += 1;
+                        }
+                        nat.next_port
+                    };
+                nat.mappings.insert(key, port);
+                nat.ports.insert(port, local);
+                port
+            }
+        };
+
+        nat.contacted.insert((port, remote));
+        SocketAddr::new(nat.public_ip.into(), port)
+    }
+
+    /// Returns the endpoint a SYN from `source` to `remote` arrives at,
+    /// or `None` if it doesn't get there.
+    fn translate_inbound(&self, source: SocketAddr, remote: SocketAddr) -> Option<SocketAddr> {
+        if let Some(nat) = self.nats.iter().find(|nat| remote.ip() == nat.public_ip) {
+            let port = remote.port();
+            let private = *nat.ports.get(&port)?;
+            let allowed = match nat.kind {
+                NatKind::FullCone => true,
+                NatKind::Restricted => nat
+                    .contacted
+                    .iter()
+                    .any(|&(p, contacted)| p == port && contacted.ip() == source.ip()),
+                NatKind::PortRestricted | NatKind::Symmetric => {
+                    nat.contacted.contains(&(port, source))
+                }
+            };
+            return allowed.then_some(private);
+        }
+
+        // private addresses behind a NAT are only reachable from the same host
+        let host = self.hosts.iter().find(|h| h.addrs.contains(&remote.ip()))?;
+        let behind_nat = remote.is_ipv4() && host.nat.is_some();
+        (!behind_nat || host.addrs.contains(&source.ip())).then_some(remote)
+    }
+}
+
+/// Removes a connection attempt from [`World::pending`] once it's over.
+struct PendingGuard {
+    world: Arc<Mutex<World>>,
+    key: (SocketAddr, SocketAddr),
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        self.world.lock().unwrap().pending.remove(&self.key);
+    }
+}
+
+/// Accepts connections to an endpoint of a [`Host`].
+pub struct SimListener {
+    world: Arc<Mutex<World>>,
+    local: SocketAddr,
+    rx: mpsc::UnboundedReceiver<SimStream>,
+}
+
+impl Drop for SimListener {
+    fn drop(&mut self) {
+        self.world.lock().unwrap().listeners.remove(&self.local);
+    }
+}
+
+/// One end of a simulated connection.
+#[derive(Debug)]
+pub struct SimStream {
+    inner: DuplexStream,
+    local: SocketAddr,
+    peer: SocketAddr,
+}
+
+impl AsyncRead for SimStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SimStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl Transport for SimStream {
+    fn tcp_stream(&self) -> Option<&tokio::net::TcpStream> {
+        None
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        Ok(self.local)
+    }
+
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+}
+
+/// Connects `host` to the server over IPv6 and IPv4, like the client does.
+/// If its NAT rewrites ports, also opens [`PORT_PROBES`] IPv4 connections to measure it with.
+async fn connect_like_client(
+    host: &Host,
+) -> (
+    Option<Box<dyn Transport>>,
+    Option<Box<dyn Transport>>,
+    Vec<Box<dyn Transport>>,
+) {
+    let conn_v6 = host.connect_to_server(true).await;
+    let mut conn_v4 = None;
+    let mut probes = Vec::new();
+
+    if let Some(conn) = host.connect_to_server(false).await {
+        let local = conn.local_addr().unwrap();
+        let (conn, public) = public_addr(conn).await.unwrap();
+        conn_v4 = Some(conn);
+
+        if local.port() != public.port() {
+            for _ in 0..PORT_PROBES {
+                probes.extend(host.connect_to_server(false).await);
+            }
+        }
+    }
+
+    (conn_v6, conn_v4, probes)
+}
+
+/// Creates a room on the simulated server from `creator`, joins it from `joiner`,
+/// and connects the two peers to each other.
+///
+/// Returns the peers' connections, or `None` if they couldn't connect within a minute.
+pub async fn connect_peers(
+    creator: &Host,
+    joiner: &Host,
+) -> Option<(PeerConnection, PeerConnection)> {
+    let (creator_v6, creator_v4, creator_probes) = connect_like_client(creator).await;
+    let (creator_sharer, room_id) = ContactSharer::create_room(creator_v6, creator_v4, None, 1)
+        .await
+        .unwrap();
+    let mut creator_sharer = creator_sharer.with_network(creator.clone());
+
+    let (joiner_v6, joiner_v4, joiner_probes) = connect_like_client(joiner).await;
+    let joiner_sharer = ContactSharer::join_room(joiner_v6, joiner_v4, room_id)
+        .await
+        .unwrap();
+    let mut joiner_sharer = joiner_sharer.with_network(joiner.clone());
+
+    if !creator_probes.is_empty() {
+        creator_sharer
+            .measure_port_allocation(creator_probes)
+            .await
+            .unwrap();
+    }
+    if !joiner_probes.is_empty() {
+        joiner_sharer
+            .measure_port_allocation(joiner_probes)
+            .await
+            .unwrap();
+    }
+
+    let (creator_connector, joiner_connector) = tokio::join!(
+        creator_sharer.get_peer_connector(),
+        joiner_sharer.get_peer_connector()
+    );
+    let (creator_connector, joiner_connector) =
+        (creator_connector.unwrap(), joiner_connector.unwrap());
+
+    let connections = tokio::time::timeout(Duration::from_secs(60), async {
+        tokio::join!(
+            creator_connector.connect_to_peer(7),
+            joiner_connector.connect_to_peer(7)
+        )
+    })
+    .await;
+
+    match connections {
+        Ok((Ok(creator), Ok(joiner))) => Some((creator, joiner)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{ClientError, PeerPath};
+    use crate::{RejectReason, RoomEvent};
+    use futures::future::join_all;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const CONES: [NatKind; 3] = [
+        NatKind::FullCone,
+        NatKind::Restricted,
+        NatKind::PortRestricted,
+    ];
+
+    /// Asserts that `a` and `b` are the two ends of the same connection.
+    async fn assert_connected((mut a, mut b): (PeerConnection, PeerConnection)) {
+        assert_eq!(a.shared_secret, b.shared_secret);
+        a.stream.write_all(b"hi").await.unwrap();
+        let mut buf = [0; 2];
+        b.stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hi");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cone_nats_connect() {
+        for (i, creator_kind) in (0..).zip(CONES) {
+            for joiner_kind in CONES {
+                let sim = Simulator::new(0.0, i);
+                sim.start_server();
+                let creator = sim.add_host_behind_nat(creator_kind);
+                let joiner = sim.add_host_behind_nat(joiner_kind);
+
+                let peers = connect_peers(&creator, &joiner).await;
+                let peers = peers.unwrap_or_else(|| {
+                    panic!("{creator_kind:?} and {joiner_kind:?} didn't connect")
+                });
+                assert_connected(peers).await;
+            }
+        }
+    }
+
+    /// The ports of a sequential symmetric NAT are predicted by its peer.
+    #[tokio::test(start_paused = true)]
+    async fn symmetric_nat_connects_to_port_restricted() {
+        let sim = Simulator::new(0.0, 1);
+        sim.start_server();
+        let creator = sim.add_host_behind_nat(NatKind::Symmetric);
+        let joiner = sim.add_host_behind_nat(NatKind::PortRestricted);
+
+        let peers = connect_peers(&creator, &joiner).await.unwrap();
+        assert_eq!(peers.1.path, PeerPath::Predicted);
+        assert_connected(peers).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn symmetric_nats_dont_connect() {
+        let sim = Simulator::new(0.0, 2);
+        sim.start_server();
+        let creator = sim.add_host_behind_nat(NatKind::Symmetric);
+        let joiner = sim.add_host_behind_nat(NatKind::Symmetric);
+
+        assert!(connect_peers(&creator, &joiner).await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connects_despite_packet_loss() {
+        for seed in 0..5 {
+            let sim = Simulator::new(0.3, seed);
+            sim.start_server();
+            let creator = sim.add_host_behind_nat(NatKind::PortRestricted);
+            let joiner = sim.add_host_behind_nat(NatKind::PortRestricted);
+
+            assert_connected(connect_peers(&creator, &joiner).await.unwrap()).await;
+        }
+    }
+
+    /// Behind port-restricted NATs, each peer's first SYN is dropped by the other's NAT
+    /// and the reply completes a simultaneous open, so neither side's listener
+    /// gets to read the joiner id of the room's several joiners.
+    #[tokio::test(start_paused = true)]
+    async fn joiners_connect_by_simultaneous_open() {
+        let sim = Simulator::new(0.0, 6);
+        sim.start_server();
+        let creator = sim.add_host_behind_nat(NatKind::PortRestricted);
+        let joiners = [
+            sim.add_host_behind_nat(NatKind::PortRestricted),
+            sim.add_host_behind_nat(NatKind::PortRestricted),
+        ];
+
+        let (creator_sharer, room_id) =
+            ContactSharer::create_room(None, creator.connect_to_server(false).await, None, 2)
+                .await
+                .unwrap();
+        let mut creator_sharer = creator_sharer.with_network(creator.clone());
+        let mut joiner_sharers = Vec::new();
+        for joiner in &joiners {
+            let sharer =
+                ContactSharer::join_room(None, joiner.connect_to_server(false).await, room_id)
+                    .await
+                    .unwrap();
+            joiner_sharers.push(sharer.with_network(joiner.clone()));
+        }
+
+        let creator_connectors = async {
+            let first = creator_sharer.next_peer_connector().await.unwrap();
+            let second = creator_sharer.next_peer_connector().await.unwrap();
+            vec![first, second]
+        };
+        let joiner_connectors =
+            join_all(joiner_sharers.into_iter().map(|s| s.get_peer_connector()));
+        let (mut creator_connectors, joiner_connectors) =
+            tokio::join!(creator_connectors, joiner_connectors);
+
+        let mut pairs = Vec::new();
+        for joiner_connector in joiner_connectors {
+            let joiner_connector = joiner_connector.unwrap();
+            let i = creator_connectors
+                .iter()
+                .position(|c| c.joiner_id() == joiner_connector.joiner_id())
+                .unwrap();
+            let creator_connector = creator_connectors.swap_remove(i);
+            pairs.push(async move {
+                tokio::join!(
+                    creator_connector.connect_to_peer(7),
+                    joiner_connector.connect_to_peer(7)
+                )
+            });
+        }
+        let connections = tokio::time::timeout(Duration::from_secs(60), join_all(pairs))
+            .await
+            .unwrap();
+
+        for (creator, joiner) in connections {
+            let (creator, joiner) = (creator.unwrap(), joiner.unwrap());
+            assert_eq!(creator.path, PeerPath::Public);
+            assert_eq!(joiner.path, PeerPath::Public);
+            assert_connected((creator, joiner)).await;
+        }
+    }
+
+    /// The server accepts at most 10 joiners per room, and at least 1.
+    #[tokio::test(start_paused = true)]
+    async fn max_joiners_is_clamped() {
+        let sim = Simulator::new(0.0, 4);
+        sim.start_server();
+        let creator = sim.add_ipv6_host();
+
+        for (requested, accepted) in [(0, 1), (3, 3), (100, 10)] {
+            let (sharer, _) = ContactSharer::create_room(
+                creator.connect_to_server(true).await,
+                None,
+                None,
+                requested,
+            )
+            .await
+            .unwrap();
+            assert_eq!(sharer.max_joiners(), accepted);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn creator_receives_room_events() {
+        let sim = Simulator::new(0.0, 5);
+        sim.start_server();
+        let creator = sim.add_ipv6_host();
+        let joiner = sim.add_ipv6_host();
+
+        let (creator_sharer, room_id) =
+            ContactSharer::create_room(creator.connect_to_server(true).await, None, None, 1)
+                .await
+                .unwrap();
+        let mut creator_sharer = creator_sharer.with_network(creator.clone());
+        let mut events = creator_sharer.room_events().unwrap();
+        // events are only received while waiting for a peer
+        let waiting = tokio::spawn(async move { creator_sharer.next_peer_connector().await });
+
+        let join = || async {
+            ContactSharer::join_room(joiner.connect_to_server(true).await, None, room_id).await
+        };
+        let first = join().await.unwrap();
+        assert_eq!(events.recv().await, Some(RoomEvent::PeerJoined));
+
+        assert!(matches!(
+            join().await,
+            Err(ClientError::Rejected(RejectReason::RoomFull))
+        ));
+        assert_eq!(events.recv().await, Some(RoomEvent::JoinAttemptFailed));
+
+        drop(first);
+        assert_eq!(events.recv().await, Some(RoomEvent::PeerDisconnected));
+
+        // the creator is warned a minute before the room expires
+        assert_eq!(
+            events.recv().await,
+            Some(RoomEvent::RoomExpiring { seconds_left: 60 })
+        );
+
+        waiting.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ipv6_only_hosts() {
+        let sim = Simulator::new(0.0, 3);
+        sim.start_server();
+        let creator = sim.add_ipv6_host();
+        let joiner = sim.add_ipv6_host();
+        let ipv4_only = sim.add_host_behind_nat(NatKind::FullCone);
+
+        let peers = connect_peers(&creator, &joiner).await.unwrap();
+        assert!(peers.0.ipv6 && peers.1.ipv6);
+        assert_connected(peers).await;
+        assert!(connect_peers(&creator, &ipv4_only).await.is_none());
+    }
+}
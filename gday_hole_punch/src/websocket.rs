@@ -0,0 +1,523 @@
+//! A minimal WebSocket ([RFC 6455](https://www.rfc-editor.org/rfc/rfc6455)) tunnel,
+//! for reaching the server on a standard HTTPS port through firewalls that block its usual one.
+//!
+//! Only carries a byte stream: all data frames are treated as a continuation of it.
+//! Pings are ignored rather than answered.
+
+use crate::Transport;
+use sha1::{Digest, Sha1};
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+
+/// Path the server accepts WebSocket connections on.
+pub const WEBSOCKET_PATH: &str = "/gday";
+
+/// Longest HTTP head accepted during the handshake.
+const MAX_HEAD: usize = 8192;
+
+/// Largest payload of a single frame this side sends.
+const MAX_FRAME: usize = 16 * 1024;
+
+/// Appended to the client's key to compute the server's accept key.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+
+/// A byte stream tunneled through a WebSocket over `T`.
+#[derive(Debug)]
+pub struct WebSocket<T> {
+    inner: T,
+    /// True on the client side, which must mask every frame it sends.
+    masked: bool,
+    read_state: ReadState,
+    /// Header of the frame being received
+    header: [u8; 14],
+    /// Number of bytes of `header` received
+    header_len: usize,
+    /// Encoded frame waiting to be written to `inner`
+    write_buf: Vec<u8>,
+    /// Number of bytes of `write_buf` already written
+    written: usize,
+    /// True once a close frame has been queued
+    close_sent: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ReadState {
+    Header,
+    Payload {
+        remaining: u64,
+        mask: Option<[u8; 4]>,
+        /// Position in the payload, to know which byte of `mask` applies
+        offset: usize,
+        /// False for control frames, whose payload is discarded
+        data: bool,
+    },
+    Closed,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> WebSocket<T> {
+    /// Opens a WebSocket to the server at `host` over `stream`.
+    pub async fn connect(mut stream: T, host: &str) -> std::io::Result<Self> {
+        let key = base64(&rand::random::<[u8; 16]>());
+        let request = format!(
+            "GET {WEBSOCKET_PATH} HTTP/1.1\r\n\
+            Host: {host}\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Key: {key}\r\n\
+            Sec-WebSocket-Version: 13\r\n\r\n"
+        );
+        stream.write_all(request.as_bytes()).await?;
+        stream.flush().await?;
+
+        let head = read_head(&mut stream).await?;
+        let status = head.lines().next().unwrap_or_default();
+        if status.split_whitespace().nth(1) != Some("101") {
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("Server refused WebSocket: {status}"),
+            ));
+        }
+        if header(&head, "sec-websocket-accept") != Some(&accept_key(&key)) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Server sent an invalid WebSocket accept key",
+            ));
+        }
+
+        Ok(Self::new(stream, true))
+    }
+
+    /// Accepts a WebSocket from a client over `stream`.
+    ///
+    /// Replies "400 Bad Request" to anything other than
+    /// a WebSocket upgrade of [`WEBSOCKET_PATH`].
+    pub async fn accept(mut stream: T) -> std::io::Result<Self> {
+        let head = read_head(&mut stream).await?;
+        let request = head.lines().next().unwrap_or_default();
+        let upgrade = header(&head, "upgrade").is_some_and(|u| u.eq_ignore_ascii_case("websocket"));
+
+        let key = match header(&head, "sec-websocket-key") {
+            Some(key) if upgrade && request.starts_with(&format!("GET {WEBSOCKET_PATH} ")) => key,
+            _ => {
+                stream
+                    .write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n")
+                    .await?;
+                stream.shutdown().await?;
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Invalid WebSocket request: {request}"),
+                ));
+            }
+        };
+
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(key)
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.flush().await?;
+
+        Ok(Self::new(stream, false))
+    }
+}
+
+impl<T> WebSocket<T> {
+    fn new(inner: T, masked: bool) -> Self {
+        Self {
+            inner,
+            masked,
+            read_state: ReadState::Header,
+            header: [0; 14],
+            header_len: 0,
+            write_buf: Vec::new(),
+            written: 0,
+            close_sent: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Appends a frame carrying `payload` to `write_buf`.
+    fn queue_frame(&mut self, opcode: u8, payload: &[u8]) {
+        self.write_buf.push(0x80 | opcode);
+
+        let mask_bit = if self.masked { 0x80 } else { 0 };
+        if payload.len() < 126 {
+            self.write_buf.push(mask_bit | payload.len() as u8);
+        } else if let Ok(len) = u16::try_from(payload.len()) {
+            self.write_buf.push(mask_bit | 126);
+            self.write_buf.extend_from_slice(&len.to_be_bytes());
+        } else {
+            self.write_buf.push(mask_bit | 127);
+            self.write_buf
+                .extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        }
+
+        if self.masked {
+            let mask = rand::random::<[u8; 4]>();
+            self.write_buf.extend_from_slice(&mask);
+            self.write_buf.extend(
+                payload
+                    .iter()
+                    .zip(mask.iter().cycle())
+                    .map(|(byte, mask)| byte ^ mask),
+            );
+        } else {
+            self.write_buf.extend_from_slice(payload);
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> WebSocket<T> {
+    /// Writes all of `write_buf` to `inner`.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while self.written < self.write_buf.len() {
+            let bytes = &self.write_buf[self.written..];
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, bytes))?;
+            if n == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.write_buf.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for WebSocket<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        // reading nothing from the inner stream would look like it closed
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            match this.read_state {
+                ReadState::Closed => return Poll::Ready(Ok(())),
+                ReadState::Header => {
+                    let needed = header_size(&this.header[..this.header_len]);
+                    if this.header_len < needed {
+                        let mut header = ReadBuf::new(&mut this.header[this.header_len..needed]);
+                        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut header))?;
+                        let n = header.filled().len();
+                        if n == 0 && this.header_len == 0 {
+                            // closed between frames
+                            this.read_state = ReadState::Closed;
+                        } else if n == 0 {
+                            return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+                        }
+                        this.header_len += n;
+                        continue;
+                    }
+
+                    this.read_state = parse_header(&this.header[..needed]);
+                    this.header_len = 0;
+                }
+                ReadState::Payload { remaining: 0, .. } => this.read_state = ReadState::Header,
+                ReadState::Payload {
+                    remaining,
+                    mask,
+                    offset,
+                    data,
+                } => {
+                    let mut discarded = [0; 125];
+                    let dest = if data {
+                        buf.initialize_unfilled()
+                    } else {
+                        &mut discarded[..]
+                    };
+                    let max = usize::try_from(remaining)
+                        .unwrap_or(usize::MAX)
+                        .min(dest.len());
+                    let dest = &mut dest[..max];
+
+                    let mut read = ReadBuf::new(dest);
+                    ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+                    let n = read.filled().len();
+                    if n == 0 {
+                        return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+                    }
+
+                    if let Some(mask) = mask {
+                        for (i, byte) in dest[..n].iter_mut().enumerate() {
+                            *byte ^= mask[(offset + i) % 4];
+                        }
+                    }
+
+                    this.read_state = ReadState::Payload {
+                        remaining: remaining - n as u64,
+                        mask,
+                        offset: offset + n,
+                        data,
+                    };
+
+                    if data {
+                        buf.advance(n);
+                        return Poll::Ready(Ok(()));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for WebSocket<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let len = buf.len().min(MAX_FRAME);
+        this.queue_frame(OPCODE_BINARY, &buf[..len]);
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        if !this.close_sent {
+            this.close_sent = true;
+            this.queue_frame(OPCODE_CLOSE, &[]);
+            ready!(this.poll_drain(cx))?;
+        }
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+impl<T: Transport> Transport for WebSocket<T> {
+    fn tcp_stream(&self) -> Option<&TcpStream> {
+        self.inner.tcp_stream()
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
+
+/// Returns the full size of the frame header that starts with `received`,
+/// or 2 if too little of it was received to tell.
+fn header_size(received: &[u8]) -> usize {
+    let [_, second, ..] = received else {
+        return 2;
+    };
+    let length_size = match second & 0x7f {
+        126 => 2,
+        127 => 8,
+        _ => 0,
+    };
+    let mask_size = if second & 0x80 != 0 { 4 } else { 0 };
+    2 + length_size + mask_size
+}
+
+/// Parses a complete frame header.
+fn parse_header(header: &[u8]) -> ReadState {
+    let opcode = header[0] & 0x0f;
+    if opcode == OPCODE_CLOSE {
+        return ReadState::Closed;
+    }
+
+    let (remaining, rest) = match header[1] & 0x7f {
+        126 => (
+            u64::from(u16::from_be_bytes([header[2], header[3]])),
+            &header[4..],
+        ),
+        127 => (
+            u64::from_be_bytes(header[2..10].try_into().unwrap()),
+            &header[10..],
+        ),
+        len => (u64::from(len), &header[2..]),
+    };
+    let mask = (header[1] & 0x80 != 0).then(|| rest[..4].try_into().unwrap());
+
+    ReadState::Payload {
+        remaining,
+        mask,
+        offset: 0,
+        // opcodes 0x8 and above are control frames
+        data: opcode < 0x8,
+    }
+}
+
+/// Reads an HTTP head one byte at a time, so that no bytes after it are consumed.
+async fn read_head(stream: &mut (impl AsyncRead + Unpin)) -> std::io::Result<String> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() > MAX_HEAD {
+            return Err(Error::new(ErrorKind::InvalidData, "HTTP head too long"));
+        }
+        head.push(stream.read_u8().await?);
+    }
+    String::from_utf8(head).map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid HTTP head"))
+}
+
+/// Returns the value of the header called `name` (in lowercase) in an HTTP head.
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then_some(value.trim())
+    })
+}
+
+/// Returns the `Sec-WebSocket-Accept` value for a client's `Sec-WebSocket-Key`.
+fn accept_key(key: &str) -> String {
+    let hash = Sha1::new()
+        .chain_update(key.as_bytes())
+        .chain_update(ACCEPT_GUID.as_bytes())
+        .finalize();
+    base64(&hash)
+}
+
+/// Encodes `bytes` as standard base64 with padding.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from(b[0]) << 16 | u32::from(b[1]) << 8 | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(char::from(ALPHABET[(n >> (18 - 6 * i)) as usize & 63]));
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_base64() {
+        // test vectors from RFC 4648
+        for (bytes, encoded) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(base64(bytes.as_bytes()), encoded);
+        }
+    }
+
+    #[test]
+    fn computes_accept_key() {
+        // example from RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[tokio::test]
+    async fn tunnels_bytes_both_ways() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (client, server) = tokio::join!(
+            WebSocket::connect(client, "example.com"),
+            WebSocket::accept(server)
+        );
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+        // larger than a frame, and than the duplex buffer
+        let big: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        let send = async {
+            client.write_all(b"hello").await.unwrap();
+            client.write_all(&big).await.unwrap();
+            client.shutdown().await.unwrap();
+        };
+        let receive = async {
+            let mut received = Vec::new();
+            server.read_to_end(&mut received).await.unwrap();
+            received
+        };
+        let ((), received) = tokio::join!(send, receive);
+        assert_eq!(&received[..5], b"hello");
+        assert_eq!(&received[5..], &big[..]);
+
+        server.write_all(b"reply").await.unwrap();
+        server.flush().await.unwrap();
+        let mut reply = [0; 5];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"reply");
+    }
+
+    #[tokio::test]
+    async fn reads_into_empty_buffer() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (client, server) = tokio::join!(
+            WebSocket::connect(client, "example.com"),
+            WebSocket::accept(server)
+        );
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+        client.write_all(b"hello").await.unwrap();
+        client.flush().await.unwrap();
+        let mut first = [0; 2];
+        server.read_exact(&mut first).await.unwrap();
+
+        // in the middle of a data frame
+        assert_eq!(server.read(&mut []).await.unwrap(), 0);
+        let mut rest = [0; 3];
+        server.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"llo");
+    }
+
+    #[tokio::test]
+    async fn rejects_other_requests() {
+        let (mut client, server) = tokio::io::duplex(4096);
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(WebSocket::accept(server).await.is_err());
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 400"));
+    }
+}
@@ -1,9 +1,10 @@
 #![warn(clippy::all, clippy::pedantic)]
 
+mod tls;
+
 use clap::Parser;
 use gday_hole_punch::server;
 use socket2::{SockRef, TcpKeepalive};
-use std::fs;
 use std::path::PathBuf;
 use std::process::exit;
 use std::sync::Arc;
@@ -15,67 +16,115 @@ use tokio_rustls::rustls;
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// Private TLS Key
+    /// PEM file with the private TLS key (PKCS#8, RSA, or EC)
     #[arg(short, long)]
     key: PathBuf,
 
-    /// Signed server TLS certificate
+    /// PEM file with the server's TLS certificate chain, leaf certificate first
     #[arg(short, long)]
     certificate: PathBuf,
+
+    /// How often to check the key and certificate files for changes, in seconds.
+    /// The certificate is also reloaded on SIGHUP. 0 only reloads on SIGHUP.
+    #[arg(long, default_value_t = 60)]
+    reload_interval: u64,
+
+    /// File of access keys allowed to create rooms, one per line, each optionally
+    /// followed by the maximum number of rooms per hour. If omitted, anyone can create rooms.
+    #[arg(long)]
+    access_keys: Option<PathBuf>,
+
+    /// Maximum number of peers that can join a single room
+    #[arg(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    max_joiners: u32,
+
+    /// How many connections each IP address can open every 5 seconds.
+    /// Later ones wait their turn. Raise this if many clients share an address.
+    #[arg(
+        long,
+        default_value_t = server::DEFAULT_CONNECTION_BURST,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    connection_burst: u32,
+
+    /// Also accept clients tunneled over WebSocket on this port, usually 443,
+    /// for clients behind firewalls that block the usual port
+    #[arg(long)]
+    websocket_port: Option<u16>,
 }
 
 #[tokio::main]
 async fn main() {
-    let listener = TcpListener::bind("0.0.0.0:49870").await.unwrap_or_else(|err| {
-        println!("Error binding listener socket: {err}");
-        exit(1)
-    });
-    let sock2 = SockRef::from(&listener);
-    let tcp_keepalive = TcpKeepalive::new()
-        .with_time(Duration::from_secs(60))
-        .with_interval(Duration::from_secs(1))
-        .with_retries(10);
+    let cli = Cli::parse();
 
-    sock2.set_tcp_keepalive(&tcp_keepalive).unwrap_or_else(|err| {
-        println!("Error setting TCP KeepAlive: {err}");
-        exit(1)
-    });
+    let listener = bind_listener(49870).await;
+    let websocket_listener = match cli.websocket_port {
+        Some(port) => Some(bind_listener(port).await),
+        None => None,
+    };
+
+    let tls_acceptor = get_tls_acceptor(&cli);
 
-    let tls_acceptor = get_tls_acceptor();
+    let access_list = cli.access_keys.as_ref().map(|path| {
+        let access_list = server::AccessList::from_file(path).unwrap_or_else(|err| {
+            println!("Error loading access keys '{}': {err}", path.display());
+            exit(1)
+        });
+        println!("Loaded {} access keys.", access_list.len());
+        access_list
+    });
 
-    if let Err(err) = server::run(listener, tls_acceptor).await {
+    if let Err(err) = server::run(
+        listener,
+        websocket_listener,
+        tls_acceptor,
+        access_list,
+        cli.max_joiners,
+        cli.connection_burst,
+    )
+    .await
+    {
         println!("Server stopped due to error: {err}");
     }
 }
 
-fn get_tls_acceptor() -> tokio_rustls::TlsAcceptor {
-    let cli = Cli::parse();
+async fn bind_listener(port: u16) -> TcpListener {
+    let listener = TcpListener::bind(("0.0.0.0", port))
+        .await
+        .unwrap_or_else(|err| {
+            println!("Error binding listener socket to port {port}: {err}");
+            exit(1)
+        });
+    let sock2 = SockRef::from(&listener);
+    let tcp_keepalive = TcpKeepalive::new()
+        .with_time(Duration::from_secs(60))
+        .with_interval(Duration::from_secs(1))
+        .with_retries(10);
 
-    let key_file = fs::read(&cli.key).unwrap_or_else(|err| {
-        println!("Couldn't open key '{}': {}", cli.key.display(), err);
-        exit(1)
-    });
+    sock2
+        .set_tcp_keepalive(&tcp_keepalive)
+        .unwrap_or_else(|err| {
+            println!("Error setting TCP KeepAlive: {err}");
+            exit(1)
+        });
+    listener
+}
 
-    let cert_file = fs::read(&cli.certificate).unwrap_or_else(|err| {
-        println!(
-            "Couldn't open certificate '{}': {}",
-            cli.certificate.display(),
-            err
-        );
-        exit(1)
-    });
+fn get_tls_acceptor(cli: &Cli) -> tokio_rustls::TlsAcceptor {
+    let resolver =
+        tls::CertResolver::new(cli.certificate.clone(), cli.key.clone()).unwrap_or_else(|err| {
+            println!("Error loading TLS certificate: {err}");
+            exit(1)
+        });
+    let resolver = Arc::new(resolver);
+    tls::print_spki_hash(&resolver);
 
-    let key = rustls::PrivateKey(key_file);
-    let cert = rustls::Certificate(cert_file);
+    tls::spawn_reloader(resolver.clone(), Duration::from_secs(cli.reload_interval));
 
     let tls_config = rustls::ServerConfig::builder()
         .with_safe_defaults()
         .with_no_client_auth()
-        .with_single_cert(vec![cert], key)
-        .unwrap();
+        .with_cert_resolver(resolver);
 
-    tokio_rustls::TlsAcceptor::try_from(Arc::new(tls_config)).unwrap_or_else(|err| {
-        println!("Error making TLS Acceptor: {err}");
-        exit(1);
-    })
+    tokio_rustls::TlsAcceptor::from(Arc::new(tls_config))
 }
@@ -0,0 +1,351 @@
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::fs;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+use tokio_rustls::rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey, SigningKey},
+    Certificate, PrivateKey, SignatureScheme,
+};
+
+#[derive(Error, Debug)]
+pub enum CertError {
+    #[error("Couldn't open '{0}': {1}")]
+    Read(PathBuf, std::io::Error),
+
+    #[error("Couldn't parse PEM file '{0}': {1}")]
+    InvalidPem(PathBuf, std::io::Error),
+
+    #[error("No certificates found in '{0}'")]
+    NoCertificates(PathBuf),
+
+    #[error("No PKCS#8, RSA, or EC private key found in '{0}'")]
+    NoPrivateKey(PathBuf),
+
+    #[error("Private key in '{0}' is invalid or of an unsupported type")]
+    UnsupportedKey(PathBuf),
+
+    #[error("Private key in '{1}' doesn't belong to the certificate in '{0}'")]
+    KeyMismatch(PathBuf, PathBuf),
+}
+
+/// Signature schemes tried when checking that a key belongs to a certificate,
+/// with the algorithm that verifies each.
+static SCHEMES: [(SignatureScheme, &webpki::SignatureAlgorithm); 5] = [
+    (
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        &webpki::ECDSA_P256_SHA256,
+    ),
+    (
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        &webpki::ECDSA_P384_SHA384,
+    ),
+    (SignatureScheme::ED25519, &webpki::ED25519),
+    (
+        SignatureScheme::RSA_PSS_SHA256,
+        &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    ),
+    (
+        SignatureScheme::RSA_PKCS1_SHA256,
+        &webpki::RSA_PKCS1_2048_8192_SHA256,
+    ),
+];
+
+/// Serves the certificate chain and private key found in two files,
+/// and can reload them when they change on disk.
+pub struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+    /// Modification times of (certificate, key) when they were last loaded.
+    modified: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl CertResolver {
+    /// Loads the certificate chain at `cert_path` and the private key at `key_path`.
+    pub fn new(cert_path: PathBuf, key_path: PathBuf) -> Result<Self, CertError> {
+        let modified = (modified_time(&cert_path), modified_time(&key_path));
+        let certified_key = load_certified_key(&cert_path, &key_path)?;
+        Ok(Self {
+            cert_path,
+            key_path,
+            current: RwLock::new(Arc::new(certified_key)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Loads the files again, and starts serving them if they're valid.
+    /// On error, keeps serving the previous certificate.
+    pub fn reload(&self) -> Result<(), CertError> {
+        let modified = (
+            modified_time(&self.cert_path),
+            modified_time(&self.key_path),
+        );
+        let certified_key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = Arc::new(certified_key);
+        *self.modified.lock().unwrap() = modified;
+        Ok(())
+    }
+
+    /// Returns the hexadecimal SHA-256 hash of the current certificate's
+    /// `SubjectPublicKeyInfo`, which clients can pin with `gday --pin`.
+    pub fn spki_hash(&self) -> Option<String> {
+        let current = self.current.read().unwrap();
+        let (_, cert) =
+            x509_parser::parse_x509_certificate(&current.end_entity_cert().ok()?.0).ok()?;
+        let hash = Sha256::digest(cert.public_key().raw);
+        Some(hash.iter().fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        }))
+    }
+
+    /// True if either file's modification time differs from when it was last loaded.
+    fn files_changed(&self) -> bool {
+        let modified = (
+            modified_time(&self.cert_path),
+            modified_time(&self.key_path),
+        );
+        *self.modified.lock().unwrap() != modified
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Spawns a task that reloads the certificate whenever the process receives SIGHUP,
+/// or the certificate or key files are modified. The files are checked every `interval`,
+/// or never if `interval` is zero.
+pub fn spawn_reloader(resolver: Arc<CertResolver>, interval: Duration) {
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let mut hangup =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).ok();
+
+        let mut timer = (!interval.is_zero()).then(|| tokio::time::interval(interval));
+
+        loop {
+            #[cfg(unix)]
+            let forced = tokio::select! {
+                Some(_) = async { Some(timer.as_mut()?.tick().await) } => false,
+                Some(()) = async { hangup.as_mut()?.recv().await } => true,
+                else => return,
+            };
+            #[cfg(not(unix))]
+            let forced = {
+                let Some(timer) = timer.as_mut() else {
+                    return;
+                };
+                timer.tick().await;
+                false
+            };
+
+            if !forced && !resolver.files_changed() {
+                continue;
+            }
+
+            match resolver.reload() {
+                Ok(()) => {
+                    println!("Reloaded TLS certificate.");
+                    print_spki_hash(&resolver);
+                }
+                Err(err) => println!("Couldn't reload TLS certificate, keeping the old one: {err}"),
+            }
+        }
+    });
+}
+
+pub fn print_spki_hash(resolver: &CertResolver) {
+    if let Some(hash) = resolver.spki_hash() {
+        println!("Server public key SHA-256: {hash}");
+    } else {
+        println!("Couldn't parse the server certificate to print its public key hash.");
+    }
+}
+
+/// Loads a certificate chain and a private key, and checks
+/// that rustls can sign with the key, and that the key belongs to the leaf certificate.
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, CertError> {
+    let certs = load_certs(cert_path)?;
+    let key = load_key(key_path)?;
+    let signing_key = sign::any_supported_type(&key)
+        .map_err(|_| CertError::UnsupportedKey(key_path.to_path_buf()))?;
+    if !key_matches_cert(&*signing_key, &certs[0]) {
+        return Err(CertError::KeyMismatch(
+            cert_path.to_path_buf(),
+            key_path.to_path_buf(),
+        ));
+    }
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+/// True if a signature made with `key` verifies with the public key in `cert`.
+fn key_matches_cert(key: &dyn SigningKey, cert: &Certificate) -> bool {
+    let Ok(cert) = webpki::EndEntityCert::try_from(&cert.0[..]) else {
+        return false;
+    };
+    let Some(signer) = key.choose_scheme(&SCHEMES.map(|(scheme, _)| scheme)) else {
+        return false;
+    };
+    let Some((_, algorithm)) = SCHEMES
+        .iter()
+        .find(|(scheme, _)| *scheme == signer.scheme())
+    else {
+        return false;
+    };
+
+    let message = b"gday-server key check";
+    signer.sign(message).is_ok_and(|signature| {
+        cert.verify_signature(algorithm, message, &signature)
+            .is_ok()
+    })
+}
+
+/// Reads the certificate chain from a PEM file, leaf certificate first.
+/// A file that isn't PEM is read as a single DER certificate.
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, CertError> {
+    let bytes = fs::read(path).map_err(|err| CertError::Read(path.to_path_buf(), err))?;
+
+    if !is_pem(&bytes) {
+        return Ok(vec![Certificate(bytes)]);
+    }
+
+    let certs = rustls_pemfile::certs(&mut &bytes[..])
+        .map_err(|err| CertError::InvalidPem(path.to_path_buf(), err))?;
+
+    if certs.is_empty() {
+        return Err(CertError::NoCertificates(path.to_path_buf()));
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Reads the first PKCS#8, RSA (PKCS#1), or EC (SEC1) private key from a PEM file.
+/// A file that isn't PEM is read as a single DER key.
+fn load_key(path: &Path) -> Result<PrivateKey, CertError> {
+    let bytes = fs::read(path).map_err(|err| CertError::Read(path.to_path_buf(), err))?;
+
+    if !is_pem(&bytes) {
+        return Ok(PrivateKey(bytes));
+    }
+
+    let mut reader: &[u8] = &bytes;
+    loop {
+        match rustls_pemfile::read_one(&mut reader as &mut dyn BufRead) {
+            Ok(Some(
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key),
+            )) => return Ok(PrivateKey(key)),
+            Ok(Some(_)) => {}
+            Ok(None) => return Err(CertError::NoPrivateKey(path.to_path_buf())),
+            Err(err) => return Err(CertError::InvalidPem(path.to_path_buf(), err)),
+        }
+    }
+}
+
+fn is_pem(bytes: &[u8]) -> bool {
+    bytes.windows(11).any(|window| window == b"-----BEGIN ")
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("src/testdata")
+            .join(name)
+    }
+
+    #[test]
+    fn loads_pem_chain() {
+        let certs = load_certs(&testdata("ec_chain.pem")).unwrap();
+        assert_eq!(certs.len(), 2);
+        // the leaf comes first
+        assert_eq!(certs[0].0, fs::read(testdata("ec_leaf.der")).unwrap());
+    }
+
+    #[test]
+    fn loads_der_certificate() {
+        let certs = load_certs(&testdata("ec_leaf.der")).unwrap();
+        assert_eq!(
+            certs,
+            [Certificate(fs::read(testdata("ec_leaf.der")).unwrap())]
+        );
+    }
+
+    #[test]
+    fn loads_every_key_format() {
+        let der = fs::read(testdata("ec_key.der")).unwrap();
+        assert_eq!(load_key(&testdata("ec_key.der")).unwrap().0, der);
+        assert_eq!(load_key(&testdata("ec_key_pkcs8.pem")).unwrap().0, der);
+        assert!(load_key(&testdata("ec_key_sec1.pem")).is_ok());
+        assert!(load_key(&testdata("rsa_key.pem")).is_ok());
+
+        for key in ["ec_key.der", "ec_key_pkcs8.pem", "ec_key_sec1.pem"] {
+            load_certified_key(&testdata("ec_chain.pem"), &testdata(key)).unwrap();
+        }
+        load_certified_key(&testdata("rsa_cert.pem"), &testdata("rsa_key.pem")).unwrap();
+    }
+
+    #[test]
+    fn pem_without_certificates_or_key() {
+        assert!(matches!(
+            load_certs(&testdata("ec_key_pkcs8.pem")),
+            Err(CertError::NoCertificates(_))
+        ));
+        assert!(matches!(
+            load_key(&testdata("ec_chain.pem")),
+            Err(CertError::NoPrivateKey(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_key_of_another_certificate() {
+        assert!(matches!(
+            load_certified_key(&testdata("ec_chain.pem"), &testdata("rsa_key.pem")),
+            Err(CertError::KeyMismatch(..))
+        ));
+        assert!(matches!(
+            load_certified_key(&testdata("rsa_cert.pem"), &testdata("ec_key.der")),
+            Err(CertError::KeyMismatch(..))
+        ));
+    }
+
+    #[test]
+    fn reload_keeps_certificate_on_mismatch() {
+        let dir = std::env::temp_dir().join(format!("gday_tls_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        fs::copy(testdata("ec_chain.pem"), &cert_path).unwrap();
+        fs::copy(testdata("ec_key_sec1.pem"), &key_path).unwrap();
+
+        let resolver = CertResolver::new(cert_path.clone(), key_path.clone()).unwrap();
+        let hash = resolver.spki_hash().unwrap();
+
+        // a new key without its certificate
+        fs::copy(testdata("rsa_key.pem"), &key_path).unwrap();
+        assert!(matches!(resolver.reload(), Err(CertError::KeyMismatch(..))));
+        assert_eq!(resolver.spki_hash().unwrap(), hash);
+
+        // and then the certificate that goes with it
+        fs::copy(testdata("rsa_cert.pem"), &cert_path).unwrap();
+        resolver.reload().unwrap();
+        assert_ne!(resolver.spki_hash().unwrap(), hash);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}